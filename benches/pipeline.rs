@@ -0,0 +1,196 @@
+// TSパイプラインのベンチマーク
+// 旧実装(録画ループのバッファコピーとsplit_tsの1バイト毎の出力)と借用スライス＋再利用バッファの比較
+// 出力が旧実装と同一であることはpipeline.rsのテストで確認している
+//
+//   cargo bench --bench pipeline
+//
+#[allow(dead_code)]
+#[path = "../src/ts_splitter_core.rs"]
+mod ts_splitter_core;
+#[allow(dead_code)]
+#[path = "../src/pipeline.rs"]
+mod pipeline;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::pipeline::{DropCheck, TsPipeline};
+use crate::ts_splitter_core::{LENGTH_PACKET, TSS_ERROR, TSS_SUCCESS, get_pid, split_select, split_startup, split_ts};
+
+// チューナーのリードサイズ(tuner::CAPと同じ)
+const CAP: usize = 188 * 87;
+
+// 計測データサイズ(約64MB)
+const TOTAL_PACKETS: usize = 64 * 1024 * 1024 / LENGTH_PACKET;
+
+// 計測回数
+const ROUNDS: u32 = 5;
+
+// TSパケット作成
+fn packet(pid: u16, cc: &mut [u8; 8192], pusi: bool, payload: &[u8]) -> [u8; LENGTH_PACKET] {
+
+    let mut p = [0xffu8; LENGTH_PACKET];
+    p[0] = 0x47;
+    p[1] = ((pid >> 8) as u8 & 0x1f) | if pusi { 0x40 } else { 0x00 };
+    p[2] = pid as u8;
+    p[3] = 0x10 | cc[pid as usize];
+    cc[pid as usize] = (cc[pid as usize] + 1) & 0x0f;
+    p[4..4 + payload.len()].copy_from_slice(payload);
+    p
+
+}
+
+// テスト用TSの作成(2サービス、PAT/PMTを100パケット毎に挿入)
+fn make_ts() -> Vec<u8> {
+
+    let mut cc = [0u8; 8192];
+    let mut ts = Vec::with_capacity(TOTAL_PACKETS * LENGTH_PACKET);
+
+    // PAT (NIT, SID=101 -> PMT 0x0101, SID=102 -> PMT 0x0102)
+    let pat = [
+        0x00, 0x00, 0xb0, 21, 0x7f, 0xe0, 0xc1, 0x00, 0x00,
+        0x00, 0x00, 0xe0, 0x10,
+        0x00, 0x65, 0xe1, 0x01,
+        0x00, 0x66, 0xe1, 0x02,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // PMT (PCR=VIDEO, VIDEO + AUDIO)
+    let pmt = |sid: u8, video: u8, audio: u8| [
+        0x00, 0x02, 0xb0, 23, 0x00, sid, 0xc1, 0x00, 0x00,
+        0xe1, video, 0xf0, 0x00,
+        0x02, 0xe1, video, 0xf0, 0x00,
+        0x0f, 0xe1, audio, 0xf0, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    let pmt1 = pmt(0x65, 0x11, 0x12);
+    let pmt2 = pmt(0x66, 0x21, 0x22);
+
+    let es_pids = [0x0111, 0x0121, 0x0111, 0x0121, 0x0112, 0x0122];
+    let payload = [0x5au8; LENGTH_PACKET - 4];
+
+    let mut count = 0;
+    while count < TOTAL_PACKETS {
+        if count % 100 == 0 {
+            ts.extend_from_slice(&packet(0x0000, &mut cc, true, &pat));
+            ts.extend_from_slice(&packet(0x0101, &mut cc, true, &pmt1));
+            ts.extend_from_slice(&packet(0x0102, &mut cc, true, &pmt2));
+            count += 3;
+        };
+        ts.extend_from_slice(&packet(es_pids[count % es_pids.len()], &mut cc, false, &payload));
+        count += 1;
+    };
+
+    ts
+
+}
+
+// 旧実装の処理(参照用に旧録画ループと旧split_tsのコピー処理を再現)
+// ドロップチェック後にSID分離用のコピーと出力バッファを毎回作成し、残すパケットを1バイトずつ出力バッファへ追加する
+// (処理量の比較用のため、PATの再構築・PMT解析済みパケットの除外は省略)
+fn run_legacy(ts: &[u8], sid: &str) -> usize {
+
+    let mut sp = split_startup(sid);
+    let mut split_select_finish = TSS_ERROR;
+    let mut drop_check = DropCheck::new();
+    let mut total = 0;
+
+    for read_buffer in ts.chunks(CAP) {
+
+        // パケットドロップチェック
+        drop_check.check(&sp, read_buffer, |_, _, _| {});
+
+        // SID分離用のコピーと出力バッファ
+        let mut split_buff: Vec<u8> = vec![];
+        let mut data_buff: Vec<u8> = vec![0; read_buffer.len()];
+        data_buff[..read_buffer.len()].copy_from_slice(read_buffer);
+        if split_select_finish != TSS_SUCCESS {
+            split_select_finish = split_select(&mut sp, &data_buff);
+        };
+
+        // 残すパケット(PAT・対象PID)を1バイトずつ出力
+        if split_select_finish == TSS_SUCCESS {
+            let mut in_index = 0;
+            while (data_buff.len() as i32 - in_index as i32 - LENGTH_PACKET as i32) >= 0 {
+                let pid = get_pid(&data_buff[in_index..in_index + LENGTH_PACKET - 1]) as usize;
+                if pid == 0 || sp.pids[pid] != 0 {
+                    for cnt in 0..LENGTH_PACKET {
+                        split_buff.push(data_buff[cnt + in_index]);
+                    };
+                };
+                in_index += LENGTH_PACKET;
+            };
+        };
+        total += black_box(&split_buff).len();
+
+    };
+
+    total
+
+}
+
+// 新実装の処理(借用スライス＋再利用バッファ)
+fn run_pipeline(ts: &[u8], sid: &str) -> usize {
+
+    let mut pipeline = TsPipeline::new(sid, true);
+    let mut drop_check = DropCheck::new();
+    let mut total = 0;
+
+    for read_buffer in ts.chunks(CAP) {
+
+        drop_check.check(&pipeline.sp, read_buffer, |_, _, _| {});
+        total += black_box(pipeline.process(read_buffer)).len();
+
+    };
+
+    total
+
+}
+
+// 新実装のsplit_ts単体
+fn run_split_ts(ts: &[u8], sid: &str) -> usize {
+
+    let mut sp = split_startup(sid);
+    let _ = split_select(&mut sp, ts);
+    let mut split_buff: Vec<u8> = Vec::with_capacity(CAP);
+    let mut total = 0;
+
+    for read_buffer in ts.chunks(CAP) {
+        split_buff.clear();
+        split_ts(&mut sp, read_buffer, &mut split_buff);
+        total += black_box(&split_buff).len();
+    };
+
+    total
+
+}
+
+// 計測処理(最速値を採用)
+fn bench<F: FnMut() -> usize>(name: &str, bytes: usize, mut f: F) -> Duration {
+
+    let mut best = Duration::MAX;
+    let mut out = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        out = f();
+        best = best.min(start.elapsed());
+    };
+    println!("{:<24} {:>10.2?}  {:>8.1} MB/s  (output {} bytes)",
+        name, best, bytes as f64 / 1024.0 / 1024.0 / best.as_secs_f64(), out);
+
+    best
+
+}
+
+fn main() {
+
+    let ts = make_ts();
+    println!("input {} bytes, read size {} bytes", ts.len(), CAP);
+
+    let legacy = bench("legacy (copy path)", ts.len(), || run_legacy(&ts, "101"));
+    let pipeline = bench("pipeline (borrowed)", ts.len(), || run_pipeline(&ts, "101"));
+    bench("split_ts only", ts.len(), || run_split_ts(&ts, "101"));
+
+    println!("speedup {:.2}x", legacy.as_secs_f64() / pipeline.as_secs_f64());
+
+}
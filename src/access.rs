@@ -0,0 +1,218 @@
+use log::debug;
+use std::fs;
+use std::net::IpAddr;
+
+use crate::http::HttpRequest;
+
+// 認証失敗時のWWW-Authenticateヘッダー
+pub const AUTHENTICATE_REALM: &str = "Basic realm=\"recpt3\", Bearer realm=\"recpt3\"";
+
+// base64の文字テーブル
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// アドレス範囲(CIDR表記)
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+#[allow(dead_code)]
+impl Cidr {
+
+    // CIDR表記の解析(プレフィックス長省略時はホストアドレス)
+    pub fn parse(text: &str) -> Option<Cidr> {
+
+        let (addr, prefix) = match text.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text.trim(), None),
+        };
+        let addr = addr.parse::<IpAddr>().ok()?.to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max_prefix)?,
+            None => max_prefix,
+        };
+
+        Some(Cidr { addr: addr, prefix: prefix })
+
+    }
+
+    // アドレスの包含判定(IPv4射影アドレスはIPv4として判定)
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+                u32::from(network) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = if self.prefix == 0 { 0 } else { u128::MAX << (128 - self.prefix) };
+                u128::from(network) & mask == u128::from(addr) & mask
+            },
+            _ => false,
+        }
+
+    }
+
+}
+
+// 認証方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum AuthScheme {
+    Basic,
+    Bearer,
+}
+
+// 認証情報
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+pub struct Credential {
+    pub scheme: AuthScheme,
+    // Basic認証は「ユーザー:パスワード」のbase64、Bearerはトークン
+    pub secret: String,
+    // この認証情報のクライアントの優先度(優先度ヘッダーの上限)
+    pub priority: Option<i32>,
+}
+
+// アクセス制御(アドレス制限＋認証)
+#[derive(Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct AccessControl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub credentials: Vec<Credential>,
+}
+
+#[allow(dead_code)]
+impl AccessControl {
+
+    // アドレスの許可判定(denyに一致したら拒否、allow指定時はallowに一致したもののみ許可)
+    pub fn is_allowed(&self, addr: &IpAddr) -> bool {
+
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        };
+        self.allow.len() == 0 || self.allow.iter().any(|cidr| cidr.contains(addr))
+
+    }
+
+    // 認証が必要か
+    pub fn requires_auth(&self) -> bool {
+
+        self.credentials.len() > 0
+
+    }
+
+    // Authorizationヘッダーの認証
+    pub fn authenticate(&self, request: &HttpRequest) -> bool {
+
+        self.requires_auth() == false || self.credential(request).is_some()
+
+    }
+
+    // Authorizationヘッダーに一致する認証情報
+    pub fn credential(&self, request: &HttpRequest) -> Option<&Credential> {
+
+        let authorization = request.header("Authorization")?.trim();
+        let (scheme, value) = authorization.split_once(' ')?;
+        let value = value.trim();
+
+        self.credentials.iter().find(|credential| match credential.scheme {
+            AuthScheme::Basic => scheme.eq_ignore_ascii_case("Basic") && secure_eq(&credential.secret, value),
+            AuthScheme::Bearer => scheme.eq_ignore_ascii_case("Bearer") && secure_eq(&credential.secret, value),
+        })
+
+    }
+
+}
+
+// CIDRリストの解析(カンマ区切り)
+#[allow(dead_code)]
+pub fn parse_cidr_list(text: &str) -> Result<Vec<Cidr>, String> {
+
+    text.split(',')
+        .filter(|cidr| cidr.trim() != "")
+        .map(|cidr| Cidr::parse(cidr).ok_or(format!("bad address {}", cidr)))
+        .collect()
+
+}
+
+// 認証ファイルの読み込み
+// 書式は1行1件で「basic ユーザー:パスワード」または「bearer トークン」、#以降はコメント
+// 行末に「priority=N」を付けるとその認証情報のクライアントの優先度になる
+#[allow(dead_code)]
+pub fn load_auth_file(path: &str) -> Result<Vec<Credential>, String> {
+
+    let content = fs::read_to_string(path).map_err(|e| format!("{} ({})", path, e))?;
+
+    let mut credentials = vec![];
+    for (line_no, line) in content.lines().enumerate() {
+
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line == "" { continue; };
+
+        let (kind, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let mut value = value.trim();
+
+        // 優先度の指定
+        let mut priority = None;
+        if let Some((rest, last)) = value.rsplit_once(char::is_whitespace) {
+            if let Some(number) = last.strip_prefix("priority=") {
+                priority = Some(number.parse::<i32>().map_err(|_| format!("{} line {} ({})", path, line_no + 1, last))?);
+                value = rest.trim();
+            };
+        };
+
+        let credential = match &kind.to_ascii_lowercase()[..] {
+            "basic" if value.contains(':') => Credential { scheme: AuthScheme::Basic, secret: base64_encode(value.as_bytes()), priority: priority },
+            "bearer" if value != "" => Credential { scheme: AuthScheme::Bearer, secret: value.to_string(), priority: priority },
+            _ => return Err(format!("{} line {} ({})", path, line_no + 1, kind)),
+        };
+        credentials.push(credential);
+
+    };
+    debug!("load_auth_file {} ({} credentials)", path, credentials.len());
+
+    if credentials.len() == 0 {
+        return Err(format!("{} (no credentials)", path));
+    };
+
+    Ok(credentials)
+
+}
+
+// base64エンコード(パディング付き)
+fn base64_encode(data: &[u8]) -> String {
+
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_TABLE[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            }
+            else {
+                encoded.push('=');
+            };
+        };
+    };
+
+    encoded
+
+}
+
+// 認証情報の比較(一致位置で処理時間が変わらないように全体を比較)
+fn secure_eq(expected: &str, actual: &str) -> bool {
+
+    let expected = expected.as_bytes();
+    let actual = actual.as_bytes();
+    let diff = (0..expected.len().max(actual.len())).fold(expected.len() ^ actual.len(), |diff, i| {
+        diff | (*expected.get(i).unwrap_or(&0) ^ *actual.get(i).unwrap_or(&0)) as usize
+    });
+
+    diff == 0
+
+}
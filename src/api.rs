@@ -0,0 +1,297 @@
+use serde::Serialize;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use crate::decoder::b25_decode_errors;
+use crate::metrics::{Metric, MetricType, render};
+use crate::session::{SessionBroker, Subscriber, TunerSession};
+use crate::tuner::tuner_list;
+
+// 購読者(クライアント)の状態
+#[derive(Serialize)]
+pub struct ClientStatus {
+    pub id: u64,
+    pub priority: i32,
+    pub client: String,
+    pub sids: Vec<String>,
+    pub bytes_sent: u64,
+    pub skipped: u64,
+    pub slow_client: String,
+    pub queued: usize,
+    pub queue_capacity: usize,
+    pub lag_ms: u64,
+    pub max_lag_ms: u64,
+    pub resyncs: u64,
+    pub timeshifted: bool,
+    pub timeshift_delay: f64,
+    pub uptime: u64,
+}
+
+// セッションの状態
+#[derive(Serialize)]
+pub struct SessionStatus {
+    pub id: u64,
+    pub priority: i32,
+    pub channel: String,
+    pub channel_type: String,
+    pub device: String,
+    pub bytes: u64,
+    pub drops: u64,
+    pub cn: f32,
+    pub uptime: u64,
+    pub timeshift: Option<TimeShiftStatus>,
+    pub clients: Vec<ClientStatus>,
+}
+
+// タイムシフトバッファの状態(時刻はTOTから求めたunix秒)
+#[derive(Serialize)]
+pub struct TimeShiftStatus {
+    pub window: u64,
+    pub seconds: f64,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub bytes: u64,
+}
+
+// デーモンの状態(/api/status)
+#[derive(Serialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub uptime: u64,
+    pub max_sessions: usize,
+    pub preemptions: u64,
+    pub client_count: usize,
+    pub sessions: Vec<SessionStatus>,
+}
+
+// チューナーの利用者(Mirakurun互換)
+#[derive(Serialize)]
+pub struct TunerUser {
+    pub id: String,
+    pub priority: i32,
+    pub agent: String,
+}
+
+// チューナーの状態(/api/tuners)
+// index以降はMirakurun互換の項目
+#[derive(Serialize)]
+pub struct TunerStatus {
+    pub device: String,
+    pub types: Vec<String>,
+    pub busy: bool,
+    pub owner: Option<String>,
+    pub session: Option<u64>,
+    pub channel: Option<String>,
+    pub cn: Option<f32>,
+    pub index: usize,
+    pub name: String,
+    #[serde(rename = "isAvailable")]
+    pub is_available: bool,
+    #[serde(rename = "isRemote")]
+    pub is_remote: bool,
+    #[serde(rename = "isFree")]
+    pub is_free: bool,
+    #[serde(rename = "isUsing")]
+    pub is_using: bool,
+    #[serde(rename = "isFault")]
+    pub is_fault: bool,
+    pub users: Vec<TunerUser>,
+}
+
+// 経過秒数
+fn elapsed(since: SystemTime) -> u64 {
+
+    SystemTime::now().duration_since(since).unwrap_or_default().as_secs()
+
+}
+
+// 購読者の状態作成
+fn client_status(session: &TunerSession, subscriber: &Subscriber) -> ClientStatus {
+
+    // タイムシフト再生中は最新のデータからの遅れ
+    let timeshift_delay = match &session.timeshift {
+        Some(buffer) if subscriber.is_shifted() => buffer.delay(subscriber.position.load(Ordering::Relaxed)),
+        _ => 0.0,
+    };
+
+    ClientStatus {
+        id: subscriber.id,
+        priority: subscriber.priority,
+        client: subscriber.addr.to_string(),
+        sids: subscriber.sid.split(',').filter(|sid| *sid != "").map(|sid| sid.to_string()).collect(),
+        bytes_sent: subscriber.sent.load(Ordering::Relaxed),
+        skipped: subscriber.skipped.load(Ordering::Relaxed),
+        slow_client: subscriber.policy.name().to_string(),
+        queued: subscriber.queue.len(),
+        queue_capacity: subscriber.queue.capacity(),
+        lag_ms: subscriber.lag_ms.load(Ordering::Relaxed),
+        max_lag_ms: subscriber.max_lag_ms.load(Ordering::Relaxed),
+        resyncs: subscriber.resyncs.load(Ordering::Relaxed),
+        timeshifted: subscriber.is_shifted(),
+        timeshift_delay: timeshift_delay,
+        uptime: elapsed(subscriber.started),
+    }
+
+}
+
+// セッションの状態作成
+fn session_status(session: &TunerSession) -> SessionStatus {
+
+    SessionStatus {
+        id: session.id,
+        priority: session.priority(),
+        channel: session.channel.to_string(),
+        channel_type: session.channel_type.to_string(),
+        device: session.device.to_string(),
+        bytes: session.bytes.load(Ordering::Relaxed),
+        drops: session.drops.load(Ordering::Relaxed),
+        cn: session.signal(),
+        uptime: elapsed(session.started),
+        timeshift: session.timeshift.as_ref().map(|buffer| {
+            let (seconds, start, end, bytes) = buffer.range();
+            TimeShiftStatus { window: buffer.window as u64, seconds: seconds, start: start, end: end, bytes: bytes }
+        }),
+        clients: session.subscribers().iter().map(|subscriber| client_status(session, subscriber)).collect(),
+    }
+
+}
+
+// デーモンの状態取得
+pub fn daemon_status(broker: &SessionBroker) -> DaemonStatus {
+
+    let sessions: Vec<SessionStatus> = broker.sessions().iter().map(|session| session_status(session)).collect();
+
+    DaemonStatus {
+        pid: process::id(),
+        uptime: elapsed(broker.started),
+        max_sessions: broker.max_sessions,
+        preemptions: broker.preemptions.load(Ordering::Relaxed),
+        client_count: sessions.iter().map(|session| session.clients.len()).sum(),
+        sessions: sessions,
+    }
+
+}
+
+// チューナーの状態取得
+// セッションの状態のみで判定する(デバイスのオープンは選局中のセッション・他プロセスと競合するため行わない)
+pub fn tuner_status(broker: &SessionBroker) -> Vec<TunerStatus> {
+
+    let sessions = broker.sessions();
+
+    tuner_list().into_iter().enumerate().map(|(index, (device, types))| {
+
+        let session = sessions.iter().find(|session| session.device == device && session.is_stopped() == false);
+        let (busy, owner) = match session {
+            Some(_) => (true, Some("http".to_string())),
+            None => (false, None),
+        };
+
+        TunerStatus {
+            device: device.to_string(),
+            types: types.iter().map(|channel_type| channel_type.to_string()).collect(),
+            busy: busy,
+            owner: owner,
+            session: session.map(|session| session.id),
+            channel: session.map(|session| session.channel.to_string()),
+            cn: session.map(|session| session.signal()),
+            index: index,
+            name: device.to_string(),
+            is_available: true,
+            is_remote: false,
+            is_free: busy == false,
+            is_using: busy,
+            is_fault: false,
+            users: match session {
+                Some(session) => session.subscribers().iter().map(|subscriber| TunerUser {
+                    id: subscriber.addr.to_string(),
+                    priority: subscriber.priority,
+                    agent: subscriber.agent.to_string(),
+                }).collect(),
+                None => vec![],
+            },
+        }
+
+    }).collect()
+
+}
+
+// /api/statusのJSON作成
+pub fn status_json(broker: &SessionBroker) -> String {
+
+    serde_json::to_string_pretty(&daemon_status(broker)).unwrap_or_default()
+
+}
+
+// /api/tunersのJSON作成
+pub fn tuners_json(broker: &SessionBroker) -> String {
+
+    serde_json::to_string_pretty(&tuner_status(broker)).unwrap_or_default()
+
+}
+
+// /metricsのテキスト作成(OpenMetrics形式)
+pub fn metrics_text(broker: &SessionBroker) -> String {
+
+    let status = daemon_status(broker);
+
+    let mut uptime = Metric::new("recpt3_uptime_seconds", "Daemon uptime", MetricType::Gauge);
+    uptime.sample(&[], status.uptime as f64);
+    let mut clients = Metric::new("recpt3_active_clients", "Connected streaming clients", MetricType::Gauge);
+    clients.sample(&[], status.client_count as f64);
+    let mut sessions = Metric::new("recpt3_active_sessions", "Open tuner sessions", MetricType::Gauge);
+    sessions.sample(&[], status.sessions.len() as f64);
+
+    // チューナー毎の使用状態
+    let mut busy = Metric::new("recpt3_tuner_busy", "Tuner device in use (1=busy)", MetricType::Gauge);
+    for tuner in tuner_status(broker) {
+        let owner = tuner.owner.unwrap_or_default();
+        busy.sample(&[("device", &tuner.device), ("owner", &owner)], if tuner.busy { 1.0 } else { 0.0 });
+    };
+
+    // セッション毎のC/N・バイト数・CCエラー
+    let mut signal = Metric::new("recpt3_signal_cn_db", "Tuner C/N in dB", MetricType::Gauge);
+    let mut bytes = Metric::new("recpt3_session_bytes", "Bytes received from the tuner", MetricType::Counter);
+    let mut drops = Metric::new("recpt3_session_cc_errors", "Continuity counter errors", MetricType::Counter);
+    let mut sent = Metric::new("recpt3_client_sent_bytes", "Bytes sent to the client", MetricType::Counter);
+    let mut skipped = Metric::new("recpt3_client_skipped_chunks", "Chunks skipped for a slow client", MetricType::Counter);
+    let mut queued = Metric::new("recpt3_client_queued_chunks", "Chunks waiting in the client send queue", MetricType::Gauge);
+    let mut lag = Metric::new("recpt3_client_lag_seconds", "Delay from tuner to client of the last chunk sent", MetricType::Gauge);
+    let mut max_lag = Metric::new("recpt3_client_max_lag_seconds", "Maximum delay from tuner to client", MetricType::Gauge);
+    let mut resyncs = Metric::new("recpt3_client_resyncs", "Send queue restarts from the next PAT", MetricType::Counter);
+    let mut buffered = Metric::new("recpt3_timeshift_seconds", "Seconds of stream kept in the time-shift buffer", MetricType::Gauge);
+    let mut delay = Metric::new("recpt3_client_timeshift_delay_seconds", "Delay behind live of a time-shifted client", MetricType::Gauge);
+    for session in &status.sessions {
+        let id = session.id.to_string();
+        let labels = [("session", id.as_str()), ("channel", session.channel.as_str()), ("device", session.device.as_str())];
+        signal.sample(&labels[1..], session.cn as f64);
+        bytes.sample(&labels, session.bytes as f64);
+        drops.sample(&labels, session.drops as f64);
+        if let Some(timeshift) = &session.timeshift {
+            buffered.sample(&labels, timeshift.seconds);
+        };
+        for client in &session.clients {
+            let client_labels = [("session", id.as_str()), ("client", client.client.as_str())];
+            sent.sample(&client_labels, client.bytes_sent as f64);
+            skipped.sample(&client_labels, client.skipped as f64);
+            queued.sample(&client_labels, client.queued as f64);
+            lag.sample(&client_labels, client.lag_ms as f64 / 1000.0);
+            max_lag.sample(&client_labels, client.max_lag_ms as f64 / 1000.0);
+            resyncs.sample(&client_labels, client.resyncs as f64);
+            delay.sample(&client_labels, client.timeshift_delay);
+        };
+    };
+
+    // プリエンプション回数
+    let mut preemptions = Metric::new("recpt3_session_preemptions", "Sessions stopped for a higher priority client", MetricType::Counter);
+    preemptions.sample(&[], status.preemptions as f64);
+
+    // B25デコードエラー
+    let mut b25_errors = Metric::new("recpt3_b25_decode_errors", "B25 decode failures", MetricType::Counter);
+    b25_errors.sample(&[], b25_decode_errors() as f64);
+    let mut b25_failures = Metric::new("recpt3_b25_startup_failures", "B-CAS initialization failures", MetricType::Counter);
+    b25_failures.sample(&[], broker.b25_failures.load(Ordering::Relaxed) as f64);
+
+    render(&[uptime, clients, sessions, busy, signal, bytes, drops, sent, skipped, queued, lag, max_lag, resyncs, buffered, delay, preemptions, b25_errors, b25_failures], true)
+
+}
@@ -0,0 +1,215 @@
+use log::{debug, warn};
+use serde::Serialize;
+use std::fs;
+
+use crate::mirakurun::{ServiceItem, entry_services, mirakurun_type};
+use crate::tuner::{BS_CHANNELS, bs_channel_name, channel_file_path, channel_type};
+
+// チャンネル情報(プレイリスト・チャンネル一覧用)
+#[derive(Debug, Clone, Serialize)]
+#[allow(dead_code)]
+pub struct ChannelEntry {
+    // 選局用のチャンネル指定(BS01_0, 27, C13, CS2など)
+    pub channel: String,
+    // SID分離の指定(SID, hd, allなど)
+    pub sid: String,
+    // 表示名
+    pub name: String,
+    // チャンネル番号(表示用)
+    pub number: String,
+    // チャンネルタイプ(BS, CS, T, CATV)
+    pub channel_type: String,
+    // プレイリストのグループ名
+    pub group: String,
+}
+
+#[allow(dead_code)]
+impl ChannelEntry {
+
+    // ストリームのパス(/channel/sid)
+    pub fn path(&self) -> String {
+
+        format!("/{}/{}", self.channel, self.sid)
+
+    }
+
+}
+
+// チャンネル一覧のJSON項目(URL付き、Mirakurun互換のtype・servicesを含む)
+#[derive(Serialize)]
+struct ChannelItem<'a> {
+    #[serde(flatten)]
+    entry: &'a ChannelEntry,
+    url: String,
+    #[serde(rename = "type")]
+    mirakurun_type: &'static str,
+    services: Vec<ServiceItem>,
+}
+
+// チャンネルタイプのグループ名
+fn group_name(channel_type: &str) -> &'static str {
+
+    match channel_type {
+        "T" => "地上波",
+        "BS" => "BS",
+        "CS" => "CS",
+        "CATV" => "CATV",
+        _ => "その他",
+    }
+
+}
+
+// チャンネル情報の作成(チャンネル指定が不正な場合はNone)
+fn channel_entry(channel: &str, sid: &str, name: &str, number: &str) -> Option<ChannelEntry> {
+
+    let (channel_type, _freq) = channel_type(channel.to_string());
+    if channel_type == "" {
+        return None;
+    };
+
+    Some(ChannelEntry {
+        channel: channel.to_string(),
+        sid: sid.to_string(),
+        name: name.to_string(),
+        number: number.to_string(),
+        group: group_name(&channel_type).to_string(),
+        channel_type: channel_type,
+    })
+
+}
+
+// ユーザーチャンネルファイルの読み込み
+// 書式は1行1チャンネルで「チャンネル[/SID]: 名前」、#以降はコメント
+// 選局できないチャンネル指定の行(13-62: Terrestrial Channels など)は読み飛ばす
+#[allow(dead_code)]
+pub fn load_channel_file(path: &str) -> Vec<ChannelEntry> {
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            debug!("load_channel_file {} ({})", path, e);
+            return vec![];
+        },
+    };
+
+    let mut entries = vec![];
+    for (line_no, line) in content.lines().enumerate() {
+
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line == "" { continue; };
+
+        // チャンネル指定と名前の分解
+        let (target, name) = match line.split_once(':') {
+            Some((target, name)) => (target.trim(), name.trim()),
+            None => (line, ""),
+        };
+        let (channel, sid) = match target.split_once('/') {
+            Some((channel, sid)) => (channel.trim(), sid.trim()),
+            None => (target, ""),
+        };
+
+        // SID未指定時は既定のSID
+        let sid = if sid == "" { default_sid(channel) } else { sid.to_string() };
+        let name = if name == "" { channel.to_string() } else { name.to_string() };
+        let number = if sid.parse::<u32>().is_ok() { sid.to_string() } else { channel.to_string() };
+
+        match channel_entry(channel, &sid, &name, &number) {
+            Some(entry) => entries.push(entry),
+            None => debug!("load_channel_file skip line {} ({})", line_no + 1, line),
+        };
+
+    };
+
+    entries
+
+}
+
+// 既定のSID(BSのチャンネル番号指定はサービスID、それ以外は先頭のHDサービス)
+fn default_sid(channel: &str) -> String {
+
+    match channel.parse::<i32>() {
+        Ok(number) if BS_CHANNELS.iter().any(|bs| bs.channel == number) => number.to_string(),
+        _ => "hd".to_string(),
+    }
+
+}
+
+// チャンネルマップの作成
+// BS(BS_CHANNELS) → ユーザーチャンネルファイル → 地上波(13-62) → CATV(C13-C63) → CS(CS2-CS24)の順
+// ユーザーチャンネルファイルで定義したチャンネルは地上波・CATV・CSの既定項目より優先する
+#[allow(dead_code)]
+pub fn channel_map() -> Vec<ChannelEntry> {
+
+    let mut entries: Vec<ChannelEntry> = vec![];
+
+    // BSチャンネル
+    for bs in BS_CHANNELS.iter() {
+        let number = bs.channel.to_string();
+        if let Some(entry) = channel_entry(&bs_channel_name(bs), &number, bs.name, &number) {
+            entries.push(entry);
+        };
+    };
+
+    // ユーザーチャンネルファイル
+    let path = channel_file_path();
+    let user_entries = load_channel_file(&path);
+    if user_entries.len() == 0 && fs::metadata(&path).is_ok() {
+        warn!("No channels found in {}", path);
+    };
+    let user_channels: Vec<String> = user_entries.iter().map(|entry| entry.channel.to_uppercase()).collect();
+    entries.extend(user_entries);
+
+    // 地上波・CATV・CSの既定項目
+    let mut ranges: Vec<(String, String)> = vec![];
+    for ch in 13..=62 {
+        ranges.push((ch.to_string(), format!("地上波 {}ch", ch)));
+    };
+    for ch in 13..=63 {
+        ranges.push((format!("C{}", ch), format!("CATV C{}ch", ch)));
+    };
+    for ch in (2..=24).step_by(2) {
+        ranges.push((format!("CS{}", ch), format!("CS ND{}", ch)));
+    };
+    for (channel, name) in ranges {
+        if user_channels.contains(&channel.to_uppercase()) { continue; };
+        if let Some(entry) = channel_entry(&channel, "hd", &name, &channel) {
+            entries.push(entry);
+        };
+    };
+
+    entries
+
+}
+
+// M3Uプレイリストの作成
+#[allow(dead_code)]
+pub fn channels_m3u(entries: &[ChannelEntry], base_url: &str) -> String {
+
+    let mut m3u = String::from("#EXTM3U\n");
+
+    for entry in entries {
+        m3u.push_str(&format!("#EXTINF:-1 tvg-id=\"{}.{}\" tvg-chno=\"{}\" tvg-name=\"{}\" group-title=\"{}\",{}\n",
+            entry.channel, entry.sid, entry.number, entry.name.replace('"', "'"), entry.group, entry.name));
+        m3u.push_str(&format!("{}{}\n", base_url, entry.path()));
+    };
+
+    m3u
+
+}
+
+// チャンネル一覧のJSON作成
+#[allow(dead_code)]
+pub fn channels_json(entries: &[ChannelEntry], base_url: &str) -> String {
+
+    let items: Vec<ChannelItem> = entries.iter()
+        .map(|entry| ChannelItem {
+            entry: entry,
+            url: format!("{}{}", base_url, entry.path()),
+            mirakurun_type: mirakurun_type(&entry.channel_type),
+            services: entry_services(entry, base_url),
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&items).unwrap_or_default()
+
+}
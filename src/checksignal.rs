@@ -19,6 +19,10 @@ mod arib_b25;
 mod commands;
 mod decoder;
 mod ffi;
+mod metrics;
+mod output;
+mod pipeline;
+mod ring_queue;
 mod ts_splitter_core;
 mod tuner;
 
@@ -46,4 +46,41 @@ pub struct CommanLineOpt {
     pub duration: u64,
     pub infile: String,
     pub outfile: String,
+    pub use_fallback_dir: bool,
+    pub fallback_dir: String,
+    pub use_preallocate: bool,
+    pub buffer_size: usize,
+    pub sync_interval: u64,
+    pub stall_timeout: u64,
+    pub linger: u64,
+    pub metrics_file: String,
+    pub use_hdhomerun: bool,
+    pub use_hdhomerun_discover: bool,
+    pub use_dlna: bool,
+    pub dlna_interface: String,
+    pub foreground: bool,
+    pub pidfile: String,
+    pub log_file: String,
+    pub user: String,
+    pub group: String,
+    pub bind: String,
+    pub allow: String,
+    pub deny: String,
+    pub auth_file: String,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub max_sessions: usize,
+    pub client_queue: usize,
+    pub slow_client: String,
+    pub slow_client_timeout: u64,
+    pub idle_timeout: u64,
+    pub send_timeout: u64,
+    pub hls_segment: u64,
+    pub hls_window: usize,
+    pub hls_dir: String,
+    pub timeshift: u64,
+    pub timeshift_dir: String,
+    pub recordings_dir: String,
+    pub offset: i64,
+    pub resume: u64,
 }
@@ -1,9 +1,55 @@
 use log::{error};
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::arib_b25::{ARIB_STD_B25, ARIB_STD_B25_BUFFER,  B_CAS_CARD};
 use crate::ffi::{create_arib_std_b25, create_b_cas_card};
 
+// B25デコードエラー回数(プロセス全体)
+static B25_DECODE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+// B25デコードエラー回数の取得(プロセス全体)
+#[allow(dead_code)]
+pub fn b25_decode_errors() -> u64 {
+
+    B25_DECODE_ERRORS.load(Ordering::Relaxed)
+
+}
+
+// B25デコーダーコンテキスト(デコードスレッドへの受け渡し用)
+#[allow(dead_code)]
+pub struct B25Context {
+    pub dec: *mut ARIB_STD_B25,
+    pub bcas: *mut B_CAS_CARD,
+    // このデコーダーのデコードエラー回数
+    pub errors: u64,
+}
+
+// デコーダーは受け渡し先の1スレッドのみで使用する
+unsafe impl Send for B25Context {}
+
+#[allow(dead_code)]
+impl B25Context {
+
+    // デコーダーコンテキストの作成
+    pub fn new(dec: *mut ARIB_STD_B25, bcas: *mut B_CAS_CARD) -> B25Context {
+
+        B25Context { dec: dec, bcas: bcas, errors: 0 }
+
+    }
+
+    // B25デコード処理(デコードエラー回数をコンテキスト毎に集計)
+    pub unsafe fn decode<'a>(&mut self, sbuf: &'a ARIB_STD_B25_BUFFER) -> (&'a [u8], i32) {
+
+        let (buffer, len, errors) = b25_decode_counted(self.dec, sbuf);
+        self.errors += errors;
+
+        (buffer, len)
+
+    }
+
+}
+
 // B25デコードの初期設定
 #[allow(dead_code)]
 pub unsafe fn b25_startup(round: i32, strip: i32, emm: i32 ) -> (i32, *mut ARIB_STD_B25, *mut B_CAS_CARD) {
@@ -53,12 +99,25 @@ pub unsafe fn b25_startup(round: i32, strip: i32, emm: i32 ) -> (i32, *mut ARIB_
 #[allow(dead_code)]
 pub unsafe fn b25_decode(dec: *mut ARIB_STD_B25, sbuf: &ARIB_STD_B25_BUFFER) -> (&[u8], i32) {
 
+    let (buffer, len, _) = b25_decode_counted(dec, sbuf);
+
+    (buffer, len)
+
+}
+
+// B25デコード処理(今回のデコードエラー回数も返す)
+unsafe fn b25_decode_counted(dec: *mut ARIB_STD_B25, sbuf: &ARIB_STD_B25_BUFFER) -> (&[u8], i32, u64) {
+
     let mut result;
+    let mut errors: u64 = 0;
 
     // BCASカードへデータ送信
     //debug!("b25_decode put len = {}", sbuf.size);
     result = dec.as_ref().expect("b25->put failed").put(sbuf);
-    if result < 0 { error!("b25->put failed") };
+    if result < 0 {
+        error!("b25->put failed");
+        errors += 1;
+    };
 
     // BCASカードからの受信バッファ
     let mut buffer_struct = ARIB_STD_B25_BUFFER {
@@ -68,23 +127,31 @@ pub unsafe fn b25_decode(dec: *mut ARIB_STD_B25, sbuf: &ARIB_STD_B25_BUFFER) ->
 
     // BCASカードからデータ受信
     result = dec.as_ref().expect("b25->get failed").get(&mut buffer_struct);
-    if result < 0 { error!("b25->get failed(result={})", result) };
+    if result < 0 {
+        error!("b25->get failed(result={})", result);
+        errors += 1;
+    };
+    B25_DECODE_ERRORS.fetch_add(errors, Ordering::Relaxed);
     //debug!("b25_decode rc={} , get len = {}", result,buffer_struct.size);
 
     match buffer_struct.size {
-        0 => (&[0], 0),
+        0 => (&[0], 0, errors),
         _ => {
             let buff = core::slice::from_raw_parts_mut(buffer_struct.data, buffer_struct.size as usize);
-            (buff, buffer_struct.size.try_into().unwrap())
+            (buff, buffer_struct.size.try_into().unwrap(), errors)
         },
     }
 }
 
-// B25シャットダウン処理
+// B25シャットダウン処理(初期化に失敗した場合は作成済みのもののみ解放)
 #[allow(dead_code)]
 pub unsafe fn b25_shutdown(dec: *mut ARIB_STD_B25, bcas: *mut B_CAS_CARD) -> () {
 
-    dec.as_mut().expect("b25->release failed").release();
-    bcas.as_mut().unwrap().release();
+    if let Some(dec) = dec.as_mut() {
+        dec.release();
+    };
+    if let Some(bcas) = bcas.as_mut() {
+        bcas.release();
+    };
 
 }
@@ -0,0 +1,862 @@
+use crc::{Crc, CRC_32_ISO_HDLC};
+use log::{debug, error, info};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::VERSION;
+use crate::access::AccessControl;
+use crate::channels::ChannelEntry;
+use crate::hdhomerun::local_addr_for;
+use crate::http::HttpRequest;
+
+// SSDPのマルチキャストアドレスとポート
+pub const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const SSDP_PORT: u16 = 1900;
+
+// 広告の有効期間(秒)、有効期間の半分毎にNOTIFYを再送
+const SSDP_MAX_AGE: u64 = 1800;
+
+// SSDPソケットの受信タイムアウト(終了・NOTIFY再送のチェック間隔)
+const SSDP_POLL: u64 = 1;
+
+// SSDPソケットの受信エラーが続く場合の再試行間隔の上限(秒)
+const SSDP_ERROR_BACKOFF: u64 = 30;
+
+// UPnPのURLパス
+pub const DLNA_PATH: &str = "dlna";
+pub const DESCRIPTION_NAME: &str = "description.xml";
+pub const CONTROL_NAME: &str = "control";
+
+// XMLのContent-Type
+pub const XML_CONTENT_TYPE: &str = "text/xml; charset=\"utf-8\"";
+
+// デバイス・サービスの種類
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+// 公開するサービス(サービス名 , サービスの種類)
+const SERVICES: [(&str, &str); 2] = [("ContentDirectory", CONTENT_DIRECTORY), ("ConnectionManager", CONNECTION_MANAGER)];
+
+// ライブ配信のDLNA属性(シーク不可 , 変換無し , ストリーミング転送 , DLNA 1.5)
+pub const CONTENT_FEATURES: &str = "DLNA.ORG_OP=00;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+// チャンネルのMIMEタイプ
+const ITEM_MIME_TYPE: &str = "video/mpeg";
+
+// アクションの引数(引数名 , 出力の場合はtrue , 関連する状態変数)
+type Argument = (&'static str, bool, &'static str);
+
+// ContentDirectoryのアクションと状態変数(変数名 , データ型 , イベント通知)
+const CONTENT_DIRECTORY_ACTIONS: [(&str, &[Argument]); 4] = [
+    ("Browse", &[
+        ("ObjectID", false, "A_ARG_TYPE_ObjectID"),
+        ("BrowseFlag", false, "A_ARG_TYPE_BrowseFlag"),
+        ("Filter", false, "A_ARG_TYPE_Filter"),
+        ("StartingIndex", false, "A_ARG_TYPE_Index"),
+        ("RequestedCount", false, "A_ARG_TYPE_Count"),
+        ("SortCriteria", false, "A_ARG_TYPE_SortCriteria"),
+        ("Result", true, "A_ARG_TYPE_Result"),
+        ("NumberReturned", true, "A_ARG_TYPE_Count"),
+        ("TotalMatches", true, "A_ARG_TYPE_Count"),
+        ("UpdateID", true, "A_ARG_TYPE_UpdateID"),
+    ]),
+    ("GetSearchCapabilities", &[("SearchCaps", true, "SearchCapabilities")]),
+    ("GetSortCapabilities", &[("SortCaps", true, "SortCapabilities")]),
+    ("GetSystemUpdateID", &[("Id", true, "SystemUpdateID")]),
+];
+const CONTENT_DIRECTORY_VARIABLES: [(&str, &str, bool); 11] = [
+    ("A_ARG_TYPE_ObjectID", "string", false),
+    ("A_ARG_TYPE_BrowseFlag", "string", false),
+    ("A_ARG_TYPE_Filter", "string", false),
+    ("A_ARG_TYPE_Index", "ui4", false),
+    ("A_ARG_TYPE_Count", "ui4", false),
+    ("A_ARG_TYPE_SortCriteria", "string", false),
+    ("A_ARG_TYPE_Result", "string", false),
+    ("A_ARG_TYPE_UpdateID", "ui4", false),
+    ("SearchCapabilities", "string", false),
+    ("SortCapabilities", "string", false),
+    ("SystemUpdateID", "ui4", true),
+];
+
+// ConnectionManagerのアクションと状態変数
+const CONNECTION_MANAGER_ACTIONS: [(&str, &[Argument]); 3] = [
+    ("GetProtocolInfo", &[("Source", true, "SourceProtocolInfo"), ("Sink", true, "SinkProtocolInfo")]),
+    ("GetCurrentConnectionIDs", &[("ConnectionIDs", true, "CurrentConnectionIDs")]),
+    ("GetCurrentConnectionInfo", &[
+        ("ConnectionID", false, "A_ARG_TYPE_ConnectionID"),
+        ("RcsID", true, "A_ARG_TYPE_RcsID"),
+        ("AVTransportID", true, "A_ARG_TYPE_AVTransportID"),
+        ("ProtocolInfo", true, "A_ARG_TYPE_ProtocolInfo"),
+        ("PeerConnectionManager", true, "A_ARG_TYPE_ConnectionManager"),
+        ("PeerConnectionID", true, "A_ARG_TYPE_ConnectionID"),
+        ("Direction", true, "A_ARG_TYPE_Direction"),
+        ("Status", true, "A_ARG_TYPE_ConnectionStatus"),
+    ]),
+];
+const CONNECTION_MANAGER_VARIABLES: [(&str, &str, bool); 10] = [
+    ("SourceProtocolInfo", "string", true),
+    ("SinkProtocolInfo", "string", true),
+    ("CurrentConnectionIDs", "string", true),
+    ("A_ARG_TYPE_ConnectionStatus", "string", false),
+    ("A_ARG_TYPE_ConnectionManager", "string", false),
+    ("A_ARG_TYPE_Direction", "string", false),
+    ("A_ARG_TYPE_ProtocolInfo", "string", false),
+    ("A_ARG_TYPE_ConnectionID", "i4", false),
+    ("A_ARG_TYPE_AVTransportID", "i4", false),
+    ("A_ARG_TYPE_RcsID", "i4", false),
+];
+
+// ホスト名
+fn host_name() -> String {
+
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "localhost".to_string())
+
+}
+
+// デバイスのUUID(ホスト名とHTTPポート番号から作成し、再起動しても変わらない)
+pub fn device_uuid(http_port: u16) -> String {
+
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(host_name().as_bytes());
+    format!("uuid:{:08x}-7265-6370-7433-{:012x}", crc, http_port)
+
+}
+
+// コンテンツの更新ID(チャンネル一覧が変わると変わる)
+fn update_id(entries: &[ChannelEntry]) -> u32 {
+
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let mut digest = crc.digest();
+    for entry in entries {
+        digest.update(entry.path().as_bytes());
+        digest.update(entry.name.as_bytes());
+    };
+    digest.finalize().max(1)
+
+}
+
+// チャンネルのオブジェクトID(/channel/sid の先頭の/を除いたもの)
+fn object_id(entry: &ChannelEntry) -> String {
+
+    entry.path().trim_start_matches('/').to_string()
+
+}
+
+// SSDPのSERVERヘッダー
+fn server_name() -> String {
+
+    format!("Linux UPnP/1.0 recpt3/{}", VERSION)
+
+}
+
+// XMLの特殊文字のエスケープ
+fn xml_escape(text: &str) -> String {
+
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+
+}
+
+// XMLの特殊文字の復元
+fn xml_unescape(text: &str) -> String {
+
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+
+}
+
+// デバイス記述(/dlna/description.xml)の作成
+pub fn description_xml(http_port: u16) -> String {
+
+    let mut services = String::new();
+    for (name, service_type) in SERVICES {
+        services.push_str(&format!(
+            "<service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:{}</serviceId>\
+            <SCPDURL>/{}/{}.xml</SCPDURL><controlURL>/{}/{}/{}</controlURL><eventSubURL>/{}/event/{}</eventSubURL></service>\n",
+            service_type, name, DLNA_PATH, name, DLNA_PATH, CONTROL_NAME, name, DLNA_PATH, name));
+    };
+
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\" xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">\n\
+        <specVersion><major>1</major><minor>0</minor></specVersion>\n\
+        <device>\n\
+        <deviceType>{}</deviceType>\n\
+        <friendlyName>{}</friendlyName>\n\
+        <manufacturer>recpt3</manufacturer>\n\
+        <modelName>recpt3</modelName>\n\
+        <modelNumber>{}</modelNumber>\n\
+        <UDN>{}</UDN>\n\
+        <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>\n\
+        <serviceList>\n{}</serviceList>\n\
+        </device>\n\
+        </root>\n",
+        DEVICE_TYPE, xml_escape(&format!("recpt3 ({})", host_name())), VERSION, device_uuid(http_port), services)
+
+}
+
+// サービス記述(/dlna/{サービス名}.xml)の作成、対象外のサービス名はNone
+pub fn service_xml(name: &str) -> Option<String> {
+
+    let (actions, variables) = match name {
+        "ContentDirectory.xml" => (&CONTENT_DIRECTORY_ACTIONS[..], &CONTENT_DIRECTORY_VARIABLES[..]),
+        "ConnectionManager.xml" => (&CONNECTION_MANAGER_ACTIONS[..], &CONNECTION_MANAGER_VARIABLES[..]),
+        _ => return None,
+    };
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\n\
+        <specVersion><major>1</major><minor>0</minor></specVersion>\n\
+        <actionList>\n");
+    for (action, arguments) in actions {
+        xml.push_str(&format!("<action><name>{}</name><argumentList>\n", action));
+        for (argument, out, variable) in arguments.iter() {
+            xml.push_str(&format!("<argument><name>{}</name><direction>{}</direction><relatedStateVariable>{}</relatedStateVariable></argument>\n",
+                argument, if *out { "out" } else { "in" }, variable));
+        };
+        xml.push_str("</argumentList></action>\n");
+    };
+    xml.push_str("</actionList>\n<serviceStateTable>\n");
+    for (variable, data_type, events) in variables {
+        xml.push_str(&format!("<stateVariable sendEvents=\"{}\"><name>{}</name><dataType>{}</dataType></stateVariable>\n",
+            if *events { "yes" } else { "no" }, variable, data_type));
+    };
+    xml.push_str("</serviceStateTable>\n</scpd>\n");
+
+    Some(xml)
+
+}
+
+// ストリーム要求へのDLNAヘッダー(getcontentFeatures.dlna.org・transferMode.dlna.orgの要求が有る場合)
+pub fn dlna_headers(request: &HttpRequest) -> Vec<(&'static str, String)> {
+
+    let mut headers = vec![];
+    if request.header("getcontentFeatures.dlna.org") == Some("1") {
+        headers.push(("contentFeatures.dlna.org", CONTENT_FEATURES.to_string()));
+    };
+    if request.header("transferMode.dlna.org").is_some() {
+        headers.push(("transferMode.dlna.org", "Streaming".to_string()));
+    };
+    headers
+
+}
+
+// SOAP要求の引数の取り出し(<名前>値</名前> , <名前/>は空文字)
+fn soap_argument(body: &str, name: &str) -> Option<String> {
+
+    if body.contains(&format!("<{}/>", name)) {
+        return Some("".to_string());
+    };
+    let start = body.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + body[start..].find(&format!("</{}>", name))?;
+
+    Some(xml_unescape(body[start..end].trim()))
+
+}
+
+// SOAP応答の作成(出力引数の値はエスケープして設定)
+fn soap_envelope(service_type: &str, action: &str, arguments: &[(&str, String)]) -> String {
+
+    let mut values = String::new();
+    for (name, value) in arguments {
+        values.push_str(&format!("<{}>{}</{}>", name, xml_escape(value), name));
+    };
+
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body><u:{}Response xmlns:u=\"{}\">{}</u:{}Response></s:Body></s:Envelope>\n",
+        action, service_type, values, action)
+
+}
+
+// SOAPエラー応答の作成(UPnPのエラーコード)
+fn soap_fault(code: u16, description: &str) -> (u16, String) {
+
+    (500, format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>\
+        <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError>\
+        </detail></s:Fault></s:Body></s:Envelope>\n", code, description))
+
+}
+
+// 制御要求(/dlna/control/{サービス名})の処理
+// ステータスコードとSOAP応答をリターン、対象外のサービス名は404
+pub fn control_response(service: &str, soap_action: Option<&str>, body: &[u8], entries: &[ChannelEntry], base_url: &str) -> (u16, String) {
+
+    let service_type = match SERVICES.iter().find(|(name, _)| *name == service) {
+        Some((_, service_type)) => *service_type,
+        None => return (404, "".to_string()),
+    };
+
+    // SOAPACTIONヘッダー("サービスの種類#アクション名")
+    let action = match soap_action.map(|value| value.trim().trim_matches('"')).and_then(|value| value.split_once('#')) {
+        Some((action_type, action)) if action_type == service_type => action,
+        _ => return soap_fault(401, "Invalid Action"),
+    };
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return soap_fault(402, "Invalid Args"),
+    };
+    debug!("DLNA control {}#{}", service, action);
+
+    let arguments = match (service_type, action) {
+        (CONTENT_DIRECTORY, "Browse") => match browse(body, entries, base_url) {
+            Ok(arguments) => arguments,
+            Err(fault) => return fault,
+        },
+        (CONTENT_DIRECTORY, "GetSearchCapabilities") => vec![("SearchCaps", "".to_string())],
+        (CONTENT_DIRECTORY, "GetSortCapabilities") => vec![("SortCaps", "".to_string())],
+        (CONTENT_DIRECTORY, "GetSystemUpdateID") => vec![("Id", update_id(entries).to_string())],
+        (CONNECTION_MANAGER, "GetProtocolInfo") => vec![
+            ("Source", format!("http-get:*:{}:{}", ITEM_MIME_TYPE, CONTENT_FEATURES)),
+            ("Sink", "".to_string()),
+        ],
+        (CONNECTION_MANAGER, "GetCurrentConnectionIDs") => vec![("ConnectionIDs", "0".to_string())],
+        (CONNECTION_MANAGER, "GetCurrentConnectionInfo") => vec![
+            ("RcsID", "-1".to_string()),
+            ("AVTransportID", "-1".to_string()),
+            ("ProtocolInfo", "".to_string()),
+            ("PeerConnectionManager", "".to_string()),
+            ("PeerConnectionID", "-1".to_string()),
+            ("Direction", "Output".to_string()),
+            ("Status", "OK".to_string()),
+        ],
+        _ => return soap_fault(401, "Invalid Action"),
+    };
+
+    (200, soap_envelope(service_type, action, &arguments))
+
+}
+
+// Browseアクション(ルートコンテナ"0"の直下に全チャンネルを配置)
+fn browse(body: &str, entries: &[ChannelEntry], base_url: &str) -> Result<Vec<(&'static str, String)>, (u16, String)> {
+
+    let (object, flag) = match (soap_argument(body, "ObjectID"), soap_argument(body, "BrowseFlag")) {
+        (Some(object), Some(flag)) => (object, flag),
+        _ => return Err(soap_fault(402, "Invalid Args")),
+    };
+    let start = soap_argument(body, "StartingIndex").unwrap_or_default().parse::<usize>().unwrap_or(0);
+    let count = soap_argument(body, "RequestedCount").unwrap_or_default().parse::<usize>().unwrap_or(0);
+
+    // 対象のオブジェクト(DIDL-Liteの要素)と総数
+    let (objects, total) = match (&object[..], &flag[..]) {
+        ("0", "BrowseMetadata") => (vec![root_container(entries.len())], 1),
+        ("0", "BrowseDirectChildren") => {
+            let count = if count == 0 { entries.len() } else { count };
+            (entries.iter().skip(start).take(count).map(|entry| channel_item(entry, base_url)).collect(), entries.len())
+        },
+        (object, "BrowseMetadata") => match entries.iter().find(|entry| object_id(entry) == object) {
+            Some(entry) => (vec![channel_item(entry, base_url)], 1),
+            None => return Err(soap_fault(701, "No such object")),
+        },
+        (object, "BrowseDirectChildren") => match entries.iter().any(|entry| object_id(entry) == object) {
+            true => (vec![], 0),
+            false => return Err(soap_fault(701, "No such object")),
+        },
+        _ => return Err(soap_fault(402, "Invalid Args")),
+    };
+
+    let didl = format!("<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+        xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">{}</DIDL-Lite>", objects.concat());
+
+    Ok(vec![
+        ("Result", didl),
+        ("NumberReturned", objects.len().to_string()),
+        ("TotalMatches", total.to_string()),
+        ("UpdateID", update_id(entries).to_string()),
+    ])
+
+}
+
+// ルートコンテナの要素
+fn root_container(children: usize) -> String {
+
+    format!("<container id=\"0\" parentID=\"-1\" restricted=\"1\" searchable=\"0\" childCount=\"{}\">\
+        <dc:title>recpt3</dc:title><upnp:class>object.container.storageFolder</upnp:class></container>", children)
+
+}
+
+// チャンネルの要素(再生URLは/channel/sidのストリーム)
+fn channel_item(entry: &ChannelEntry, base_url: &str) -> String {
+
+    let channel_number = match entry.number.parse::<u32>() {
+        Ok(number) => format!("<upnp:channelNr>{}</upnp:channelNr>", number),
+        Err(_) => "".to_string(),
+    };
+
+    format!("<item id=\"{}\" parentID=\"0\" restricted=\"1\"><dc:title>{}</dc:title>\
+        <upnp:class>object.item.videoItem.videoBroadcast</upnp:class><upnp:channelName>{}</upnp:channelName>{}\
+        <res protocolInfo=\"http-get:*:{}:{}\">{}{}</res></item>",
+        xml_escape(&object_id(entry)), xml_escape(&entry.name), xml_escape(&entry.name), channel_number,
+        ITEM_MIME_TYPE, CONTENT_FEATURES, xml_escape(base_url), xml_escape(&entry.path()))
+
+}
+
+// SSDPメッセージの解析(開始行とヘッダー一覧、ヘッダー名は大文字に統一)
+fn parse_ssdp(packet: &[u8]) -> Option<(String, Vec<(String, String)>)> {
+
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n").flat_map(|line| line.split('\n'));
+    let start_line = lines.next()?.trim().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_uppercase(), value.trim().to_string()))
+        .collect();
+
+    Some((start_line, headers))
+
+}
+
+// SSDP応答・通知の対象(NT/STの値 , USN)
+fn notification_types(uuid: &str) -> Vec<(String, String)> {
+
+    let mut types = vec![
+        ("upnp:rootdevice".to_string(), format!("{}::upnp:rootdevice", uuid)),
+        (uuid.to_string(), uuid.to_string()),
+        (DEVICE_TYPE.to_string(), format!("{}::{}", uuid, DEVICE_TYPE)),
+    ];
+    for (_, service_type) in SERVICES {
+        types.push((service_type.to_string(), format!("{}::{}", uuid, service_type)));
+    };
+    types
+
+}
+
+// M-SEARCH要求への応答作成(対象外の要求は空)
+pub fn search_replies(request: &[u8], uuid: &str, location: &str) -> Vec<String> {
+
+    let (start_line, headers) = match parse_ssdp(request) {
+        Some(message) => message,
+        None => return vec![],
+    };
+    let header = |name: &str| headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    if start_line.starts_with("M-SEARCH ") == false || header("MAN").map(|man| man.trim_matches('"')) != Some("ssdp:discover") {
+        return vec![];
+    };
+    let search_target = header("ST").unwrap_or("");
+
+    notification_types(uuid).into_iter()
+        .filter(|(target, _)| search_target == "ssdp:all" || search_target == target)
+        .map(|(target, usn)| format!("HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nDATE: {}\r\nEXT:\r\nLOCATION: {}\r\nSERVER: {}\r\nST: {}\r\nUSN: {}\r\n\r\n",
+            SSDP_MAX_AGE, chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT"), location, server_name(), target, usn))
+        .collect()
+
+}
+
+// NOTIFYメッセージの作成(ssdp:alive , ssdp:byebye)
+fn notify_messages(uuid: &str, location: &str, alive: bool) -> Vec<String> {
+
+    notification_types(uuid).into_iter()
+        .map(|(target, usn)| match alive {
+            true => format!("NOTIFY * HTTP/1.1\r\nHOST: {}:{}\r\nCACHE-CONTROL: max-age={}\r\nLOCATION: {}\r\nNT: {}\r\nNTS: ssdp:alive\r\nSERVER: {}\r\nUSN: {}\r\n\r\n",
+                SSDP_ADDR, SSDP_PORT, SSDP_MAX_AGE, location, target, server_name(), usn),
+            false => format!("NOTIFY * HTTP/1.1\r\nHOST: {}:{}\r\nNT: {}\r\nNTS: ssdp:byebye\r\nUSN: {}\r\n\r\n",
+                SSDP_ADDR, SSDP_PORT, target, usn),
+        })
+        .collect()
+
+}
+
+// デバイス記述のURL
+fn location_url(addr: IpAddr, http_port: u16) -> String {
+
+    format!("http://{}/{}/{}", SocketAddr::new(addr, http_port), DLNA_PATH, DESCRIPTION_NAME)
+
+}
+
+// SSDPソケットの作成(他のSSDPサービスと共存するためSO_REUSEADDRを設定し、指定インターフェースでマルチキャストに参加)
+fn ssdp_socket(interface: Ipv4Addr) -> std::io::Result<UdpSocket> {
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), SSDP_PORT).into())?;
+    socket.join_multicast_v4(&SSDP_ADDR, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(4)?;
+    socket.set_read_timeout(Some(Duration::from_secs(SSDP_POLL)))?;
+
+    Ok(socket.into())
+
+}
+
+// SSDP応答スレッド
+#[allow(dead_code)]
+pub struct SsdpResponder {
+    socket: UdpSocket,
+    // 通知の送信先(SSDPのマルチキャストグループ)
+    group: SocketAddr,
+    uuid: String,
+    location: String,
+    stopped: Arc<AtomicBool>,
+}
+
+#[allow(dead_code)]
+impl SsdpResponder {
+
+    // 通知の送信(マルチキャスト)
+    fn notify(socket: &UdpSocket, group: SocketAddr, uuid: &str, location: &str, alive: bool) {
+
+        for message in notify_messages(uuid, location, alive) {
+            if let Err(e) = socket.send_to(message.as_bytes(), group) {
+                error!("SSDP notify send error ({})", e);
+                return;
+            };
+        };
+
+    }
+
+    // 応答の停止(ssdp:byebyeを送信)
+    pub fn stop(&self) {
+
+        self.stopped.store(true, Ordering::Release);
+        SsdpResponder::notify(&self.socket, self.group, &self.uuid, &self.location, false);
+        info!("SSDP responder stopped");
+
+    }
+
+}
+
+// SSDP応答スレッドの起動(UDP 1900)
+// interfaceはマルチキャストに参加するインターフェースのアドレス(空の場合は既定のインターフェース)
+pub fn start_ssdp_responder(http_port: u16, interface: &str, access: Arc<AccessControl>) -> Option<SsdpResponder> {
+
+    let interface = match interface {
+        "" => Ipv4Addr::UNSPECIFIED,
+        interface => match interface.parse::<Ipv4Addr>() {
+            Ok(interface) => interface,
+            Err(_) => {
+                error!("Bad DLNA interface address ({})", interface);
+                return None;
+            },
+        },
+    };
+    let socket = match ssdp_socket(interface) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("SSDP bind error (udp/{}, {})", SSDP_PORT, e);
+            return None;
+        },
+    };
+
+    // 通知するデバイス記述のURL(インターフェース指定時はそのアドレス)
+    let group = SocketAddr::V4(SocketAddrV4::new(SSDP_ADDR, SSDP_PORT));
+    let notify_addr = match interface.is_unspecified() {
+        true => local_addr_for(&group),
+        false => IpAddr::V4(interface),
+    };
+    let location = location_url(notify_addr, http_port);
+    info!("SSDP responder (udp/{}, {}, {})", SSDP_PORT, device_uuid(http_port), location);
+
+    spawn_ssdp_responder(socket, group, http_port, &location, access)
+
+}
+
+// バインド済みソケットでのSSDP応答スレッドの起動
+// 通知はgroupへ送信し、M-SEARCH要求には要求元へユニキャストで応答する
+fn spawn_ssdp_responder(socket: UdpSocket, group: SocketAddr, http_port: u16, location: &str, access: Arc<AccessControl>) -> Option<SsdpResponder> {
+
+    let thread_socket = match socket.try_clone() {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("SSDP socket error ({})", e);
+            return None;
+        },
+    };
+
+    let uuid = device_uuid(http_port);
+    let location = location.to_string();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let responder = SsdpResponder {
+        socket: socket,
+        group: group,
+        uuid: uuid.to_string(),
+        location: location.to_string(),
+        stopped: Arc::clone(&stopped),
+    };
+
+    thread::spawn(move || {
+
+        let socket = thread_socket;
+        let mut buffer = [0u8; 2048];
+        let mut next_notify = Instant::now();
+        let mut errors: u32 = 0;
+
+        loop {
+
+            if stopped.load(Ordering::Acquire) {
+                break;
+            };
+
+            // 有効期間の半分毎にssdp:aliveを送信
+            if Instant::now() >= next_notify {
+                SsdpResponder::notify(&socket, group, &uuid, &location, true);
+                next_notify = Instant::now() + Duration::from_secs(SSDP_MAX_AGE / 2);
+            };
+
+            let (length, peer) = match socket.recv_from(&mut buffer) {
+                Ok(received) => {
+                    errors = 0;
+                    received
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    errors = 0;
+                    continue;
+                },
+                Err(e) => {
+                    // 連続したエラーは再試行間隔を倍に延ばす(ログは1,2,4,8...回目のみ)
+                    errors = errors.saturating_add(1);
+                    if errors.is_power_of_two() {
+                        error!("SSDP receive error ({}, {} times)", e, errors);
+                    };
+                    let backoff = (SSDP_POLL << (errors - 1).min(5)).min(SSDP_ERROR_BACKOFF);
+                    thread::sleep(Duration::from_secs(backoff));
+                    continue;
+                },
+            };
+
+            // 許可されていないアドレスからの要求は無視
+            if access.is_allowed(&peer.ip()) == false {
+                debug!("SSDP search denied {}", peer);
+                continue;
+            };
+
+            // 応答するデバイス記述のURL(要求元から到達できるアドレス)
+            let replies = search_replies(&buffer[..length], &uuid, &location_url(local_addr_for(&peer), http_port));
+            if replies.len() > 0 {
+                debug!("SSDP search request from {}", peer);
+            };
+            for reply in replies {
+                if let Err(e) = socket.send_to(reply.as_bytes(), peer) {
+                    error!("SSDP send error ({}, {})", peer, e);
+                    break;
+                };
+            };
+
+        };
+
+    });
+
+    Some(responder)
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const UUID: &str = "uuid:00000000-7265-6370-7433-00000000a0ec";
+    const LOCATION: &str = "http://127.0.0.1:40772/dlna/description.xml";
+    const BASE_URL: &str = "http://127.0.0.1:40772";
+
+    fn entry(channel: &str, sid: &str, name: &str, number: &str) -> ChannelEntry {
+
+        ChannelEntry {
+            channel: channel.to_string(),
+            sid: sid.to_string(),
+            name: name.to_string(),
+            number: number.to_string(),
+            channel_type: "T".to_string(),
+            group: "".to_string(),
+        }
+
+    }
+
+    fn entries() -> Vec<ChannelEntry> {
+
+        vec![entry("27", "1024", "NHK総合", "1"), entry("26", "1032", "NHK Eテレ", "2"), entry("BS15_0", "hd", "A&B <BS>", "")]
+
+    }
+
+    // 応答のヘッダーの値
+    fn header_value<'a>(reply: &'a str, name: &str) -> Option<&'a str> {
+
+        reply.split("\r\n").filter_map(|line| line.split_once(": ")).find(|(key, _)| *key == name).map(|(_, value)| value)
+
+    }
+
+    // Browse要求のSOAP本文
+    fn browse_body(object: &str, flag: &str, start: usize, count: usize) -> Vec<u8> {
+
+        format!("<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+            <u:Browse xmlns:u=\"{}\"><ObjectID>{}</ObjectID><BrowseFlag>{}</BrowseFlag><Filter>*</Filter>\
+            <StartingIndex>{}</StartingIndex><RequestedCount>{}</RequestedCount><SortCriteria/></u:Browse></s:Body></s:Envelope>",
+            CONTENT_DIRECTORY, object, flag, start, count).into_bytes()
+
+    }
+
+    fn browse(body: &[u8]) -> (u16, String) {
+
+        let action = format!("\"{}#Browse\"", CONTENT_DIRECTORY);
+        control_response("ContentDirectory", Some(&action), body, &entries(), BASE_URL)
+
+    }
+
+    // 通知の受信(NTSの値の一覧)
+    fn receive_notify(client: &UdpSocket, from: SocketAddr) -> Vec<String> {
+
+        let mut buffer = [0u8; 2048];
+        (0..notification_types(UUID).len()).map(|_| {
+            let (length, peer) = client.recv_from(&mut buffer).unwrap();
+            assert_eq!(peer, from);
+            let message = String::from_utf8_lossy(&buffer[..length]).to_string();
+            assert!(message.starts_with("NOTIFY * HTTP/1.1\r\n"));
+            header_value(&message, "NTS").unwrap().to_string()
+        }).collect()
+
+    }
+
+    // 127.0.0.1上の応答スレッドへM-SEARCHを送信し、ユニキャストの応答を確認
+    // 通知の送信先はテスト用のクライアント(開始時にssdp:alive、停止時にssdp:byebye)
+    #[test]
+    fn search_round_trip() {
+
+        let access = Arc::new(AccessControl { allow: vec![], deny: vec![], credentials: vec![] });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(SSDP_POLL))).unwrap();
+        let responder_addr = socket.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let responder = spawn_ssdp_responder(socket, client.local_addr().unwrap(), 40772, LOCATION, access).unwrap();
+        assert!(receive_notify(&client, responder_addr).iter().all(|nts| nts == "ssdp:alive"));
+
+        let request = format!("M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n", DEVICE_TYPE);
+        client.send_to(request.as_bytes(), responder_addr).unwrap();
+
+        let mut buffer = [0u8; 2048];
+        let (length, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(from, responder_addr);
+        let reply = String::from_utf8_lossy(&buffer[..length]).to_string();
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(header_value(&reply, "ST"), Some(DEVICE_TYPE));
+        assert_eq!(header_value(&reply, "USN"), Some(&format!("{}::{}", device_uuid(40772), DEVICE_TYPE)[..]));
+        assert_eq!(header_value(&reply, "LOCATION"), Some(LOCATION));
+
+        responder.stop();
+        assert!(receive_notify(&client, responder_addr).iter().all(|nts| nts == "ssdp:byebye"));
+
+    }
+
+    // ssdp:allは全ての対象に応答
+    #[test]
+    fn search_all() {
+
+        let request = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: ssdp:all\r\n\r\n";
+        let replies = search_replies(request, UUID, LOCATION);
+        assert_eq!(replies.len(), 5);
+        for reply in &replies {
+            assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(reply.ends_with("\r\n\r\n"));
+            assert_eq!(header_value(reply, "LOCATION"), Some(LOCATION));
+            assert!(header_value(reply, "USN").unwrap().starts_with(UUID));
+        };
+        let targets: Vec<&str> = replies.iter().filter_map(|reply| header_value(reply, "ST")).collect();
+        assert_eq!(targets, vec!["upnp:rootdevice", UUID, DEVICE_TYPE, CONTENT_DIRECTORY, CONNECTION_MANAGER]);
+
+    }
+
+    // 指定した対象のみ応答(ヘッダー名の大文字小文字・LFのみの改行も受け付ける)
+    #[test]
+    fn search_target() {
+
+        let request = format!("M-SEARCH * HTTP/1.1\nhost: 239.255.255.250:1900\nman: \"ssdp:discover\"\nst: {}\n\n", CONTENT_DIRECTORY);
+        let replies = search_replies(request.as_bytes(), UUID, LOCATION);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(header_value(&replies[0], "ST"), Some(CONTENT_DIRECTORY));
+        assert_eq!(header_value(&replies[0], "USN"), Some(&format!("{}::{}", UUID, CONTENT_DIRECTORY)[..]));
+
+        let request = format!("M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: {}\r\n\r\n", UUID);
+        assert_eq!(search_replies(request.as_bytes(), UUID, LOCATION).len(), 1);
+
+    }
+
+    // M-SEARCH以外・MANが不正・対象外のSTには応答しない
+    #[test]
+    fn search_ignored() {
+
+        let requests: [&[u8]; 5] = [
+            b"NOTIFY * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: ssdp:all\r\n\r\n",
+            b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n",
+            b"M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:update\"\r\nST: ssdp:all\r\n\r\n",
+            b"M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: urn:schemas-upnp-org:device:MediaRenderer:1\r\n\r\n",
+            b"\xff\xfe",
+        ];
+        for request in requests {
+            assert_eq!(search_replies(request, UUID, LOCATION).len(), 0);
+        };
+
+    }
+
+    // ルートコンテナの子要素をページ単位で取得
+    #[test]
+    fn browse_children() {
+
+        let (status, response) = browse(&browse_body("0", "BrowseDirectChildren", 0, 0));
+        assert_eq!(status, 200);
+        assert!(response.contains(&format!("<u:BrowseResponse xmlns:u=\"{}\">", CONTENT_DIRECTORY)));
+        assert_eq!(soap_argument(&response, "NumberReturned").as_deref(), Some("3"));
+        assert_eq!(soap_argument(&response, "TotalMatches").as_deref(), Some("3"));
+        assert_eq!(soap_argument(&response, "UpdateID"), Some(update_id(&entries()).to_string()));
+
+        // ResultのDIDL-Liteはエスケープされているため、復元して内容を確認
+        let didl = soap_argument(&response, "Result").unwrap();
+        assert!(didl.starts_with("<DIDL-Lite "));
+        assert!(didl.contains("<item id=\"27/1024\" parentID=\"0\" restricted=\"1\"><dc:title>NHK総合</dc:title>"));
+        assert!(didl.contains("<upnp:channelNr>1</upnp:channelNr>"));
+        assert!(didl.contains(&format!(">{}/27/1024</res>", BASE_URL)));
+        assert!(didl.contains("<dc:title>A&amp;B &lt;BS&gt;</dc:title>"));
+
+        let (status, response) = browse(&browse_body("0", "BrowseDirectChildren", 1, 1));
+        assert_eq!(status, 200);
+        assert_eq!(soap_argument(&response, "NumberReturned").as_deref(), Some("1"));
+        assert_eq!(soap_argument(&response, "TotalMatches").as_deref(), Some("3"));
+        assert!(soap_argument(&response, "Result").unwrap().contains("<item id=\"26/1032\""));
+
+    }
+
+    // コンテナ・チャンネルのメタデータ
+    #[test]
+    fn browse_metadata() {
+
+        let (status, response) = browse(&browse_body("0", "BrowseMetadata", 0, 0));
+        assert_eq!(status, 200);
+        assert!(soap_argument(&response, "Result").unwrap().contains("<container id=\"0\" parentID=\"-1\" restricted=\"1\" searchable=\"0\" childCount=\"3\">"));
+
+        let (status, response) = browse(&browse_body("BS15_0/hd", "BrowseMetadata", 0, 0));
+        assert_eq!(status, 200);
+        assert_eq!(soap_argument(&response, "NumberReturned").as_deref(), Some("1"));
+        assert!(soap_argument(&response, "Result").unwrap().contains("<item id=\"BS15_0/hd\""));
+
+    }
+
+    // 不明なオブジェクト・引数不足・不明なアクション・不明なサービス
+    #[test]
+    fn browse_faults() {
+
+        let (status, response) = browse(&browse_body("99/1", "BrowseMetadata", 0, 0));
+        assert_eq!(status, 500);
+        assert_eq!(soap_argument(&response, "errorCode").as_deref(), Some("701"));
+
+        let (status, response) = browse(b"<s:Envelope><s:Body><u:Browse><ObjectID>0</ObjectID></u:Browse></s:Body></s:Envelope>");
+        assert_eq!(status, 500);
+        assert_eq!(soap_argument(&response, "errorCode").as_deref(), Some("402"));
+
+        let action = format!("\"{}#Browse\"", CONNECTION_MANAGER);
+        let (status, response) = control_response("ContentDirectory", Some(&action), &browse_body("0", "BrowseMetadata", 0, 0), &entries(), BASE_URL);
+        assert_eq!(status, 500);
+        assert_eq!(soap_argument(&response, "errorCode").as_deref(), Some("401"));
+
+        let (status, _) = control_response("AVTransport", None, b"", &entries(), BASE_URL);
+        assert_eq!(status, 404);
+
+    }
+
+}
@@ -16,12 +16,16 @@ mod arib_b25;
 mod commands;
 mod decoder;
 mod ffi;
+mod metrics;
+mod output;
+mod pipeline;
+mod ring_queue;
 mod ts_splitter_core;
 mod tuner;
 
 use crate::commands::{PROGRAM_DROP_CHECK};
-use crate::ts_splitter_core::{LENGTH_PACKET, MAX_PID, get_pid, split_select, split_startup,
-    TSS_ERROR, TSS_SUCCESS};
+use crate::pipeline::DropCheck;
+use crate::ts_splitter_core::{MAX_PID, split_select, split_startup, TSS_ERROR, TSS_SUCCESS};
 use crate::tuner::CAP;
 
 pub const VERSION: &str = env!("VERSION_TS_SPLITTER");
@@ -159,10 +163,8 @@ fn drop_check(command_opt: &mut CommanLineOpt) -> () {
         .unwrap()
         .progress_chars("#>-"));
 
-    // パケット巡回カウンター変数の作成
-    let mut continuity_counter: i32;
-    let mut continuity_counter_flag: [i32; MAX_PID] = [0; MAX_PID];
-    let mut next_continuity_counter: [i32; MAX_PID] = [0; MAX_PID];
+    // パケット巡回カウンター
+    let mut drop_check = DropCheck::new();
 
     loop {
         let length = {
@@ -173,42 +175,18 @@ fn drop_check(command_opt: &mut CommanLineOpt) -> () {
             // ファイル終端以外の処理
             if read_buffer.len() > 0 {
 
-                // データバッファ作成
-                let mut data_buff: Vec<u8> = vec![0; read_buffer.len()];
-                data_buff[..read_buffer.len()].copy_from_slice(&read_buffer[..read_buffer.len()]);
+                // 対象PIDの確定
                 if split_select_finish != TSS_SUCCESS {
-                    split_select_finish = split_select(&mut sp, &mut data_buff);
+                    split_select_finish = split_select(&mut sp, read_buffer);
                 };
 
-                // バッファ処理インデックス
-                let mut index = 0;
+                // パケットドロップチェック
+                drop_check.check(&sp, read_buffer, |pid, continuity_counter, next_continuity_counter| {
 
-                // バッファ終了までループ
-                while (data_buff.len() as i32 - index as i32 - LENGTH_PACKET as i32) >= 0 {
+                    warn!("パケットドロップ PID={}(0x{:04x}) , continuity_counter={} , next_continuity_counter={}\n",
+                        pid, pid, continuity_counter, next_continuity_counter);
 
-                    // PID取得
-                    let pid = get_pid(&data_buff[index..index + LENGTH_PACKET - 1]) as usize;
-
-                    // パケット巡回カウンターの作成
-                    continuity_counter = (data_buff[index + 3] & 0x0f) as i32;
-
-                    // パケットドロップチェック
-                    if (sp.pmt_pids[pid] > 0 || (sp.pids[pid] > 0 && pid < 0x100)) &&
-                        continuity_counter_flag[pid] == 1 && continuity_counter != next_continuity_counter[pid] {
-
-                        warn!("パケットドロップ PID={}(0x{:04x}) , continuity_counter={} , next_continuity_counter={}\n",
-                            pid, pid, continuity_counter, next_continuity_counter[pid]);
-
-                    };
-
-                    // 次パケット巡回カウンターの計算
-                    next_continuity_counter[pid] = (continuity_counter + 1) & 0x0f;
-                    continuity_counter_flag[pid] = 1;
-
-                    // 次パケットまでインデックス更新
-                    index += LENGTH_PACKET;
-
-                };
+                });
             };
 
             // リターン情報
@@ -0,0 +1,132 @@
+use log::debug;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Server-Sent EventsのContent-Type
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+// 受信側が遅れた場合に保持するイベント数
+const EVENT_CAPACITY: usize = 1024;
+
+// 監視イベント
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(dead_code)]
+pub enum Event {
+    // セッション開始
+    SessionStarted { session: u64, channel: String, device: String },
+    // セッション終了
+    SessionStopped { session: u64, channel: String, device: String, bytes: u64, drops: u64 },
+    // C/N(dB)
+    Signal { session: u64, channel: String, device: String, cn: f32 },
+    // パケットドロップ(巡回カウンターエラー)の集計(PID毎)
+    ContinuityErrors { session: u64, channel: String, device: String, pid: u16, count: u64 },
+    // B25デコードエラー(デコード失敗回数 , スクランブルが解除されていないパケット数)
+    B25Errors { session: u64, channel: String, device: String, decode_errors: u64, scrambled: u64 },
+    // PAT・PMTのバージョン変更(PMTはSID分離中の購読者のrescan_pidで検出)
+    PsiChanged { session: u64, channel: String, table: String, pid: u16, version: u8, client: Option<String> },
+    // 受信側の遅れで破棄したイベント数
+    Lagged { missed: u64 },
+    // デーモン終了
+    DaemonStopping {},
+}
+
+#[allow(dead_code)]
+impl Event {
+
+    // イベント名(SSEのevent行)
+    pub fn name(&self) -> &'static str {
+
+        match self {
+            Event::SessionStarted { .. } => "session_started",
+            Event::SessionStopped { .. } => "session_stopped",
+            Event::Signal { .. } => "signal",
+            Event::ContinuityErrors { .. } => "continuity_errors",
+            Event::B25Errors { .. } => "b25_errors",
+            Event::PsiChanged { .. } => "psi_changed",
+            Event::Lagged { .. } => "lagged",
+            Event::DaemonStopping {} => "daemon_stopping",
+        }
+
+    }
+
+}
+
+// 配信するイベント(通し番号と発生時刻を付加)
+#[derive(Serialize, Debug, Clone)]
+pub struct EventMessage {
+    pub id: u64,
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[allow(dead_code)]
+impl EventMessage {
+
+    // SSEの形式(id , event , data行)
+    pub fn to_sse(&self) -> String {
+
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.event.name(), serde_json::to_string(self).unwrap_or_default())
+
+    }
+
+}
+
+// イベントの配信(セッションスレッド・HTTPの各タスクから/api/eventsの接続へ)
+#[allow(dead_code)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<EventMessage>>,
+    next_id: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl EventBus {
+
+    // 作成
+    pub fn new() -> EventBus {
+
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+
+        EventBus {
+            sender: sender,
+            next_id: AtomicU64::new(1),
+        }
+
+    }
+
+    // イベントの送信(受信側が無い場合は破棄)
+    pub fn publish(&self, event: Event) {
+
+        if self.sender.receiver_count() == 0 {
+            return;
+        };
+
+        let message = EventMessage {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0),
+            event: event,
+        };
+        debug!("Event {}", message.event.name());
+        let _ = self.sender.send(Arc::new(message));
+
+    }
+
+    // 受信の開始
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<EventMessage>> {
+
+        self.sender.subscribe()
+
+    }
+
+    // 受信中の接続数
+    pub fn listeners(&self) -> usize {
+
+        self.sender.receiver_count()
+
+    }
+
+}
@@ -0,0 +1,392 @@
+use crc::{Crc, CRC_32_ISO_HDLC};
+use log::{debug, error, info};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::access::AccessControl;
+use crate::channels::ChannelEntry;
+
+// HDHomeRunディスカバリーのUDPポート
+pub const DISCOVER_PORT: u16 = 65001;
+
+// 受信エラーが続く場合の再試行間隔の初期値・上限(秒)
+const DISCOVER_ERROR_WAIT: u64 = 1;
+const DISCOVER_ERROR_BACKOFF: u64 = 30;
+
+// ディスカバリーパケットの種類
+const TYPE_DISCOVER_REQ: u16 = 0x0002;
+const TYPE_DISCOVER_RPY: u16 = 0x0003;
+
+// ディスカバリーパケットのタグ
+const TAG_DEVICE_TYPE: u8 = 0x01;
+const TAG_DEVICE_ID: u8 = 0x02;
+const TAG_TUNER_COUNT: u8 = 0x10;
+const TAG_LINEUP_URL: u8 = 0x27;
+const TAG_BASE_URL: u8 = 0x2A;
+const TAG_DEVICE_AUTH_STR: u8 = 0x2B;
+
+// デバイス種別(チューナー)とワイルドカード
+const DEVICE_TYPE_TUNER: u32 = 0x0000_0001;
+const DEVICE_WILDCARD: u32 = 0xFFFF_FFFF;
+
+// エミュレーションするモデル情報
+const FRIENDLY_NAME: &str = "recpt3";
+const MANUFACTURER: &str = "Silicondust";
+const MODEL_NUMBER: &str = "HDTC-2US";
+const FIRMWARE_NAME: &str = "hdhomeruntc_atsc";
+const FIRMWARE_VERSION: &str = "20200101";
+const DEVICE_AUTH: &str = "recpt3";
+
+// デバイスIDのチェックサム用テーブル
+const CHECKSUM_TABLE: [u32; 16] = [0xA, 0x5, 0xF, 0x6, 0x7, 0xC, 0x1, 0xB, 0x9, 0x2, 0x8, 0xD, 0x4, 0x3, 0xE, 0x0];
+
+// /discover.json
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Discover {
+    friendly_name: String,
+    manufacturer: String,
+    model_number: String,
+    firmware_name: String,
+    firmware_version: String,
+    #[serde(rename = "DeviceID")]
+    device_id: String,
+    device_auth: String,
+    tuner_count: usize,
+    #[serde(rename = "BaseURL")]
+    base_url: String,
+    #[serde(rename = "LineupURL")]
+    lineup_url: String,
+}
+
+// /lineup.jsonの項目
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LineupItem {
+    guide_number: String,
+    guide_name: String,
+    #[serde(rename = "URL")]
+    url: String,
+}
+
+// /lineup_status.json
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LineupStatus {
+    scan_in_progress: u8,
+    scan_possible: u8,
+    source: String,
+    source_list: Vec<String>,
+}
+
+// デバイスIDのチェックサム計算(上位7桁から最下位桁を決める)
+fn device_id_checksum(device_id: u32) -> u32 {
+
+    let mut checksum = 0;
+    for shift in (4..32).step_by(4).rev() {
+        let nibble = (device_id >> shift) & 0x0F;
+        checksum ^= if (shift / 4) % 2 == 1 { CHECKSUM_TABLE[nibble as usize] } else { nibble };
+    };
+    checksum
+
+}
+
+// デバイスIDの作成(HTTPポート番号から作成し、チェックサムが合うように最下位桁を設定)
+pub fn device_id(http_port: u16) -> u32 {
+
+    let base = 0x1050_0000 | ((http_port as u32) << 4);
+    (base & 0xFFFF_FFF0) | device_id_checksum(base)
+
+}
+
+// lineupのガイド番号からチャンネルを検索
+pub fn find_lineup<'a>(entries: &'a [ChannelEntry], guide_number: &str) -> Option<&'a ChannelEntry> {
+
+    entries.iter().find(|entry| entry.number.eq_ignore_ascii_case(guide_number))
+
+}
+
+// /discover.jsonの作成
+pub fn discover_json(base_url: &str, tuner_count: usize, http_port: u16) -> String {
+
+    let discover = Discover {
+        friendly_name: FRIENDLY_NAME.to_string(),
+        manufacturer: MANUFACTURER.to_string(),
+        model_number: MODEL_NUMBER.to_string(),
+        firmware_name: FIRMWARE_NAME.to_string(),
+        firmware_version: FIRMWARE_VERSION.to_string(),
+        device_id: format!("{:08X}", device_id(http_port)),
+        device_auth: DEVICE_AUTH.to_string(),
+        tuner_count: tuner_count,
+        base_url: base_url.to_string(),
+        lineup_url: format!("{}/lineup.json", base_url),
+    };
+
+    serde_json::to_string_pretty(&discover).unwrap_or_default()
+
+}
+
+// /lineup.jsonの作成(ストリームは/auto/v<ガイド番号>)
+pub fn lineup_json(entries: &[ChannelEntry], base_url: &str) -> String {
+
+    let lineup: Vec<LineupItem> = entries.iter().map(|entry| LineupItem {
+        guide_number: entry.number.to_string(),
+        guide_name: entry.name.to_string(),
+        url: format!("{}/auto/v{}", base_url, entry.number),
+    }).collect();
+
+    serde_json::to_string_pretty(&lineup).unwrap_or_default()
+
+}
+
+// /lineup_status.jsonの作成(チャンネルスキャンは非対応)
+pub fn lineup_status_json() -> String {
+
+    let status = LineupStatus {
+        scan_in_progress: 0,
+        scan_possible: 0,
+        source: "Antenna".to_string(),
+        source_list: vec!["Antenna".to_string()],
+    };
+
+    serde_json::to_string_pretty(&status).unwrap_or_default()
+
+}
+
+// TLV形式のタグ追加(長さは128以上の場合に2バイト)
+fn push_tag(payload: &mut Vec<u8>, tag: u8, value: &[u8]) {
+
+    payload.push(tag);
+    if value.len() < 0x80 {
+        payload.push(value.len() as u8);
+    }
+    else {
+        payload.push((value.len() & 0x7F) as u8 | 0x80);
+        payload.push((value.len() >> 7) as u8);
+    };
+    payload.extend_from_slice(value);
+
+}
+
+// パケットの作成(種類＋長さ＋ペイロード＋CRC32リトルエンディアン)
+fn build_packet(packet_type: u16, payload: &[u8]) -> Vec<u8> {
+
+    let mut packet = Vec::with_capacity(payload.len() + 8);
+    packet.extend_from_slice(&packet_type.to_be_bytes());
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&packet);
+    packet.extend_from_slice(&crc.to_le_bytes());
+
+    packet
+
+}
+
+// パケットの解析(CRCが正しい場合に種類とタグ一覧をリターン)
+fn parse_packet(packet: &[u8]) -> Option<(u16, Vec<(u8, Vec<u8>)>)> {
+
+    if packet.len() < 8 {
+        return None;
+    };
+
+    // CRCチェック
+    let (body, crc) = packet.split_at(packet.len() - 4);
+    let crc = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+    if Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(body) != crc {
+        return None;
+    };
+
+    // 種類と長さ
+    let packet_type = u16::from_be_bytes([body[0], body[1]]);
+    let length = u16::from_be_bytes([body[2], body[3]]) as usize;
+    let payload = body.get(4..4 + length)?;
+
+    // タグの分解
+    let mut tags = vec![];
+    let mut pos = 0;
+    while pos + 2 <= payload.len() {
+        let tag = payload[pos];
+        let mut value_len = payload[pos + 1] as usize;
+        pos += 2;
+        if value_len & 0x80 != 0 {
+            value_len = (value_len & 0x7F) | ((*payload.get(pos)? as usize) << 7);
+            pos += 1;
+        };
+        tags.push((tag, payload.get(pos..pos + value_len)?.to_vec()));
+        pos += value_len;
+    };
+
+    Some((packet_type, tags))
+
+}
+
+// ディスカバリー要求への応答作成(対象外の要求はNone)
+pub fn discover_reply(request: &[u8], device_id: u32, tuner_count: usize, base_url: &str) -> Option<Vec<u8>> {
+
+    let (packet_type, tags) = parse_packet(request)?;
+    if packet_type != TYPE_DISCOVER_REQ {
+        return None;
+    };
+
+    // デバイス種別・デバイスIDの指定チェック
+    for (tag, value) in &tags {
+        if value.len() != 4 { continue; };
+        let value = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+        match *tag {
+            TAG_DEVICE_TYPE if value != DEVICE_WILDCARD && value != DEVICE_TYPE_TUNER => return None,
+            TAG_DEVICE_ID if value != DEVICE_WILDCARD && value != device_id => return None,
+            _ => {},
+        };
+    };
+
+    // 応答の作成
+    let mut payload = vec![];
+    push_tag(&mut payload, TAG_DEVICE_TYPE, &DEVICE_TYPE_TUNER.to_be_bytes());
+    push_tag(&mut payload, TAG_DEVICE_ID, &device_id.to_be_bytes());
+    push_tag(&mut payload, TAG_TUNER_COUNT, &[tuner_count.min(255) as u8]);
+    push_tag(&mut payload, TAG_BASE_URL, base_url.as_bytes());
+    push_tag(&mut payload, TAG_LINEUP_URL, format!("{}/lineup.json", base_url).as_bytes());
+    push_tag(&mut payload, TAG_DEVICE_AUTH_STR, DEVICE_AUTH.as_bytes());
+
+    Some(build_packet(TYPE_DISCOVER_RPY, &payload))
+
+}
+
+// 要求元から見た自ホストのアドレス(経路上の送信元アドレス)
+pub fn local_addr_for(peer: &SocketAddr) -> IpAddr {
+
+    let probe = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+        .and_then(|socket| socket.connect(peer).map(|_| socket))
+        .and_then(|socket| socket.local_addr());
+
+    match probe {
+        Ok(addr) => addr.ip(),
+        Err(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    }
+
+}
+
+// ディスカバリー応答スレッドの起動(UDP 65001)
+pub fn start_discover_responder(http_port: u16, tuner_count: usize, access: Arc<AccessControl>) {
+
+    let socket = match UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DISCOVER_PORT)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("HDHomeRun discover bind error (udp/{}, {})", DISCOVER_PORT, e);
+            return;
+        },
+    };
+    info!("HDHomeRun discover responder (udp/{})", DISCOVER_PORT);
+
+    spawn_discover_responder(socket, http_port, tuner_count, access);
+
+}
+
+// バインド済みソケットでのディスカバリー応答スレッドの起動
+fn spawn_discover_responder(socket: UdpSocket, http_port: u16, tuner_count: usize, access: Arc<AccessControl>) {
+
+    let device_id = device_id(http_port);
+
+    thread::spawn(move || {
+
+        let mut buffer = [0u8; 1500];
+        let mut errors: u32 = 0;
+
+        loop {
+            let (length, peer) = match socket.recv_from(&mut buffer) {
+                Ok(received) => {
+                    errors = 0;
+                    received
+                },
+                Err(e) => {
+                    // 連続したエラーは再試行間隔を倍に延ばす(ログは1,2,4,8...回目のみ)
+                    errors = errors.saturating_add(1);
+                    if errors.is_power_of_two() {
+                        error!("HDHomeRun discover receive error ({}, {} times)", e, errors);
+                    };
+                    let backoff = (DISCOVER_ERROR_WAIT << (errors - 1).min(5)).min(DISCOVER_ERROR_BACKOFF);
+                    thread::sleep(Duration::from_secs(backoff));
+                    continue;
+                },
+            };
+
+            // 許可されていないアドレスからの要求は無視
+            if access.is_allowed(&peer.ip()) == false {
+                debug!("HDHomeRun discover denied {}", peer);
+                continue;
+            };
+
+            // 応答するベースURL(要求元から到達できるアドレス)
+            let base_url = format!("http://{}", SocketAddr::new(local_addr_for(&peer), http_port));
+
+            match discover_reply(&buffer[..length], device_id, tuner_count, &base_url) {
+                Some(reply) => {
+                    debug!("HDHomeRun discover request from {}", peer);
+                    if let Err(e) = socket.send_to(&reply, peer) {
+                        error!("HDHomeRun discover send error ({}, {})", peer, e);
+                    };
+                },
+                None => debug!("HDHomeRun discover ignored packet from {}", peer),
+            };
+        };
+
+    });
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // 127.0.0.1上の応答スレッドへディスカバリー要求を送信し、応答のタグを確認
+    #[test]
+    fn discover_round_trip() {
+
+        let access = Arc::new(AccessControl { allow: vec![], deny: vec![], credentials: vec![] });
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let responder_addr = responder.local_addr().unwrap();
+        spawn_discover_responder(responder, 40772, 2, access);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut payload = vec![];
+        push_tag(&mut payload, TAG_DEVICE_TYPE, &DEVICE_TYPE_TUNER.to_be_bytes());
+        push_tag(&mut payload, TAG_DEVICE_ID, &DEVICE_WILDCARD.to_be_bytes());
+        client.send_to(&build_packet(TYPE_DISCOVER_REQ, &payload), responder_addr).unwrap();
+
+        let mut buffer = [0u8; 1500];
+        let (length, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(from, responder_addr);
+
+        let (packet_type, tags) = parse_packet(&buffer[..length]).unwrap();
+        assert_eq!(packet_type, TYPE_DISCOVER_RPY);
+        let tag = |tag: u8| tags.iter().find(|(key, _)| *key == tag).map(|(_, value)| value.clone()).unwrap();
+        assert_eq!(tag(TAG_DEVICE_ID), device_id(40772).to_be_bytes().to_vec());
+        assert_eq!(tag(TAG_TUNER_COUNT), vec![2]);
+        assert_eq!(tag(TAG_BASE_URL), b"http://127.0.0.1:40772".to_vec());
+        assert_eq!(tag(TAG_LINEUP_URL), b"http://127.0.0.1:40772/lineup.json".to_vec());
+
+    }
+
+    // 他のデバイスIDを指定した要求・CRCが不正な要求には応答しない
+    #[test]
+    fn discover_ignores_other_devices() {
+
+        let mut payload = vec![];
+        push_tag(&mut payload, TAG_DEVICE_ID, &0x1234_5678u32.to_be_bytes());
+        let request = build_packet(TYPE_DISCOVER_REQ, &payload);
+        assert!(discover_reply(&request, device_id(40772), 2, "http://127.0.0.1:40772").is_none());
+
+        let mut request = build_packet(TYPE_DISCOVER_REQ, &[]);
+        let last = request.len() - 1;
+        request[last] ^= 0xff;
+        assert!(discover_reply(&request, device_id(40772), 2, "http://127.0.0.1:40772").is_none());
+
+    }
+
+}
@@ -0,0 +1,543 @@
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::AtomicU64, atomic::Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::commands::{CommanLineOpt, DecoderOptions};
+use crate::pipeline::{PCR_CLOCK, PCR_WRAP, TsPipeline, adaptation_field};
+use crate::session::{ClientInfo, SessionBroker, SessionError, session_key};
+use crate::ts_splitter_core::{LENGTH_PACKET, get_pid};
+
+// プレイリストのContent-Type
+pub const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+// プレイリストのファイル名
+pub const PLAYLIST_NAME: &str = "index.m3u8";
+
+// プレイリスト・セグメントの要求が無い場合にHLSストリームを終了するまでの秒数
+const HLS_IDLE_TIMEOUT: u64 = 30;
+
+// ランダムアクセスポイントが無い場合にセグメントを強制的に区切る長さ(目標時間の倍数)
+const FORCE_CUT_FACTOR: f64 = 3.0;
+
+// 映像のstream_type(MPEG-1/2 Video , MPEG-4 Visual , H.264 , H.265)
+const VIDEO_STREAM_TYPES: [u8; 5] = [0x01, 0x02, 0x10, 0x1B, 0x24];
+
+// セグメントのデータ(メモリ上または一時ディレクトリのファイル)
+#[allow(dead_code)]
+pub enum SegmentData {
+    Memory(Arc<Vec<u8>>),
+    File(PathBuf),
+}
+
+// セグメント
+#[allow(dead_code)]
+pub struct HlsSegment {
+    pub sequence: u64,
+    pub duration: f64,
+    pub data: SegmentData,
+}
+
+#[allow(dead_code)]
+impl HlsSegment {
+
+    // セグメントのデータ読み込み
+    pub fn read(&self) -> Option<Arc<Vec<u8>>> {
+
+        match &self.data {
+            SegmentData::Memory(data) => Some(Arc::clone(data)),
+            SegmentData::File(path) => fs::read(path).ok().map(Arc::new),
+        }
+
+    }
+
+    // セグメントの削除(一時ファイルの場合)
+    fn remove(&self) {
+
+        if let SegmentData::File(path) = &self.data {
+            if let Err(e) = fs::remove_file(path) {
+                debug!("HLS segment remove error ({}, {})", path.display(), e);
+            };
+        };
+
+    }
+
+}
+
+// MPEG-TSのセグメント分割
+// ランダムアクセスポイント(アダプテーションフィールドのrandom_access_indicator)で区切り、
+// 各セグメントの先頭には直近のPAT・PMTを付加する
+// ランダムアクセスポイントはPATの先頭のサービス(SID分離後は選択したサービス)の映像・PCRのPIDのみ対象
+#[allow(dead_code)]
+pub struct Segmenter {
+    target: f64,
+    current: Vec<u8>,
+    // 現在のセグメントの開始・直近のPCR(27MHz)
+    first_pcr: Option<u64>,
+    last_pcr: Option<u64>,
+    // PCRを取得するPID(PMTのPCR_PID、PMT受信前は最初にPCRを検出したPID)
+    pcr_pid: Option<u16>,
+    // ランダムアクセスポイントを判定するPID(サービスの映像・PCRのPID)
+    random_access_pids: Vec<u16>,
+    // 現在のセグメントの開始時刻(PCRが無い場合の長さ計算用)
+    started: Instant,
+    pat: Option<Vec<u8>>,
+    pmt: HashMap<u16, Vec<u8>>,
+    // PATのPMT PID一覧(PATの記載順)
+    pmt_pids: Vec<u16>,
+}
+
+#[allow(dead_code)]
+impl Segmenter {
+
+    // 初期設定(目標のセグメント長は秒)
+    pub fn new(target: u64) -> Segmenter {
+
+        Segmenter {
+            target: target as f64,
+            current: vec![],
+            first_pcr: None,
+            last_pcr: None,
+            pcr_pid: None,
+            random_access_pids: vec![],
+            started: Instant::now(),
+            pat: None,
+            pmt: HashMap::new(),
+            pmt_pids: vec![],
+        }
+
+    }
+
+    // TSパケットの投入
+    // 区切りに達したセグメント(データ,長さ秒)をリターン
+    pub fn push(&mut self, buffer: &[u8]) -> Vec<(Vec<u8>, f64)> {
+
+        let mut segments = vec![];
+
+        for packet in buffer.chunks_exact(LENGTH_PACKET) {
+
+            if packet[0] != 0x47 { continue; };
+            let pid = get_pid(packet) as u16;
+            let unit_start = (packet[1] & 0x40) != 0;
+
+            // PAT・PMTの保存(セグメント先頭への付加用)
+            if pid == 0 && unit_start {
+                let pmt_pids = pat_pmt_pids(packet);
+                self.pmt.retain(|pmt_pid, _| pmt_pids.contains(pmt_pid));
+                for pmt_pid in &pmt_pids {
+                    self.pmt.entry(*pmt_pid).or_insert(vec![]);
+                };
+                if self.pmt_pids.first() != pmt_pids.first() {
+                    self.random_access_pids.clear();
+                };
+                self.pmt_pids = pmt_pids;
+                self.pat = Some(packet.to_vec());
+            }
+            else if unit_start && self.pmt.contains_key(&pid) {
+                self.pmt.insert(pid, packet.to_vec());
+
+                // 対象サービスの映像・PCRのPID
+                if self.pmt_pids.first() == Some(&pid) {
+                    if let Some((pcr_pid, video_pids)) = pmt_service_pids(packet) {
+                        if self.pcr_pid != Some(pcr_pid) {
+                            self.pcr_pid = Some(pcr_pid);
+                            self.first_pcr = None;
+                            self.last_pcr = None;
+                        };
+                        self.random_access_pids = video_pids;
+                        self.random_access_pids.push(pcr_pid);
+                    };
+                };
+            };
+
+            // アダプテーションフィールドのランダムアクセスポイント・PCR
+            let (random_access, pcr) = adaptation_field(packet);
+            let random_access = random_access && self.random_access_pids.contains(&pid);
+            if let Some(pcr) = pcr {
+                if *self.pcr_pid.get_or_insert(pid) == pid {
+                    self.last_pcr = Some(pcr);
+                    if self.first_pcr.is_none() {
+                        self.first_pcr = Some(pcr);
+                    };
+                };
+            };
+
+            // セグメントの区切り(目標時間に達した後のランダムアクセスポイント)
+            if self.current.len() > 0 && unit_start {
+                let duration = self.duration();
+                if (random_access && duration >= self.target) || duration >= self.target * FORCE_CUT_FACTOR {
+                    segments.push((std::mem::take(&mut self.current), duration));
+                    self.start_segment();
+                };
+            }
+            // 最初のセグメントはPAT受信後のランダムアクセスポイントから開始
+            else if self.current.len() == 0 {
+                if random_access == false || unit_start == false || self.pat.is_none() {
+                    continue;
+                };
+                self.start_segment();
+            };
+
+            self.current.extend_from_slice(packet);
+
+        };
+
+        segments
+
+    }
+
+    // 新しいセグメントの開始(PAT・PMTを付加)
+    fn start_segment(&mut self) {
+
+        if let Some(pat) = &self.pat {
+            self.current.extend_from_slice(pat);
+        };
+        for pmt in self.pmt.values() {
+            self.current.extend_from_slice(pmt);
+        };
+        self.first_pcr = self.last_pcr;
+        self.started = Instant::now();
+
+    }
+
+    // 現在のセグメントの長さ(秒)
+    fn duration(&self) -> f64 {
+
+        match (self.first_pcr, self.last_pcr) {
+            (Some(first), Some(last)) => ((last + PCR_WRAP - first) % PCR_WRAP) as f64 / PCR_CLOCK,
+            _ => self.started.elapsed().as_secs_f64(),
+        }
+
+    }
+
+}
+
+// PATのPMT PID一覧(NITのprogram_number=0は除外)
+fn pat_pmt_pids(packet: &[u8]) -> Vec<u16> {
+
+    // ペイロードの開始位置(アダプテーションフィールドとpointer_fieldを除く)
+    let mut pos = 4;
+    if (packet[3] & 0x20) != 0 {
+        pos += 1 + packet[4] as usize;
+    };
+    if pos >= LENGTH_PACKET { return vec![]; };
+    pos += 1 + packet[pos] as usize;
+
+    // セクション長(CRCを除いたエントリー部分)
+    if pos + 8 > LENGTH_PACKET { return vec![]; };
+    let section_length = (((packet[pos + 1] as usize) & 0x0F) << 8) | packet[pos + 2] as usize;
+    let end = (pos + 3 + section_length).saturating_sub(4).min(LENGTH_PACKET);
+
+    (pos + 8..end).step_by(4)
+        .filter(|entry| entry + 4 <= end)
+        .filter(|entry| ((packet[*entry] as u16) << 8 | packet[entry + 1] as u16) != 0)
+        .map(|entry| ((packet[entry + 2] as u16 & 0x1F) << 8) | packet[entry + 3] as u16)
+        .collect()
+
+}
+
+// PMTのPCR_PIDと映像のPID一覧
+fn pmt_service_pids(packet: &[u8]) -> Option<(u16, Vec<u16>)> {
+
+    // ペイロードの開始位置(アダプテーションフィールドとpointer_fieldを除く)
+    let mut pos = 4;
+    if (packet[3] & 0x20) != 0 {
+        pos += 1 + packet[4] as usize;
+    };
+    if pos >= LENGTH_PACKET { return None; };
+    pos += 1 + packet[pos] as usize;
+
+    // セクション長・PCR_PID・program_info_length
+    if pos + 12 > LENGTH_PACKET || packet[pos] != 0x02 { return None; };
+    let section_length = (((packet[pos + 1] as usize) & 0x0F) << 8) | packet[pos + 2] as usize;
+    let end = (pos + 3 + section_length).saturating_sub(4).min(LENGTH_PACKET);
+    let pcr_pid = ((packet[pos + 8] as u16 & 0x1F) << 8) | packet[pos + 9] as u16;
+    let program_info_length = (((packet[pos + 10] as usize) & 0x0F) << 8) | packet[pos + 11] as usize;
+
+    // エレメンタリストリームのループ(stream_type , elementary_PID , ES_info_length)
+    let mut video_pids = vec![];
+    let mut entry = pos + 12 + program_info_length;
+    while entry + 5 <= end {
+        let stream_type = packet[entry];
+        let elementary_pid = ((packet[entry + 1] as u16 & 0x1F) << 8) | packet[entry + 2] as u16;
+        let es_info_length = (((packet[entry + 3] as usize) & 0x0F) << 8) | packet[entry + 4] as usize;
+        if VIDEO_STREAM_TYPES.contains(&stream_type) {
+            video_pids.push(elementary_pid);
+        };
+        entry += 5 + es_info_length;
+    };
+
+    Some((pcr_pid, video_pids))
+
+}
+
+// HLSストリーム(セッション毎のスライディングウィンドウのプレイリスト)
+#[allow(dead_code)]
+pub struct HlsStream {
+    pub id: u64,
+    pub key: String,
+    pub channel: String,
+    pub sid: String,
+    target: u64,
+    window: usize,
+    dir: Option<PathBuf>,
+    segments: Mutex<VecDeque<HlsSegment>>,
+    last_access: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+#[allow(dead_code)]
+impl HlsStream {
+
+    // 要求時刻の更新(要求が途絶えたらストリーム終了)
+    pub fn touch(&self) {
+
+        *self.last_access.lock().unwrap() = Instant::now();
+
+    }
+
+    // 終了判定
+    pub fn is_closed(&self) -> bool {
+
+        self.closed.load(Ordering::Acquire)
+
+    }
+
+    // セグメント数
+    pub fn segment_count(&self) -> usize {
+
+        self.segments.lock().unwrap().len()
+
+    }
+
+    // セグメントの取得
+    pub fn segment(&self, sequence: u64) -> Option<Arc<Vec<u8>>> {
+
+        self.segments.lock().unwrap().iter().find(|segment| segment.sequence == sequence)?.read()
+
+    }
+
+    // プレイリストの作成(セグメントはプレイリストからの相対URL)
+    pub fn playlist(&self) -> String {
+
+        let segments = self.segments.lock().unwrap();
+        let target = segments.iter().map(|segment| segment.duration.ceil() as u64).max().unwrap_or(self.target).max(self.target);
+        let sequence = segments.front().map(|segment| segment.sequence).unwrap_or(0);
+
+        let mut playlist = format!("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n", target, sequence);
+        for segment in segments.iter() {
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", segment.duration, segment_name(self.id, segment.sequence)));
+        };
+        if self.is_closed() {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        };
+
+        playlist
+
+    }
+
+    // セグメントの追加(ウィンドウを超えた古いセグメントは削除)
+    fn add_segment(&self, sequence: u64, data: Vec<u8>, duration: f64) {
+
+        let data = match &self.dir {
+            Some(dir) => {
+                let path = dir.join(format!("recpt3-hls-{}-{}.ts", self.id, sequence));
+                match fs::write(&path, &data) {
+                    Ok(_) => SegmentData::File(path),
+                    Err(e) => {
+                        warn!("HLS segment write error ({}, {})", path.display(), e);
+                        SegmentData::Memory(Arc::new(data))
+                    },
+                }
+            },
+            None => SegmentData::Memory(Arc::new(data)),
+        };
+        debug!("HLS {} segment {} ({:.3}sec)", self.id, sequence, duration);
+
+        let mut segments = self.segments.lock().unwrap();
+        segments.push_back(HlsSegment { sequence: sequence, duration: duration, data: data });
+        while segments.len() > self.window {
+            if let Some(segment) = segments.pop_front() {
+                segment.remove();
+            };
+        };
+
+    }
+
+    // 全セグメントの削除
+    fn clear(&self) {
+
+        for segment in self.segments.lock().unwrap().drain(..) {
+            segment.remove();
+        };
+
+    }
+
+}
+
+// HLSストリームの管理
+// 同じチャンネル・SID・デコード設定の要求は同じHLSストリームを共有する
+#[allow(dead_code)]
+pub struct HlsManager {
+    target: u64,
+    window: usize,
+    dir: Option<PathBuf>,
+    streams: Mutex<HashMap<u64, Arc<HlsStream>>>,
+    open_lock: Mutex<()>,
+    next_id: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl HlsManager {
+
+    // 作成(セグメント長は秒、dirが空の場合はメモリ上に保持)
+    pub fn new(target: u64, window: usize, dir: &str) -> Arc<HlsManager> {
+
+        Arc::new(HlsManager {
+            target: target.max(1),
+            window: window.max(2),
+            dir: if dir != "" { Some(PathBuf::from(dir)) } else { None },
+            streams: Mutex::new(HashMap::new()),
+            open_lock: Mutex::new(()),
+            next_id: AtomicU64::new(1),
+        })
+
+    }
+
+    // HLSストリームの検索
+    pub fn stream(&self, id: u64) -> Option<Arc<HlsStream>> {
+
+        self.streams.lock().unwrap().get(&id).cloned()
+
+    }
+
+    // HLSストリーム一覧
+    pub fn streams(&self) -> Vec<Arc<HlsStream>> {
+
+        let mut streams: Vec<Arc<HlsStream>> = self.streams.lock().unwrap().values().cloned().collect();
+        streams.sort_by_key(|stream| stream.id);
+        streams
+
+    }
+
+    // 全HLSストリームのセグメント削除(デーモン終了時)
+    pub fn shutdown(&self) {
+
+        for stream in self.streams() {
+            stream.closed.store(true, Ordering::Release);
+            stream.clear();
+        };
+
+    }
+
+    // HLSストリームの開始(既存のストリームが有る場合は参加)
+    // チューナーのオープンを伴うためブロッキング処理
+    pub fn open(self: &Arc<Self>, broker: &Arc<SessionBroker>, client: &ClientInfo, channel: &String, sid: &String,
+        command_opt: &CommanLineOpt, decoder_opt: &DecoderOptions) -> Result<Arc<HlsStream>, SessionError> {
+
+        let _open = self.open_lock.lock().unwrap();
+
+        // 既存のストリームへの参加
+        let device = if command_opt._use_device { command_opt.device.to_string() } else { "".to_string() };
+        let key = format!("{}|device={}|sid={}", session_key(channel, command_opt, decoder_opt), device, sid);
+        let existing = self.streams.lock().unwrap().values().find(|stream| stream.key == key && stream.is_closed() == false).cloned();
+        if let Some(stream) = existing {
+            stream.touch();
+            return Ok(stream);
+        };
+
+        // チャンネルの購読開始(HLSストリームは共有するためタイムシフトの開始位置は指定しない)
+        let mut command_opt = command_opt.clone();
+        command_opt.offset = 0;
+        command_opt.resume = 0;
+        let (session, subscriber) = broker.subscribe(client, channel, sid, &command_opt, decoder_opt)?;
+
+        let stream = Arc::new(HlsStream {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            key: key,
+            channel: channel.to_string(),
+            sid: sid.to_string(),
+            target: self.target,
+            window: self.window,
+            dir: self.dir.clone(),
+            segments: Mutex::new(VecDeque::new()),
+            last_access: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        });
+        self.streams.lock().unwrap().insert(stream.id, Arc::clone(&stream));
+        info!("HLS {} started (channel={} , sid={} , client={})", stream.id, channel, sid, client.addr);
+
+        // セグメント分割スレッド起動
+        let manager = Arc::clone(self);
+        let broker = Arc::clone(broker);
+        let hls = Arc::clone(&stream);
+        let use_splitter = command_opt.use_splitter;
+        thread::spawn(move || {
+
+            // ts splitterの初期化処理
+            let mut pipeline = TsPipeline::new(&hls.sid, use_splitter);
+            let mut segmenter = Segmenter::new(hls.target);
+            let mut sequence: u64 = 0;
+
+            loop {
+
+                // 要求が途絶えたら終了
+                if hls.last_access.lock().unwrap().elapsed() >= Duration::from_secs(HLS_IDLE_TIMEOUT) {
+                    info!("HLS {} idle", hls.id);
+                    break;
+                };
+
+                // 配信データの取り出し(タイムシフト再生中はバッファから、セッション終了時はループ終了)
+                let chunk = match subscriber.next_chunk(&session) {
+                    Some(chunk) => chunk,
+                    None => match subscriber.queue.pop_timeout(Duration::from_secs(1)) {
+                        Some(chunk) => chunk,
+                        None if subscriber.queue.is_closed() => break,
+                        None => continue,
+                    },
+                };
+
+                // SID分離＆セグメント分割
+                let buffer = pipeline.process(&chunk.data);
+                for (data, duration) in segmenter.push(buffer) {
+                    subscriber.sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    hls.add_segment(sequence, data, duration);
+                    sequence += 1;
+                };
+                subscriber.record_lag(&chunk);
+
+            };
+
+            // チャンネルの購読終了＆セグメント削除
+            broker.unsubscribe(&session, &subscriber);
+            hls.closed.store(true, Ordering::Release);
+            hls.clear();
+            manager.streams.lock().unwrap().remove(&hls.id);
+            info!("HLS {} closed (channel={} , segments={})", hls.id, hls.channel, sequence);
+
+        });
+
+        Ok(stream)
+
+    }
+
+}
+
+// セグメントのファイル名(s<ストリームID>_<シーケンス番号>.ts)
+pub fn segment_name(id: u64, sequence: u64) -> String {
+
+    format!("s{}_{}.ts", id, sequence)
+
+}
+
+// セグメントのファイル名の解析
+pub fn parse_segment_name(name: &str) -> Option<(u64, u64)> {
+
+    let (id, sequence) = name.strip_prefix('s')?.strip_suffix(".ts")?.split_once('_')?;
+
+    Some((id.parse::<u64>().ok()?, sequence.parse::<u64>().ok()?))
+
+}
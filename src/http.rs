@@ -0,0 +1,350 @@
+use log::debug;
+use std::io;
+use std::io::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// リクエストヘッダーの最大サイズ(リクエスト行を含む)
+pub const MAX_HEADER_BYTES: usize = 8192;
+
+// リクエストヘッダーの最大数
+pub const MAX_HEADERS: usize = 64;
+
+// リクエストボディの最大サイズ(SOAPの制御要求用)
+pub const MAX_BODY_BYTES: usize = 65536;
+
+// リクエスト解析エラー
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum RequestError {
+    // リクエスト受信前に切断
+    Closed,
+    // 受信エラー(タイムアウトを含む)
+    Io,
+    // リクエスト行・ヘッダーの書式エラー
+    BadRequest,
+    // ヘッダーサイズ・数の超過
+    TooLarge,
+    // ボディサイズの超過
+    BodyTooLarge,
+    // HTTP/1.x以外
+    VersionNotSupported,
+}
+
+#[allow(dead_code)]
+impl RequestError {
+
+    // エラーに対応するステータスコード
+    pub fn status(&self) -> u16 {
+
+        match self {
+            RequestError::Closed | RequestError::Io => 400,
+            RequestError::BadRequest => 400,
+            RequestError::TooLarge => 431,
+            RequestError::BodyTooLarge => 413,
+            RequestError::VersionNotSupported => 505,
+        }
+
+    }
+
+}
+
+// HTTPリクエスト
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl HttpRequest {
+
+    // ヘッダー値の取得(ヘッダー名の大文字小文字は区別しない)
+    pub fn header(&self, name: &str) -> Option<&str> {
+
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+
+    }
+
+    // クエリパラメータの取得
+    pub fn query_value(&self, name: &str) -> Option<&str> {
+
+        self.query.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+
+    }
+
+    // パスをセグメント毎に分解(空のセグメントは除く)
+    pub fn segments(&self) -> Vec<&str> {
+
+        self.path.split('/').filter(|segment| *segment != "").collect()
+
+    }
+
+}
+
+// リクエストの受信＆解析
+// ヘッダーの終わり(空行)まで読み込み、MAX_HEADER_BYTESを超えた場合はTooLargeをリターン
+// Content-Lengthが有る場合はボディも読み込み、MAX_BODY_BYTESを超えた場合はBodyTooLargeをリターン
+#[allow(dead_code)]
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<HttpRequest, RequestError> {
+
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    let mut read_buff = [0u8; 1024];
+
+    // ヘッダーの終わりまで受信
+    let header_end = loop {
+
+        let n = match reader.read(&mut read_buff).await {
+            Ok(0) if buffer.len() == 0 => return Err(RequestError::Closed),
+            Ok(0) => return Err(RequestError::BadRequest),
+            Ok(n) => n,
+            Err(e) => {
+                debug!("read_request error ({})", e);
+                return Err(RequestError::Io);
+            },
+        };
+        buffer.extend_from_slice(&read_buff[..n]);
+
+        if let Some(pos) = find_header_end(&buffer) {
+            break pos;
+        };
+        if buffer.len() > MAX_HEADER_BYTES {
+            return Err(RequestError::TooLarge);
+        };
+
+    };
+    if header_end > MAX_HEADER_BYTES {
+        return Err(RequestError::TooLarge);
+    };
+
+    let mut request = parse_request(&buffer[..header_end])?;
+
+    // ボディの受信
+    let content_length = match request.header("Content-Length") {
+        Some(length) => length.parse::<usize>().map_err(|_| RequestError::BadRequest)?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(RequestError::BodyTooLarge);
+    };
+    let mut body = buffer.split_off(header_end);
+    while body.len() < content_length {
+        let n = match reader.read(&mut read_buff).await {
+            Ok(0) => return Err(RequestError::BadRequest),
+            Ok(n) => n,
+            Err(e) => {
+                debug!("read_request error ({})", e);
+                return Err(RequestError::Io);
+            },
+        };
+        body.extend_from_slice(&read_buff[..n]);
+    };
+    body.truncate(content_length);
+    request.body = body;
+
+    Ok(request)
+
+}
+
+// ヘッダー部(空行の手前まで)の解析
+#[allow(dead_code)]
+pub fn parse_request(header: &[u8]) -> Result<HttpRequest, RequestError> {
+
+    let text = std::str::from_utf8(header).map_err(|_| RequestError::BadRequest)?;
+    let mut lines = text.split("\r\n").flat_map(|line| line.split('\n'));
+
+    // リクエスト行の解析
+    let request_line = lines.next().ok_or(RequestError::BadRequest)?;
+    let mut parts = request_line.split(' ');
+    let method = parts.next().filter(|method| is_token(method)).ok_or(RequestError::BadRequest)?;
+    let target = parts.next().filter(|target| target.starts_with('/')).ok_or(RequestError::BadRequest)?;
+    let version = parts.next().ok_or(RequestError::BadRequest)?;
+    if parts.next().is_some() {
+        return Err(RequestError::BadRequest);
+    };
+    if version.starts_with("HTTP/") == false {
+        return Err(RequestError::BadRequest);
+    };
+    if version != "HTTP/1.0" && version != "HTTP/1.1" {
+        return Err(RequestError::VersionNotSupported);
+    };
+
+    // パスとクエリ文字列の分解
+    let (raw_path, raw_query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+    let path = percent_decode(raw_path).ok_or(RequestError::BadRequest)?;
+    let mut query = vec![];
+    for pair in raw_query.split('&').filter(|pair| *pair != "") {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(&key.replace('+', " ")).ok_or(RequestError::BadRequest)?;
+        let value = percent_decode(&value.replace('+', " ")).ok_or(RequestError::BadRequest)?;
+        query.push((key, value));
+    };
+
+    // ヘッダーの解析
+    let mut headers = vec![];
+    for line in lines {
+        if line == "" { continue; };
+        if headers.len() >= MAX_HEADERS {
+            return Err(RequestError::TooLarge);
+        };
+        let (name, value) = line.split_once(':').ok_or(RequestError::BadRequest)?;
+        if is_token(name) == false {
+            return Err(RequestError::BadRequest);
+        };
+        headers.push((name.to_string(), value.trim().to_string()));
+    };
+
+    Ok(HttpRequest {
+        method: method.to_string(),
+        path: path,
+        query: query,
+        version: version.to_string(),
+        headers: headers,
+        body: vec![],
+    })
+
+}
+
+// レスポンスヘッダーの送信(Keep-Aliveは行わないため常にConnection: close)
+#[allow(dead_code)]
+pub fn send_header<W: Write>(writer: &mut W, status: u16, content_type: &str,
+    content_length: Option<usize>, extra_headers: &[(&str, String)]) -> io::Result<()> {
+
+    let mut header = format!("HTTP/1.1 {} {}\r\n", status, status_text(status));
+    if content_type != "" {
+        header.push_str(&format!("Content-Type: {}\r\n", content_type));
+    };
+    if let Some(length) = content_length {
+        header.push_str(&format!("Content-Length: {}\r\n", length));
+    };
+    for (name, value) in extra_headers {
+        header.push_str(&format!("{}: {}\r\n", name, value));
+    };
+    header.push_str("Cache-Control: no-cache\r\n");
+    header.push_str("Connection: close\r\n\r\n");
+
+    writer.write_all(header.as_bytes())?;
+    writer.flush()
+
+}
+
+// レスポンスの送信(HEADの場合はボディを送信しない)
+#[allow(dead_code)]
+pub fn send_response<W: Write>(writer: &mut W, status: u16, content_type: &str,
+    extra_headers: &[(&str, String)], body: &[u8], head_only: bool) -> io::Result<()> {
+
+    send_header(writer, status, content_type, Some(body.len()), extra_headers)?;
+    if head_only == false && body.len() > 0 {
+        writer.write_all(body)?;
+        writer.flush()?;
+    };
+
+    Ok(())
+
+}
+
+// JSONレスポンスの送信
+#[allow(dead_code)]
+pub fn send_json<W: Write>(writer: &mut W, body: &str, head_only: bool) -> io::Result<()> {
+
+    send_response(writer, 200, "application/json; charset=utf-8", &[], body.as_bytes(), head_only)
+
+}
+
+// エラーレスポンスの送信(本文はステータス行のテキスト)
+#[allow(dead_code)]
+pub fn send_error<W: Write>(writer: &mut W, status: u16, extra_headers: &[(&str, String)], head_only: bool) -> io::Result<()> {
+
+    let body = format!("{} {}\n", status, status_text(status));
+    send_response(writer, status, "text/plain; charset=utf-8", extra_headers, body.as_bytes(), head_only)
+
+}
+
+// ステータスコードのテキスト
+#[allow(dead_code)]
+pub fn status_text(status: u16) -> &'static str {
+
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+
+}
+
+// ヘッダー終端(空行の直後)の位置
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+
+    if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        return Some(pos + 4);
+    };
+    buffer.windows(2).position(|window| window == b"\n\n").map(|pos| pos + 2)
+
+}
+
+// トークン(メソッド名・ヘッダー名)の文字チェック
+fn is_token(token: &str) -> bool {
+
+    token.len() > 0 && token.bytes().all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+
+}
+
+// パーセントエンコーディングのデコード
+fn percent_decode(text: &str) -> Option<String> {
+
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        }
+        else {
+            decoded.push(bytes[i]);
+            i += 1;
+        };
+    };
+
+    String::from_utf8(decoded).ok()
+
+}
+
+// パーセントエンコーディング(URLのパスセグメント用、非予約文字以外をエンコード)
+#[allow(dead_code)]
+pub fn percent_encode(text: &str) -> String {
+
+    text.bytes().map(|c| match c {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (c as char).to_string(),
+        _ => format!("%{:02X}", c),
+    }).collect()
+
+}
@@ -1,362 +1,1209 @@
 use log::{debug, error, warn, info};
-use std::io::{BufReader};
-use std::io::prelude::*;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
-use std::os::fd::AsRawFd;
-use std::process;
+use std::io;
+use std::io::SeekFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, atomic::Ordering};
 use std::time::{Duration, SystemTime};
-
-use crate::arib_b25::{ARIB_STD_B25, ARIB_STD_B25_BUFFER, B_CAS_CARD};
-use crate::commands::{CommanLineOpt, DecoderOptions};
-use crate::decoder::{b25_startup, b25_decode, b25_shutdown};
-use crate::ts_splitter_core::{LENGTH_PACKET, MAX_PID, get_pid, split_startup, split_select, split_ts,
-    TSS_ERROR, TSS_SUCCESS};
-use crate::tuner;
-use crate::tuner::{CAP, channel_type, signal_get, start_rec, tuner_device, tune};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::access::{AUTHENTICATE_REALM, AccessControl, load_auth_file, parse_cidr_list};
+use crate::api::{metrics_text, status_json, tuners_json};
+use crate::channels::{ChannelEntry, channel_map, channels_json, channels_m3u};
+use crate::commands::{CommanLineOpt, DecoderOptions, FALSE, TRUE};
+use crate::dlna::{CONTROL_NAME, DESCRIPTION_NAME, DLNA_PATH, XML_CONTENT_TYPE, control_response, description_xml, dlna_headers, service_xml,
+    start_ssdp_responder};
+use crate::hdhomerun::{discover_json, find_lineup, lineup_json, lineup_status_json, start_discover_responder};
+use crate::events::{EVENT_STREAM_CONTENT_TYPE, Event, EventMessage};
+use crate::hls::{HlsManager, PLAYLIST_CONTENT_TYPE, PLAYLIST_NAME, parse_segment_name};
+use crate::http::{HttpRequest, RequestError, read_request, send_error, send_header, send_json, send_response};
+use crate::metrics::OPENMETRICS_CONTENT_TYPE;
+use crate::mirakurun::{PRIORITY_HEADER, USER_ID_HEADER, channel_type_of, find_channel, find_service, mirakurun_type as mirakurun_type_of,
+    parse_priority, service_json, service_list, services_json};
+use crate::pipeline::TsPipeline;
+use crate::recordings::{Pacer, RECORDING_IDLE, is_recording, is_recording_file, packet_length, parse_range, recording_path, recordings_json, written_length};
+use crate::sd_notify::{notify, notify_ready, watchdog_interval};
+use crate::session::{ClientInfo, SessionBroker, SessionError, SlowClientPolicy};
+use crate::timeshift::TIMESHIFT_ID_HEADER;
+use crate::tls::{ClientStream, TlsAcceptor};
+use crate::ts_splitter_core::LENGTH_PACKET;
+use crate::tuner::{CAP, channel_type, is_tuner_device, tuner_list};
+
+// リクエスト受信(TLSハンドシェイクを含む)のタイムアウトの既定値(秒)
+pub const IDLE_TIMEOUT: u64 = 10;
+
+// 1回の送信の待ち合わせ(応答の無いクライアントの検出)のタイムアウトの既定値(秒)
+pub const SEND_TIMEOUT: u64 = 30;
+
+// 終了時に接続中のクライアントの切断を待つ時間(秒)
+const SHUTDOWN_TIMEOUT: u64 = 5;
+
+// 監視イベントの再接続待ち時間・接続維持のコメントの送信間隔(秒)
+const EVENT_RETRY: u64 = 3;
+const EVENT_KEEPALIVE: u64 = 15;
+
+// 追っかけ再生で録画ファイルの追記を待つ間隔(ミリ秒)
+const CHASE_INTERVAL: u64 = 500;
+
+// HLSの最初のセグメントを待つ時間(セグメント長の3倍に加える秒数)
+const HLS_START_TIMEOUT: u64 = 5;
+
+// 503応答時の再試行までの待ち時間(秒)
+const RETRY_AFTER: u64 = 10;
+
+// 対応メソッド
+const ALLOW_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// ストリームのContent-Type
+const STREAM_CONTENT_TYPE: &str = "video/mp2t";
+
+// M3UプレイリストのContent-Type
+const M3U_CONTENT_TYPE: &str = "audio/x-mpegurl; charset=utf-8";
+
+// リクエストの振り分け結果
+enum Route {
+    // 応答済み
+    Done,
+    // チャンネルのストリーム配信
+    Stream { client: ClientInfo, channel: String, sid: String, head_only: bool },
+    // HLSのプレイリスト
+    Playlist { client: ClientInfo, channel: String, sid: String, head_only: bool },
+    // 録画ファイルの配信
+    Recording { path: PathBuf, head_only: bool },
+    // 監視イベント(Server-Sent Events)
+    Events { head_only: bool },
+}
 
 pub fn http_daemon(command_opt: CommanLineOpt, decoder_opt: DecoderOptions) -> () {
 
     debug!("http_daemon run as a daemon..");
 
-    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), command_opt._http_port);
-    let _addr_v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0)), command_opt._http_port);
-    let listener = TcpListener::bind(&addr).unwrap();
+    // 非同期ランタイムの作成(チューナーの読み込みはセッション毎のスレッド)
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Runtime error ({})", e);
+            return;
+        },
+    };
 
-    // 開始時刻の取得
-    let start_time = SystemTime::now();
+    runtime.block_on(serve(command_opt, decoder_opt));
 
-    // 録画時間が設定されている場合はタイマースレッドを起動
-    if command_opt.duration > 0 {
-        std::thread::spawn(move || {
-            loop {
+}
 
-                // 実行時間を取得
-                let run_time = SystemTime::now().duration_since(start_time).unwrap().as_secs();
+// コネクション待ち受け処理(SIGTERM/SIGINTで全セッションを停止して終了)
+async fn serve(command_opt: CommanLineOpt, decoder_opt: DecoderOptions) -> () {
+
+    // 待ち受けアドレス(--bind未指定時は[::])
+    let bind_addr = command_opt.bind.parse::<IpAddr>().unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    let addr = SocketAddr::new(bind_addr, command_opt._http_port);
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Listen error ({}, {})", addr, e);
+            return;
+        },
+    };
 
-                // 実行時間が設定時間を経過したらプロセス終了
-                if run_time > command_opt.duration {
-                    process::exit(0)
-                }
-                // 実行時間が設定時間以下の場合は１秒スリープ
-                else {
-                    std::thread::sleep(Duration::from_secs_f64(1.0).into())
-                };
+    // 終了シグナル
+    let (mut sigterm, mut sigint) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Signal handler error ({})", e);
+            return;
+        },
+    };
+
+    // アクセス制御(許可・拒否アドレス＋認証)
+    let access = match access_control(&command_opt) {
+        Ok(access) => Arc::new(access),
+        Err(message) => {
+            error!("Access control error ({})", message);
+            return;
+        },
+    };
 
+    // セッションブローカーの作成(同じチャンネルのクライアントでチューナーを共有)
+    let broker = SessionBroker::new(command_opt.linger, command_opt.max_sessions);
+
+    // HLSストリームの管理
+    let hls = HlsManager::new(command_opt.hls_segment, command_opt.hls_window, &command_opt.hls_dir);
+
+    // TLS設定(証明書・秘密鍵指定時はHTTPS、SIGHUPで再読み込み)
+    let tls = if command_opt.tls_cert != "" {
+        match TlsAcceptor::new(&command_opt.tls_cert, &command_opt.tls_key) {
+            Ok(tls) => {
+                tls.reload_on_sighup();
+                info!("HTTPS enabled ({})", command_opt.tls_cert);
+                Some(tls)
+            },
+            Err(message) => {
+                error!("TLS error ({})", message);
+                return;
+            },
+        }
+    }
+    else {
+        None
+    };
+
+    // systemdへの起動完了通知
+    notify_ready(&format!("Listening on port {}", command_opt._http_port));
+
+    // systemdウォッチドッグの通知(セッション管理がロックできる間のみ通知)
+    if let Some(interval) = watchdog_interval() {
+        let broker = Arc::clone(&broker);
+        std::thread::spawn(move || {
+            loop {
+                let _ = broker.sessions();
+                notify("WATCHDOG=1");
+                std::thread::sleep(interval);
             };
         });
     };
 
-    // コネクション接続待ち
-    for stream in listener.incoming() {
+    // HDHomeRunディスカバリー応答の起動
+    if command_opt.use_hdhomerun_discover == true {
+        start_discover_responder(command_opt._http_port, tuner_list().len(), Arc::clone(&access));
+    };
 
-        match stream {
-            // コネクション受信
-            Ok(stream) => {
+    // SSDP応答の起動(UPnP/DLNAメディアサーバー)
+    let ssdp = match command_opt.use_dlna {
+        true => start_ssdp_responder(command_opt._http_port, &command_opt.dlna_interface, Arc::clone(&access)),
+        false => None,
+    };
 
-                // オプション情報のコピー
-                let mut command_opt = command_opt.clone();
-                let decoder_opt = decoder_opt.clone();
+    // 録画時間が設定されている場合は経過後に終了(SIGTERM受信時と同じ終了処理)
+    let duration = command_opt.duration;
+    let run_time = async move {
+        match duration {
+            0 => std::future::pending::<()>().await,
+            _ => tokio::time::sleep(Duration::from_secs(duration)).await,
+        };
+    };
+    tokio::pin!(run_time);
 
-                // スレッド起動まで1秒スリープ(デバイスファイルの空きが1つの場合にエラーになるのを回避するため)
-                std::thread::sleep(Duration::from_secs_f64(1.0).into());
+    // 接続毎のタスク
+    let mut connections = JoinSet::new();
 
-                // コネクション受信スレッド起動
-                std::thread::spawn(move || {
-                    response_stream(&mut command_opt, &decoder_opt, stream);
-                });
+    // コネクション接続待ち
+    loop {
 
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                // コネクション受信
+                Ok((stream, _)) => stream,
+                // コネクション受信エラー
+                Err(e) => {
+                    error!("Connection Recive Error: {}", e);
+                    continue;
+                },
+            },
+            _ = sigterm.recv() => {
+                info!("SIGTERM received. shutting down...");
+                break;
             },
-            // コネクション受信エラー
-            Err(e) => {
-                error!("Connection Recive Error: {}", e);
+            _ = sigint.recv() => {
+                info!("SIGINT received. shutting down...");
+                break;
+            },
+            _ = &mut run_time => {
+                info!("Duration reached ({}sec). shutting down...", duration);
+                break;
             },
         };
 
-    }
+        // 終了したタスクの回収
+        while connections.try_join_next().is_some() {};
+
+        // オプション情報のコピー
+        let mut command_opt = command_opt.clone();
+        let decoder_opt = decoder_opt.clone();
+        let broker = Arc::clone(&broker);
+        let access = Arc::clone(&access);
+        let hls = Arc::clone(&hls);
+        let tls = tls.clone();
+
+        // コネクション受信タスク起動
+        connections.spawn(async move {
+
+            // TLS接続の作成(ハンドシェイクにもリクエスト受信のタイムアウトを適用)
+            let stream = match &tls {
+                Some(tls) => match timeout(Duration::from_secs(command_opt.idle_timeout), tls.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        error!("TLS connection error ({})", e);
+                        return;
+                    },
+                    Err(_) => {
+                        debug!("TLS handshake timeout");
+                        return;
+                    },
+                },
+                None => ClientStream::Plain(stream),
+            };
+
+            response_stream(&broker, &access, &hls, &mut command_opt, &decoder_opt, stream).await;
+        });
+
+    };
+
+    // 新しい接続の受付を停止(DLNAクライアントにはssdp:byebyeを通知)
+    notify("STOPPING=1");
+    drop(listener);
+    if let Some(ssdp) = &ssdp {
+        ssdp.stop();
+    };
+
+    // 全セッションの停止(録画終了コマンド出力・B25解放、購読中のクライアントは切断)
+    let stopping = Arc::clone(&broker);
+    if let Err(e) = tokio::task::spawn_blocking(move || stopping.shutdown()).await {
+        error!("Session shutdown error ({})", e);
+    };
+    hls.shutdown();
+
+    // 接続中のクライアントの切断待ち(時間内に終わらない接続は打ち切り)
+    let remaining = timeout(Duration::from_secs(SHUTDOWN_TIMEOUT), async {
+        while connections.join_next().await.is_some() {};
+    }).await;
+    if remaining.is_err() {
+        warn!("Aborting {} connections", connections.len());
+        connections.shutdown().await;
+    };
+
+    info!("http daemon stopped");
+
 }
 
 // コネクションレスポンス処理
-fn response_stream(command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions, mut stream: TcpStream)
+async fn response_stream(broker: &Arc<SessionBroker>, access: &AccessControl, hls: &Arc<HlsManager>,
+    command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions, mut stream: ClientStream)
     -> () {
 
-    // リクエスト格納バッファ
-    let mut req_buff = [0; 4096];
+    // クライアントアドレス
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+    };
+    let send_timeout = Duration::from_secs(command_opt.send_timeout);
+
+    // リクエスト受信＆解析(リクエストを送らない接続はタイムアウトで切断)
+    let request = match timeout(Duration::from_secs(command_opt.idle_timeout), read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(RequestError::Closed)) => {
+            debug!("Connection closed ({})", addr);
+            return;
+        },
+        Ok(Err(e)) => {
+            warn!("Bad request from {} ({:?})", addr, e);
+            respond(&mut stream, send_timeout, |w| send_error(w, e.status(), &[], false)).await;
+            return;
+        },
+        Err(_) => {
+            debug!("Request timeout ({})", addr);
+            return;
+        },
+    };
+    info!("{} \"{} {} {}\"", addr, request.method, request.path, request.version);
+    debug!("response_stream headers={:?} , query={:?}", request.headers, request.query);
+
+    // リクエストの振り分け(ストリーム以外の応答はバッファに作成して送信)
+    let base = base_url(&request, &stream);
+    let mut response = vec![];
+    let route = route_request(broker, access, hls, command_opt, &request, addr, &base, &mut response);
+    if response.len() > 0 {
+        if let Err(message) = send_data(&mut stream, &response, send_timeout).await {
+            debug!("Response send error ({}, {})", addr, message);
+            return;
+        };
+    };
 
-    // コネクション受信ループ
-    loop {
-        match stream.read(&mut req_buff) {
-            Ok(n) => {
-                    
-                // コネクションクローズ
-                if n == 0 {
-                    info!("Connection closed");
-                    break;
-                };
+    match route {
+        Route::Stream { client, channel, sid, head_only } => {
+            stream_channel(broker, command_opt, decoder_opt, &request, &mut stream, &client, &channel, &sid, head_only).await;
+        },
+        Route::Playlist { client, channel, sid, head_only } => {
+            hls_playlist(broker, hls, command_opt, decoder_opt, &request, &mut stream, &client, &channel, &sid, head_only).await;
+        },
+        Route::Recording { path, head_only } => {
+            send_recording(command_opt, &request, &mut stream, &path, head_only).await;
+        },
+        Route::Events { head_only } => {
+            stream_events(broker, command_opt, &request, &mut stream, addr, head_only).await;
+        },
+        Route::Done => {},
+    };
 
-                // リクエストを行毎に分解
-                let request  = std::str::from_utf8(&req_buff[..n]).unwrap();
-                let request_line: Vec<&str> = request.lines().collect();
-                debug!("response_stream request={:?}", request_line);
-                
-                
-                // 1行目をパーツ毎に分解
-                let request_line = request_line[0];
-                let mut parts = request_line.split_whitespace();
-                let method = parts.next().unwrap();
-                let path = parts.next().unwrap();
-                let version = parts.next().unwrap();
-                debug!("response_stream Method={} , Path={} , Version{}", method, path, version);
-
-                // urlを分割
-                let uri: Vec<&str> = path.split('/').collect();
-                debug!("response_stream uri.len()={},{:?}",uri.len(), uri); 
-
-                // urlからチャンネルとsidを取得
-                let (channel, sid) = match uri.len() {
-                    // チャンネルとsidが有る時
-                    3 => {
-                        let channel = String::from(uri[1]);
-                        let sid = String::from(uri[2]);
-                        info!("channel={},sid={}",channel, sid);
-                        (channel, sid)
-                    },
-                    // 上記以外
-                    _ => {
-                        (String::from(""), String::from(""))
-                    },
-                };
+    // 接続の終了(TLSの場合はclose_notifyを送信)
+    let _ = timeout(send_timeout, stream.shutdown()).await;
 
-                // httpヘッダーのレスポンス送信
-                let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nCache-Control: no-cache\r\n\r\n";
-                stream.write_all(response).unwrap();
-                stream.flush().unwrap();
+}
 
-                // チャンネル情報からチャンネルタイプ,チャンネル番号,Slot番号の設定
-                let (channel_type, _freq) = channel_type(channel.to_string());
-                if channel_type == "" {
-                    warn!("Bad Channel !!");
-                    return;
-                };
+// リクエストの振り分け＆ストリーム以外の応答作成
+fn route_request(broker: &Arc<SessionBroker>, access: &AccessControl, hls: &HlsManager, command_opt: &CommanLineOpt, request: &HttpRequest,
+    addr: SocketAddr, base: &str, response: &mut Vec<u8>) -> Route {
 
-                // チューナーデバイスの検索
-                let (device, file) = tuner_device(&command_opt.device, &channel.to_string(), &command_opt.reverse_device_order);
+    // 許可されていないアドレスは拒否
+    if access.is_allowed(&addr.ip()) == false {
+        warn!("Access denied ({})", addr);
+        let _ = send_error(response, 403, &[], request.method == "HEAD");
+        return Route::Done;
+    };
 
-                // チューナーデバイスが見つからない場合はリターン
-                if device == "" {
-                    warn!("No devices available");
-                    return;
-                };
+    // メソッド判定
+    let head_only = match &request.method[..] {
+        "GET" => false,
+        "HEAD" => true,
+        // DLNAの制御要求(SOAP)
+        "POST" if command_opt.use_dlna == true && request.segments().starts_with(&[DLNA_PATH, CONTROL_NAME]) => false,
+        "OPTIONS" => {
+            let _ = send_response(response, 204, "", &[("Allow", ALLOW_METHODS.to_string())], b"", false);
+            return Route::Done;
+        },
+        _ => {
+            let _ = send_error(response, 405, &[("Allow", ALLOW_METHODS.to_string())], false);
+            return Route::Done;
+        },
+    };
 
-                // チューナーデバイスファイルの作成
-                let device_file = file.unwrap();
-
-                // チューナーの設定処理を呼び出し
-                tune(&device, &device_file, &channel.to_string(), &command_opt._lnb);
-
-                let signal = tuner::signal_get(&device_file, &channel_type);
-                info!("C/N = {:.6} dB", signal);
-
-                // B25デコード処理
-                let (result, dec, bcas) = match command_opt.use_b25 {
-                    // フラグがtrueの場合に初期設定
-                    true => {
-                        // 初期化処理呼び出し
-                        let (result, dec, bcas) = unsafe {
-                            b25_startup(decoder_opt.round,
-                                decoder_opt.strip, decoder_opt.emm)
-                        };
-                        (result, dec, bcas)
-                    },
-                    // true以外は変数初期化のみ
-                    _ => (-1, 0 as *mut ARIB_STD_B25, 0 as *mut B_CAS_CARD),
+    // 認証(チューナーを使用する前にチェック)
+    if access.authenticate(request) == false {
+        warn!("Authentication required ({})", addr);
+        let _ = send_error(response, 401, &[("WWW-Authenticate", AUTHENTICATE_REALM.to_string())], head_only);
+        return Route::Done;
+    };
+
+    // クライアント情報(優先度はX-Mirakurun-Priorityヘッダー、認証情報に優先度が有る場合はそれが上限)
+    let client = match parse_priority(request.header(PRIORITY_HEADER)) {
+        Ok(priority) => ClientInfo {
+            addr: addr,
+            priority: client_priority(priority, access.credential(request).and_then(|credential| credential.priority)),
+            agent: request.header("User-Agent").unwrap_or("").to_string(),
+        },
+        Err(message) => {
+            warn!("Bad request from {} ({})", addr, message);
+            let _ = send_error(response, 400, &[], head_only);
+            return Route::Done;
+        },
+    };
+
+    // API・ステータス系のリクエスト処理
+    match &request.path[..] {
+        // セッション一覧
+        "/api/status" => {
+            let _ = send_json(response, &status_json(broker), head_only);
+            return Route::Done;
+        },
+        // 監視イベント(SSE)
+        "/api/events" => {
+            return Route::Events { head_only: head_only };
+        },
+        // チューナー一覧
+        "/api/tuners" => {
+            let _ = send_json(response, &tuners_json(broker), head_only);
+            return Route::Done;
+        },
+        // チャンネル一覧(M3Uプレイリスト)
+        "/channels.m3u" => {
+            let m3u = channels_m3u(&channel_map(), base);
+            let _ = send_response(response, 200, M3U_CONTENT_TYPE, &[], m3u.as_bytes(), head_only);
+            return Route::Done;
+        },
+        // チャンネル一覧(JSON)
+        "/api/channels" => {
+            let json = channels_json(&channel_map(), base);
+            let _ = send_json(response, &json, head_only);
+            return Route::Done;
+        },
+        // Prometheus/OpenMetricsメトリクス
+        "/metrics" => {
+            let _ = send_response(response, 200, OPENMETRICS_CONTENT_TYPE, &[], metrics_text(broker).as_bytes(), head_only);
+            return Route::Done;
+        },
+        _ => {},
+    };
+
+    // Mirakurun互換API(/api/channels/{type}/... , /api/services/...)
+    let segments = request.segments();
+    match &segments[..] {
+        // タイプ別のチャンネル一覧
+        ["api", "channels", mirakurun_type] => {
+            if channel_type_of(mirakurun_type).is_none() {
+                let _ = send_error(response, 404, &[], head_only);
+                return Route::Done;
+            };
+            let entries: Vec<ChannelEntry> = channel_map().into_iter()
+                .filter(|entry| mirakurun_type_of(&entry.channel_type) == *mirakurun_type)
+                .collect();
+            let json = channels_json(&entries, base);
+            let _ = send_json(response, &json, head_only);
+            return Route::Done;
+        },
+        // チャンネルのストリーム(全サービス)
+        ["api", "channels", mirakurun_type, channel, "stream"] => {
+            match find_channel(&channel_map(), mirakurun_type, channel) {
+                Some(entry) => return Route::Stream { client: client, channel: entry.channel.to_string(), sid: "".to_string(), head_only: head_only },
+                None => {
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        },
+        // チャンネル内のサービスのストリーム
+        ["api", "channels", mirakurun_type, channel, "services", service_id, "stream"] => {
+            match (find_channel(&channel_map(), mirakurun_type, channel), service_id.parse::<u32>()) {
+                (Some(entry), Ok(service_id)) => return Route::Stream { client: client, channel: entry.channel.to_string(), sid: service_id.to_string(), head_only: head_only },
+                _ => {
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        },
+        // サービス一覧
+        ["api", "services"] => {
+            let json = services_json(&service_list(&channel_map(), base));
+            let _ = send_json(response, &json, head_only);
+            return Route::Done;
+        },
+        // サービス情報
+        ["api", "services", id] => {
+            match find_service(&channel_map(), id) {
+                Some(mut service) => {
+                    service.url = format!("{}/api/services/{}/stream", base, service.id);
+                    let _ = send_json(response, &service_json(&service), head_only);
+                },
+                None => {
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        },
+        // サービスのストリーム(SID分離)
+        ["api", "services", id, "stream"] => {
+            match find_service(&channel_map(), id) {
+                Some(service) => return Route::Stream { client: client, channel: service.channel.channel.to_string(), sid: service.sid.to_string(), head_only: head_only },
+                None => {
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        },
+        _ => {},
+    };
+
+    // HDHomeRunエミュレーション(Plex,Jellyfin等のLive TV向け)
+    if command_opt.use_hdhomerun == true {
+        match &request.path[..] {
+            // デバイス情報
+            "/discover.json" => {
+                let json = discover_json(base, tuner_list().len(), command_opt._http_port);
+                let _ = send_json(response, &json, head_only);
+                return Route::Done;
+            },
+            // チャンネルラインナップ
+            "/lineup.json" => {
+                let json = lineup_json(&channel_map(), base);
+                let _ = send_json(response, &json, head_only);
+                return Route::Done;
+            },
+            // チャンネルスキャンの状態
+            "/lineup_status.json" => {
+                let _ = send_json(response, &lineup_status_json(), head_only);
+                return Route::Done;
+            },
+            _ => {},
+        };
+
+        // ストリーム(/auto/v<ガイド番号>)
+        if let Some(number) = request.path.strip_prefix("/auto/v") {
+            match find_lineup(&channel_map(), number) {
+                Some(entry) => return Route::Stream { client: client, channel: entry.channel.to_string(), sid: entry.sid.to_string(), head_only: head_only },
+                None => {
+                    warn!("Bad Guide Number !! ({})", number);
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        };
+    };
+
+    // UPnP/DLNAメディアサーバー(デバイス記述 , サービス記述 , 制御要求)
+    if command_opt.use_dlna == true {
+        match &segments[..] {
+            [DLNA_PATH, DESCRIPTION_NAME] => {
+                let xml = description_xml(command_opt._http_port);
+                let _ = send_response(response, 200, XML_CONTENT_TYPE, &[], xml.as_bytes(), head_only);
+                return Route::Done;
+            },
+            [DLNA_PATH, CONTROL_NAME, service] if request.method == "POST" => {
+                let (status, xml) = control_response(service, request.header("SOAPACTION"), &request.body, &channel_map(), base);
+                match status {
+                    404 => { let _ = send_error(response, 404, &[], false); },
+                    _ => { let _ = send_response(response, status, XML_CONTENT_TYPE, &[("EXT", "".to_string())], xml.as_bytes(), false); },
                 };
-                if result < 0  && command_opt.use_b25 == true {
-                    command_opt.use_b25 = false;
-                    error!("Disabled B25...");
+                return Route::Done;
+            },
+            [DLNA_PATH, CONTROL_NAME, ..] => {
+                let _ = send_error(response, 405, &[("Allow", "POST".to_string())], head_only);
+                return Route::Done;
+            },
+            [DLNA_PATH, name] => {
+                match service_xml(name) {
+                    Some(xml) => { let _ = send_response(response, 200, XML_CONTENT_TYPE, &[], xml.as_bytes(), head_only); },
+                    None => { let _ = send_error(response, 404, &[], head_only); },
                 };
+                return Route::Done;
+            },
+            _ => {},
+        };
+    };
 
-                // ts splitterの初期化処理
-                let mut sp = split_startup(&sid);
-                let mut split_select_finish = TSS_ERROR;
-                if sid != "" { command_opt.use_splitter = true; };
+    // 録画ファイル(/recordings/ は一覧 , /recordings/{name} はファイル)
+    match &segments[..] {
+        ["recordings"] if command_opt.recordings_dir != "" => {
+            let _ = send_json(response, &recordings_json(&command_opt.recordings_dir, base), head_only);
+            return Route::Done;
+        },
+        ["recordings", name] if command_opt.recordings_dir != "" => {
+            match recording_path(&command_opt.recordings_dir, name) {
+                Some(path) => return Route::Recording { path: path, head_only: head_only },
+                None => {
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        },
+        ["recordings", ..] => {
+            let _ = send_error(response, 404, &[], head_only);
+            return Route::Done;
+        },
+        _ => {},
+    };
 
-                // 録画開始コマンド出力
-                unsafe { start_rec(device_file.as_raw_fd()).unwrap() };
-                info!("Recording...");
+    // HLS(/hls/{channel}[/{sid}]/index.m3u8 , セグメントはプレイリストからの相対URL)
+    match &segments[..] {
+        ["hls", channel, PLAYLIST_NAME] => {
+            return Route::Playlist { client: client, channel: channel.to_string(), sid: "".to_string(), head_only: head_only };
+        },
+        ["hls", channel, sid, PLAYLIST_NAME] => {
+            return Route::Playlist { client: client, channel: channel.to_string(), sid: sid.to_string(), head_only: head_only };
+        },
+        ["hls", _, name] | ["hls", _, _, name] => {
+            let segment = parse_segment_name(name)
+                .and_then(|(id, sequence)| hls.stream(id).map(|stream| (stream, sequence)))
+                .and_then(|(stream, sequence)| {
+                    stream.touch();
+                    stream.segment(sequence)
+                });
+            match segment {
+                Some(data) => {
+                    let _ = send_response(response, 200, STREAM_CONTENT_TYPE, &hls_headers(), &data, head_only);
+                },
+                None => {
+                    let _ = send_error(response, 404, &[], head_only);
+                },
+            };
+            return Route::Done;
+        },
+        _ => {},
+    };
 
-                // 出力用のバッファ作成
-                let mut data_reader = BufReader::with_capacity(CAP, &device_file);
+    // urlからチャンネルとsidを取得(/channel または /channel/sid)
+    let (channel, sid) = match segments.len() {
+        1 => (segments[0].to_string(), "".to_string()),
+        2 => (segments[0].to_string(), segments[1].to_string()),
+        _ => {
+            let _ = send_error(response, 404, &[], head_only);
+            return Route::Done;
+        },
+    };
 
-                // パケットドロップチェック用のパケット巡回カウンター変数の作成
-                let mut continuity_counter: i32;
-                let mut continuity_counter_flag: [i32; MAX_PID] = [0; MAX_PID];
-                let mut next_continuity_counter: [i32; MAX_PID] = [0; MAX_PID];
+    Route::Stream { client: client, channel: channel, sid: sid, head_only: head_only }
 
-                loop {
-                    // バッファへ読み込み
-                    let length = {
-                        let read_buffer = data_reader.fill_buf().unwrap();
-                        //debug!("response_stream data_reader={:?}",&read_buffer.len());
+}
 
-                        // データ長がCAPと違う場合はデバッグ出力
-                        if read_buffer.len() != CAP {
+// チャンネルのストリーム配信処理
+async fn stream_channel(broker: &Arc<SessionBroker>, command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions,
+    request: &HttpRequest, stream: &mut ClientStream, client: &ClientInfo, channel: &str, sid: &str, head_only: bool) -> () {
 
-                            debug!("録画終了？ (CAP={} , read_buffer.len={})", CAP, read_buffer.len());
+    let addr = client.addr;
+    let send_timeout = Duration::from_secs(command_opt.send_timeout);
 
-                        };
+    // クエリパラメータ・チャンネル情報のチェック
+    let decoder_opt = match channel_options(request, command_opt, decoder_opt, addr, channel, sid) {
+        Ok(decoder_opt) => decoder_opt,
+        Err(status) => {
+            respond(stream, send_timeout, |w| send_error(w, status, &[], head_only)).await;
+            return;
+        },
+    };
+    let sid = command_opt.sid_list.to_string();
 
-                        // use_splitterがfalseの場合に対象PID取得処理をここで実施
-                        if command_opt.use_splitter == false {
-                            // パケットドロップチェック用のデータバッファ作成
-                            let mut check_data_buff: Vec<u8> = vec![0; read_buffer.len()];
-                            check_data_buff[..read_buffer.len()].copy_from_slice(&read_buffer[..read_buffer.len()]);
-                            if split_select_finish != TSS_SUCCESS {
-                                split_select_finish = split_select(&mut sp, &mut check_data_buff);
-                            };
-                        };
+    // HEADの場合はチューナーをオープンせずにヘッダーのみ送信
+    if head_only == true {
+        respond(stream, send_timeout, |w| send_header(w, 200, STREAM_CONTENT_TYPE, None, &dlna_headers(request))).await;
+        return;
+    };
 
-                        // パケットドロップチェック用のバッファ処理インデックス
-                        let mut index = 0;
+    // チャンネルの購読開始(同じチャンネルのセッションが有る場合は共有)
+    // チューナーのオープン・選局はブロッキング処理のため別スレッドで実行
+    let subscribed = {
+        let broker = Arc::clone(broker);
+        let client = client.clone();
+        let channel = channel.to_string();
+        let sid = sid.to_string();
+        let command_opt = command_opt.clone();
+        let decoder_opt = decoder_opt.clone();
+        tokio::task::spawn_blocking(move || broker.subscribe(&client, &channel, &sid, &command_opt, &decoder_opt)).await
+    };
+    let (session, subscriber) = match subscribed {
+        Ok(Ok(subscribed)) => subscribed,
+        Ok(Err(e)) => {
+            warn!("Session start error ({:?})", e);
+            let status = session_error_status(e);
+            let retry_after = [("Retry-After", RETRY_AFTER.to_string())];
+            respond(stream, send_timeout, |w| send_error(w, status, if status == 503 { &retry_after } else { &[] }, false)).await;
+            return;
+        },
+        Err(e) => {
+            error!("Session start error ({})", e);
+            respond(stream, send_timeout, |w| send_error(w, 500, &[], false)).await;
+            return;
+        },
+    };
 
-                        // バッファ終了までループ(パケットドロップチェック)
-                        while (read_buffer.len() as i32 - index as i32 - LENGTH_PACKET as i32) >= 0 {
+    // httpヘッダーのレスポンス送信(タイムシフトバッファが有る場合は再開用のIDを付加、DLNAクライアントには転送モードを応答)
+    let mut headers = vec![(USER_ID_HEADER, addr.to_string())];
+    if session.timeshift.is_some() {
+        headers.push((TIMESHIFT_ID_HEADER, subscriber.id.to_string()));
+    };
+    headers.extend(dlna_headers(request));
+    if respond(stream, send_timeout, |w| send_header(w, 200, STREAM_CONTENT_TYPE, None, &headers)).await == false {
+        broker.unsubscribe(&session, &subscriber);
+        return;
+    };
 
-                            // PID取得
-                            let pid = get_pid(&read_buffer[index..index + LENGTH_PACKET - 1]) as usize;
+    // 遅延時に切断する場合は送信の待ち合わせも同じ時間で打ち切る
+    let send_timeout = match subscriber.policy {
+        SlowClientPolicy::Disconnect(timeout) => send_timeout.min(timeout.max(Duration::from_secs(1))),
+        _ => send_timeout,
+    };
 
-                            // パケット巡回カウンターの作成
-                            continuity_counter = (read_buffer[index + 3] & 0x0f) as i32;
+    // ts splitterの初期化処理(SID分離は購読者毎に実施)
+    let mut pipeline = TsPipeline::new(&sid, command_opt.use_splitter);
+    let mut rescans = 0;
 
-                            // パケットドロップチェック
-                            if (sp.pmt_pids[pid] > 0 || (sp.pids[pid] > 0 && pid < 0x100)) &&
-                                continuity_counter_flag[pid] == 1 && continuity_counter != next_continuity_counter[pid] {
+    // セッションから配信されたデータをストリーム出力
+    let start_time = SystemTime::now();
+    loop {
 
-                                let signal = signal_get(&device_file, &channel_type);
-                                debug!("パケットドロップ PID={}(0x{:04x}) , continuity_counter={} , next_continuity_counter={} signel={}",
-                                    pid, pid, continuity_counter, next_continuity_counter[pid], signal);
+        // 視聴時間が経過したら終了
+        if command_opt.duration > 0 && SystemTime::now().duration_since(start_time).unwrap_or_default().as_secs() >= command_opt.duration {
+            info!("Duration reached ({}sec, {})", command_opt.duration, addr);
+            break;
+        };
 
-                            };
+        // 遅延による切断
+        if subscriber.disconnected.load(Ordering::Acquire) {
+            info!("Slow client disconnected ({}, lag={}ms)", addr, subscriber.lag_ms.load(Ordering::Relaxed));
+            break;
+        };
 
-                            // 次パケット巡回カウンターの計算
-                            next_continuity_counter[pid] = (continuity_counter + 1) & 0x0f;
-                            continuity_counter_flag[pid] = 1;
+        // 配信データの取り出し(空の場合は配信通知を待ち合わせ、セッション終了時はループ終了)
+        // タイムシフト再生中のバッファ(ディスク)の読み込みは他のタスクを止めないようブロッキング扱いで行う
+        let next = match subscriber.is_shifted() {
+            true => tokio::task::block_in_place(|| subscriber.next_chunk(&session)),
+            false => subscriber.next_chunk(&session),
+        };
+        let chunk = match next {
+            Some(chunk) => chunk,
+            None if subscriber.queue.is_closed() => break,
+            None => {
+                let _ = timeout(Duration::from_secs(1), subscriber.ready.notified()).await;
+                continue;
+            },
+        };
 
-                            // 次パケットまでインデックス更新
-                            index += LENGTH_PACKET;
+        // ts splitter処理(分離しない場合はbufferをそのまま出力)
+        let write_buffer = pipeline.process(&chunk.data);
 
-                        };
+        // ストリーム出力(送信できない状態が続くクライアントは切断)
+        if write_buffer.len() > 0 {
+            if let Err(message) = send_data(stream, write_buffer, send_timeout).await {
+                error!("Data Send Error({})", message);
+                break;
+            };
+            subscriber.sent.fetch_add(write_buffer.len() as u64, Ordering::Relaxed);
+        };
+        subscriber.record_lag(&chunk);
+
+        // SID分離中のPMTのバージョン変更(rescan_pid)
+        if pipeline.sp.rescans != rescans {
+            rescans = pipeline.sp.rescans;
+            broker.events.publish(Event::PsiChanged {
+                session: session.id, channel: session.channel.to_string(), table: "PMT".to_string(),
+                pid: pipeline.sp.rescan_pid as u16, version: pipeline.sp.rescan_version, client: Some(addr.to_string()),
+            });
+        };
 
-                        // リードバッファ作成
-                        let b25_buff = ARIB_STD_B25_BUFFER {
-                            //data: read_buffer.clone().as_ptr() as *mut u8,
-                            data: read_buffer.as_ptr() as *mut u8,
-                            size: read_buffer.len() as u32,
-                        };
+    };
 
+    // チャンネルの購読終了
+    broker.unsubscribe(&session, &subscriber);
+    info!("Connection closed ({})", addr);
 
-                        // B25デコード処理
-                        let (buffer, len) = match command_opt.use_b25 {
-                            // フラグがtrueの場合にデコード
-                            true => {
-                                // デコード処理
-                                let (buffer, len) = unsafe { b25_decode(dec, &b25_buff) };
+}
 
-                                (buffer, len)
-                            },
-                            // フラグがtrue以外の処理
-                            false => {
-                                // リードデータをそのまま出力
-                                let buffer = read_buffer as &[u8];
+// HLSのプレイリスト応答処理
+// HLSストリームが無い場合はチューナーをオープンしてセグメント分割を開始し、最初のセグメントを待ち合わせる
+async fn hls_playlist(broker: &Arc<SessionBroker>, hls: &Arc<HlsManager>, command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions,
+    request: &HttpRequest, stream: &mut ClientStream, client: &ClientInfo, channel: &str, sid: &str, head_only: bool) -> () {
+
+    let addr = client.addr;
+    let send_timeout = Duration::from_secs(command_opt.send_timeout);
+
+    // クエリパラメータ・チャンネル情報のチェック
+    let decoder_opt = match channel_options(request, command_opt, decoder_opt, addr, channel, sid) {
+        Ok(decoder_opt) => decoder_opt,
+        Err(status) => {
+            respond(stream, send_timeout, |w| send_error(w, status, &[], head_only)).await;
+            return;
+        },
+    };
 
-                                (buffer, read_buffer.len() as i32)
-                            },
-                        };
+    // HEADの場合はチューナーをオープンせずにヘッダーのみ送信
+    if head_only == true {
+        respond(stream, send_timeout, |w| send_header(w, 200, PLAYLIST_CONTENT_TYPE, None, &hls_headers())).await;
+        return;
+    };
 
-                        // ts splitter向け変数
-                        let mut split_buff: Vec<u8> = vec![];
-                        let mut result = 0;
+    // HLSストリームの開始または参加(チューナーのオープンは別スレッドで実行)
+    let opened = {
+        let hls = Arc::clone(hls);
+        let broker = Arc::clone(broker);
+        let client = client.clone();
+        let channel = channel.to_string();
+        let sid = command_opt.sid_list.to_string();
+        let command_opt = command_opt.clone();
+        tokio::task::spawn_blocking(move || hls.open(&broker, &client, &channel, &sid, &command_opt, &decoder_opt)).await
+    };
+    let hls_stream = match opened {
+        Ok(Ok(hls_stream)) => hls_stream,
+        Ok(Err(e)) => {
+            warn!("HLS start error ({:?})", e);
+            let status = session_error_status(e);
+            let retry_after = [("Retry-After", RETRY_AFTER.to_string())];
+            respond(stream, send_timeout, |w| send_error(w, status, if status == 503 { &retry_after } else { &[] }, false)).await;
+            return;
+        },
+        Err(e) => {
+            error!("HLS start error ({})", e);
+            respond(stream, send_timeout, |w| send_error(w, 500, &[], false)).await;
+            return;
+        },
+    };
 
-                        // ts splitter処理
-                        if command_opt.use_splitter == true &&  len > 0 {
+    // 最初のセグメント作成待ち
+    let wait_start = SystemTime::now();
+    let wait_limit = Duration::from_secs(command_opt.hls_segment * 3 + HLS_START_TIMEOUT);
+    while hls_stream.segment_count() == 0 && hls_stream.is_closed() == false
+        && SystemTime::now().duration_since(wait_start).unwrap_or_default() < wait_limit {
+        hls_stream.touch();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+    hls_stream.touch();
 
-                            // データバッファ作成
-                            let mut data_buff: Vec<u8> = vec![0; buffer.len()];
-                            data_buff[..buffer.len()].copy_from_slice(&buffer[..buffer.len()]);
+    let playlist = hls_stream.playlist();
+    respond(stream, send_timeout, |w| send_response(w, 200, PLAYLIST_CONTENT_TYPE, &hls_headers(), playlist.as_bytes(), false)).await;
 
-                            // 処理するsidの取得
-                            if split_select_finish != TSS_SUCCESS {
+}
 
-                                split_select_finish = split_select(&mut sp, &mut data_buff);
+// HLS応答の追加ヘッダー(ブラウザの別オリジンのプレーヤーから再生可能にする)
+fn hls_headers() -> [(&'static str, String); 1] {
 
-                            };
+    [("Access-Control-Allow-Origin", "*".to_string())]
 
-                            // sid取得OK時の処理
-                            if split_select_finish == TSS_SUCCESS {
+}
 
-                                // sid split処理
-                                result = split_ts(&mut sp, &mut data_buff, &mut split_buff);
+// クエリパラメータの反映＆チャンネル情報のチェック
+// このセッションのデコードオプションをリターン(エラー時はステータスコード)
+fn channel_options(request: &HttpRequest, command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions,
+    addr: SocketAddr, channel: &str, sid: &str) -> Result<DecoderOptions, u16> {
+
+    // クエリパラメータをこのセッションのオプションへ反映
+    let mut decoder_opt = decoder_opt.clone();
+    command_opt.sid_list = sid.to_string();
+    command_opt.duration = 0;
+    if let Err(message) = apply_query(request, command_opt, &mut decoder_opt) {
+        warn!("Bad query from {} ({})", addr, message);
+        return Err(400);
+    };
+    info!("channel={},sid={}",channel, command_opt.sid_list);
 
-                            };
+    // チャンネル情報のチェック
+    let (channel_type, _freq) = channel_type(channel.to_string());
+    if channel_type == "" {
+        warn!("Bad Channel !! ({})", channel);
+        return Err(404);
+    };
+
+    Ok(decoder_opt)
+
+}
 
-                        };
+// 監視イベントの配信処理(Server-Sent Events)
+// ?types=signal,continuity_errors のようにイベント名を指定した場合はそのイベントのみ送信する
+async fn stream_events(broker: &Arc<SessionBroker>, command_opt: &CommanLineOpt, request: &HttpRequest, stream: &mut ClientStream,
+    addr: SocketAddr, head_only: bool) -> () {
+
+    let send_timeout = Duration::from_secs(command_opt.send_timeout);
+    let types: Vec<String> = request.query_value("types").unwrap_or("")
+        .split(',').filter(|name| *name != "").map(|name| name.to_string()).collect();
+
+    // httpヘッダーのレスポンス送信(再接続までの待ち時間も通知)
+    let mut receiver = broker.events.subscribe();
+    if respond(stream, send_timeout, |w| send_header(w, 200, EVENT_STREAM_CONTENT_TYPE, None, &[("Access-Control-Allow-Origin", "*".to_string())])).await == false || head_only {
+        return;
+    };
+    if send_data(stream, format!("retry: {}\n\n", EVENT_RETRY * 1000).as_bytes(), send_timeout).await.is_err() {
+        return;
+    };
+    info!("Event stream opened ({} , listeners={})", addr, broker.events.listeners());
 
-                        // データ送信
-                        if len > 0  ||  split_buff.len() > 0 {
-                            // 出力バッファ作成
-                            let write_buffer = if command_opt.use_splitter == true {
+    loop {
 
-                                if result != TSS_SUCCESS && split_buff.len() > 0 {
-                                    debug!("response_stream split_ts failed");
-                                };
+        // イベントの受信(一定時間イベントが無い場合は接続維持のコメントを送信)
+        let data = match timeout(Duration::from_secs(EVENT_KEEPALIVE), receiver.recv()).await {
+            Ok(Ok(message)) => {
+                if types.len() > 0 && types.iter().any(|name| name == message.event.name()) == false {
+                    continue;
+                };
+                let stopping = matches!(message.event, Event::DaemonStopping {});
+                let data = message.to_sse();
+                if stopping {
+                    let _ = send_data(stream, data.as_bytes(), send_timeout).await;
+                    break;
+                };
+                data
+            },
+            Ok(Err(RecvError::Lagged(missed))) => {
+                warn!("Event stream lagged ({} , missed={})", addr, missed);
+                EventMessage { id: 0, time: 0, event: Event::Lagged { missed: missed } }.to_sse()
+            },
+            Ok(Err(RecvError::Closed)) => break,
+            Err(_) => ": keep-alive\n\n".to_string(),
+        };
 
-                                // ts splitter処理時のバッファ作成
-                                let buffer: &[u8] = &split_buff;
+        if let Err(message) = send_data(stream, data.as_bytes(), send_timeout).await {
+            debug!("Event send error ({})", message);
+            break;
+        };
 
-                                // リターン情報
-                                buffer
+    };
 
-                            }
-                            else {
+    info!("Event stream closed ({})", addr);
 
-                                // リターン情報
-                                buffer
-                            };
+}
 
-                            // ストリーム出力
-                            if write_buffer.len() > 0 {
-                                match stream.write_all(write_buffer) {
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        error!("Data Send Error({})",e);
+// 録画ファイルの配信処理
+// Rangeヘッダーの範囲指定に対応し、録画中のファイルは範囲指定が無い場合に録画が終わるまで追っかけ再生する
+// ?pace=1 の場合はPCRに合わせて実時間で送信する
+async fn send_recording(command_opt: &CommanLineOpt, request: &HttpRequest, stream: &mut ClientStream, path: &PathBuf, head_only: bool) -> () {
+
+    let send_timeout = Duration::from_secs(command_opt.send_timeout);
+
+    // ファイルのオープン
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Recording open error ({}, {})", path.display(), e);
+            respond(stream, send_timeout, |w| send_error(w, 404, &[], head_only)).await;
+            return;
+        },
+    };
+    let (size, in_progress) = match file.metadata().await {
+        Ok(metadata) => (metadata.len(), is_recording(&metadata)),
+        Err(_) => (0, false),
+    };
+    let is_ts = is_recording_file(path);
+    let content_type = match path.extension().and_then(|extension| extension.to_str()) {
+        _ if is_ts => STREAM_CONTENT_TYPE,
+        Some("json") => "application/json; charset=utf-8",
+        _ => "application/octet-stream",
+    };
 
-                                        // B-CASリーダーシャットダウン
-                                        if command_opt.use_b25 == true {
-                                            unsafe { b25_shutdown(dec, bcas) };
-                                            info!("B25 shutdown");
-                                        };
+    // 範囲指定の解析(範囲外は416)
+    let range = match request.header("Range") {
+        Some(value) => match parse_range(value, size) {
+            Ok(range) => range,
+            Err(_) => {
+                respond(stream, send_timeout, |w| send_error(w, 416, &[("Content-Range", format!("bytes */{}", size))], head_only)).await;
+                return;
+            },
+        },
+        None => None,
+    };
+    let chase = in_progress && is_ts && range.is_none() && request.query_value("chase") != Some("0");
+    let packet_length = packet_length(path);
+    let mut pacer = match request.query_value("pace") {
+        Some("1") => Some(Pacer::new(packet_length)),
+        _ => None,
+    };
 
-                                        break read_buffer.len() as u64
-                                    },
-                                };
-                            };
-                        }
+    // httpヘッダーのレスポンス送信(追っかけ再生は長さ不明、録画中の範囲指定は全体の長さ不明)
+    let mut headers = vec![("Accept-Ranges", "bytes".to_string())];
+    let (status, start, length) = match range {
+        Some((start, end)) => {
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, if in_progress { "*".to_string() } else { size.to_string() })));
+            (206, start, Some(end - start + 1))
+        },
+        None if chase => (200, 0, None),
+        None => (200, 0, Some(size)),
+    };
+    if respond(stream, send_timeout, |w| send_header(w, status, content_type, length.map(|length| length as usize), &headers)).await == false || head_only {
+        return;
+    };
+    info!("Recording {} (status={} , start={} , chase={} , pace={})", path.display(), status, start, chase, pacer.is_some());
 
-                        // リターン情報
-                        read_buffer.len()
+    // ファイルの送信
+    if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+        error!("Recording seek error ({}, {})", path.display(), e);
+        return;
+    };
+    let mut remaining = length;
+    let mut read_buffer = vec![0u8; CAP / LENGTH_PACKET * packet_length];
+    let mut last_data = SystemTime::now();
+    let mut sent: u64 = 0;
+    loop {
 
+        let limit = remaining.map(|remaining| remaining.min(read_buffer.len() as u64) as usize).unwrap_or(read_buffer.len());
+        if limit == 0 { break; };
+
+        // 追っかけ再生はファイルサイズまでの書き込み済みのパケットのみ送信し、更新が止まったら終了
+        let n = match chase {
+            true => {
+                let size = file.metadata().await.map(|metadata| metadata.len()).unwrap_or(0);
+                let written = written_length(size, start + sent, packet_length).min(limit as u64) as usize;
+                if written == 0 {
+                    if SystemTime::now().duration_since(last_data).unwrap_or_default().as_secs() >= RECORDING_IDLE {
+                        debug!("Recording finished ({})", path.display());
+                        break;
                     };
+                    tokio::time::sleep(Duration::from_millis(CHASE_INTERVAL)).await;
+                    continue;
+                };
+                if let Err(e) = file.read_exact(&mut read_buffer[..written]).await {
+                    error!("Recording read error ({}, {})", path.display(), e);
+                    break;
+                };
+                last_data = SystemTime::now();
+                written
+            },
+            false => match file.read(&mut read_buffer[..limit]).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Recording read error ({}, {})", path.display(), e);
+                    break;
+                },
+            },
+        };
+
+        // PCRによる送信ペース調整
+        if let Some(pacer) = pacer.as_mut() {
+            let wait = pacer.wait(&read_buffer[..n]);
+            if wait > Duration::ZERO {
+                tokio::time::sleep(wait).await;
+            };
+        };
 
-                    // リードバッファクリア
-                    //debug!("response_stream clear length={:?}", length);
-                    data_reader.consume(length.try_into().unwrap());
+        if let Err(message) = send_data(stream, &read_buffer[..n], send_timeout).await {
+            debug!("Recording send error ({})", message);
+            break;
+        };
+        sent += n as u64;
+        remaining = remaining.map(|remaining| remaining - n as u64);
 
-                };
+    };
+
+    info!("Recording {} closed (bytes={})", path.display(), sent);
 
+}
+
+// データ送信(send_timeout以内に送信できない場合はエラー)
+async fn send_data<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8], send_timeout: Duration) -> Result<(), String> {
+
+    match timeout(send_timeout, writer.write_all(data)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("send timeout {}sec", send_timeout.as_secs())),
+    }
+
+}
+
+// 応答の作成＆送信
+async fn respond<F>(stream: &mut ClientStream, send_timeout: Duration, build: F) -> bool
+    where F: FnOnce(&mut Vec<u8>) -> io::Result<()> {
+
+    let mut response = vec![];
+    let _ = build(&mut response);
+
+    send_data(stream, &response, send_timeout).await.is_ok()
+
+}
+
+// アクセス制御の作成
+fn access_control(command_opt: &CommanLineOpt) -> Result<AccessControl, String> {
+
+    Ok(AccessControl {
+        allow: parse_cidr_list(&command_opt.allow)?,
+        deny: parse_cidr_list(&command_opt.deny)?,
+        credentials: if command_opt.auth_file != "" { load_auth_file(&command_opt.auth_file)? } else { vec![] },
+    })
+
+}
+
+// リクエストのベースURL(Hostヘッダー、無い場合は待ち受けアドレス)
+fn base_url(request: &HttpRequest, stream: &ClientStream) -> String {
+
+    let host = match request.header("Host") {
+        Some(host) if host != "" => host.to_string(),
+        _ => match stream.local_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "localhost".to_string(),
+        },
+    };
+    let scheme = if stream.is_tls() { "https" } else { "http" };
+
+    format!("{}://{}", scheme, host)
+
+}
+
+// クエリパラメータのオプション反映
+// ?b25=0|1(?decode=0|1) , ?strip=0|1 , ?emm=0|1 , ?round=N , ?sid=101,102 , ?duration=秒 , ?device=デバイスファイル , ?lnb=0|11|15
+// ?slow=drop-oldest|disconnect|skip-to-pat
+fn apply_query(request: &HttpRequest, command_opt: &mut CommanLineOpt, decoder_opt: &mut DecoderOptions) -> Result<(), String> {
+
+    for (key, value) in &request.query {
+        match &key[..] {
+            // B25デコードの有効/無効(decodeはMirakurun互換)
+            "b25" | "decode" => {
+                command_opt.use_b25 = query_flag(key, value)?;
             },
-            Err(e) => {
-                error!("Stream Read Error: {}", e);
-                break;
+            // b25デコードオプションフラグ(strip)
+            "strip" => {
+                decoder_opt.strip = if query_flag(key, value)? { TRUE } else { FALSE };
             },
-        }
+            // b25デコードオプションフラグ(EMM)
+            "emm" => {
+                decoder_opt.emm = if query_flag(key, value)? { TRUE } else { FALSE };
+            },
+            // 処理時のラウンド係数
+            "round" => {
+                command_opt._use_round = true;
+                decoder_opt.round = value.parse::<i32>().map_err(|_| format!("round={}", value))?;
+            },
+            // 処理するSID
+            "sid" => {
+                if value.split(',').any(|sid| sid == "") {
+                    return Err(format!("sid={}", value));
+                };
+                command_opt.sid_list = value.to_string();
+            },
+            // 視聴時間(秒)
+            "duration" => {
+                command_opt.duration = value.parse::<u64>().map_err(|_| format!("duration={}", value))?;
+            },
+            // チューナーデバイス(デバイステーブルに有るもののみ)
+            "device" => {
+                if is_tuner_device(value) == false {
+                    return Err(format!("device={}", value));
+                };
+                command_opt._use_device = true;
+                command_opt.device = value.to_string();
+            },
+            // LNB voltage
+            "lnb" => {
+                command_opt._use_lnb = true;
+                command_opt._lnb = match &value[..] {
+                    "0" => 0,
+                    "11" => 1,
+                    "15" => 2,
+                    _ => return Err(format!("lnb={}", value)),
+                };
+            },
+            // タイムシフト再生の開始位置(最新からの秒数、0以下)
+            "offset" => {
+                command_opt.offset = match value.parse::<i64>() {
+                    Ok(offset) if offset <= 0 => offset,
+                    _ => return Err(format!("offset={}", value)),
+                };
+            },
+            // タイムシフト再生の再開(切断時のX-Recpt3-Timeshift-Id)
+            "resume" => {
+                command_opt.resume = value.parse::<u64>().map_err(|_| format!("resume={}", value))?;
+            },
+            // 遅延時の処理方法
+            "slow" => {
+                if SlowClientPolicy::parse(value, 0).is_none() {
+                    return Err(format!("slow={}", value));
+                };
+                command_opt.slow_client = value.to_string();
+            },
+            // 上記以外は無視
+            _ => {
+                debug!("apply_query ignored {}={}", key, value);
+            },
+        };
+    };
+
+    // SID指定時は分離処理を有効化
+    command_opt.use_splitter = command_opt.sid_list != "";
+
+    Ok(())
+
+}
+
+// クエリパラメータのフラグ値(0|1)
+fn query_flag(key: &str, value: &str) -> Result<bool, String> {
+
+    match value {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(format!("{}={}", key, value)),
+    }
+
+}
+
+// クライアントの優先度(ヘッダー・認証情報とも未指定は0)
+// ヘッダーの優先度は認証情報のpriority(認証なし・priority未指定は0)を上限とし、下げる方向のみ受け付ける
+fn client_priority(header: Option<i32>, credential: Option<i32>) -> i32 {
+
+    let limit = credential.unwrap_or(0);
+    match header {
+        Some(header) => header.min(limit),
+        None => limit,
     }
+
+}
+
+// セッション開始エラーに対応するステータスコード
+fn session_error_status(error: SessionError) -> u16 {
+
+    match error {
+        // 不明なチャンネル
+        SessionError::BadChannel => 404,
+        // 空きチューナー無し、選局失敗
+        SessionError::NoDevice | SessionError::TuneFailed => 503,
+        // B-CASカードの初期化失敗
+        SessionError::B25Failed => 500,
+        // セッション数の上限
+        SessionError::SessionLimit => 503,
+    }
+
 }
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+
+// textfile出力・メトリクス更新の間隔(秒)
+#[allow(dead_code)]
+pub const METRICS_INTERVAL: u64 = 10;
+
+// OpenMetricsのContent-Type
+#[allow(dead_code)]
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// メトリクスの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+}
+
+// メトリクス(同じ名前のサンプルの集まり)
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Metric {
+    pub name: String,
+    pub help: String,
+    pub metric_type: MetricType,
+    pub samples: Vec<(Vec<(String, String)>, f64)>,
+}
+
+#[allow(dead_code)]
+impl Metric {
+
+    // メトリクスの作成(カウンターの場合もnameには_totalを付けない)
+    pub fn new(name: &str, help: &str, metric_type: MetricType) -> Metric {
+
+        Metric {
+            name: name.to_string(),
+            help: help.to_string(),
+            metric_type: metric_type,
+            samples: vec![],
+        }
+
+    }
+
+    // サンプルの追加
+    pub fn sample(&mut self, labels: &[(&str, &str)], value: f64) -> &mut Metric {
+
+        let labels = labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        self.samples.push((labels, value));
+        self
+
+    }
+
+}
+
+// テキスト形式への変換
+// openmetrics=trueはOpenMetrics形式(# EOF付き)、falseはnode_exporter textfile形式(Prometheus text 0.0.4)
+#[allow(dead_code)]
+pub fn render(metrics: &[Metric], openmetrics: bool) -> String {
+
+    let mut text = String::new();
+
+    for metric in metrics {
+
+        // カウンターのサンプル名は_total付き
+        let sample_name = match metric.metric_type {
+            MetricType::Counter => format!("{}_total", metric.name),
+            MetricType::Gauge => metric.name.to_string(),
+        };
+        let type_name = match metric.metric_type {
+            MetricType::Counter if openmetrics == false => sample_name.to_string(),
+            _ => metric.name.to_string(),
+        };
+        let type_text = match metric.metric_type {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        };
+
+        text.push_str(&format!("# HELP {} {}\n", type_name, escape(&metric.help, false)));
+        text.push_str(&format!("# TYPE {} {}\n", type_name, type_text));
+
+        for (labels, value) in &metric.samples {
+            text.push_str(&sample_name);
+            if labels.len() > 0 {
+                let labels: Vec<String> = labels.iter()
+                    .map(|(key, value)| format!("{}=\"{}\"", key, escape(value, true)))
+                    .collect();
+                text.push_str(&format!("{{{}}}", labels.join(",")));
+            };
+            text.push_str(&format!(" {}\n", format_value(*value)));
+        };
+
+    };
+
+    if openmetrics == true {
+        text.push_str("# EOF\n");
+    };
+
+    text
+
+}
+
+// node_exporter textfileの出力(一時ファイルに書き込んでからリネーム)
+#[allow(dead_code)]
+pub fn write_textfile(path: &str, metrics: &[Metric]) -> io::Result<()> {
+
+    let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(render(metrics, false).as_bytes())?;
+    }
+    fs::rename(&tmp_path, path)
+
+}
+
+// ラベル値・HELPのエスケープ
+fn escape(text: &str, quote: bool) -> String {
+
+    let mut escaped = text.replace('\\', "\\\\").replace('\n', "\\n");
+    if quote == true {
+        escaped = escaped.replace('"', "\\\"");
+    };
+    escaped
+
+}
+
+// 値の文字列化
+fn format_value(value: f64) -> String {
+
+    if value.is_nan() {
+        "NaN".to_string()
+    }
+    else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    }
+    else {
+        format!("{}", value)
+    }
+
+}
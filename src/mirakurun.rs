@@ -0,0 +1,155 @@
+use serde::Serialize;
+
+use crate::channels::ChannelEntry;
+
+// Mirakurun互換APIの優先度ヘッダー
+pub const PRIORITY_HEADER: &str = "X-Mirakurun-Priority";
+
+// ストリーム応答で返すチューナーユーザーIDのヘッダー
+pub const USER_ID_HEADER: &str = "X-Mirakurun-Tuner-User-ID";
+
+// BSのオリジナルネットワークID(BS以外はスキャンしないと分からないため0)
+const BS_NETWORK_ID: u32 = 4;
+
+// サービスIDの桁(Mirakurunのid = ネットワークID * 100000 + サービスID)
+const SERVICE_ID_BASE: u64 = 100000;
+
+// チャンネル指定(Mirakurunのchannelオブジェクト)
+#[derive(Debug, Clone, Serialize)]
+#[allow(dead_code)]
+pub struct ChannelRef {
+    #[serde(rename = "type")]
+    pub channel_type: String,
+    pub channel: String,
+}
+
+// サービス情報(Mirakurunのserviceオブジェクト)
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+pub struct ServiceItem {
+    pub id: u64,
+    pub service_id: u32,
+    pub network_id: u32,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub service_type: u8,
+    pub channel: ChannelRef,
+    // 以下はrecpt3独自の項目
+    pub sid: String,
+    pub url: String,
+}
+
+// チャンネルタイプからMirakurunのタイプへの変換(T→GR , CATV→SKY)
+#[allow(dead_code)]
+pub fn mirakurun_type(channel_type: &str) -> &'static str {
+
+    match channel_type {
+        "T" => "GR",
+        "BS" => "BS",
+        "CS" => "CS",
+        "CATV" => "SKY",
+        _ => "",
+    }
+
+}
+
+// Mirakurunのタイプからチャンネルタイプへの変換(GR→T , SKY→CATV)
+#[allow(dead_code)]
+pub fn channel_type_of(mirakurun_type: &str) -> Option<&'static str> {
+
+    match mirakurun_type {
+        "GR" => Some("T"),
+        "BS" => Some("BS"),
+        "CS" => Some("CS"),
+        "SKY" => Some("CATV"),
+        _ => None,
+    }
+
+}
+
+// チャンネル情報のサービス一覧(SIDが数値の場合のみ)
+#[allow(dead_code)]
+pub fn entry_services(entry: &ChannelEntry, base_url: &str) -> Vec<ServiceItem> {
+
+    let service_id = match entry.sid.parse::<u32>() {
+        Ok(service_id) => service_id,
+        Err(_) => return vec![],
+    };
+    let network_id = if entry.channel_type == "BS" { BS_NETWORK_ID } else { 0 };
+    let id = network_id as u64 * SERVICE_ID_BASE + service_id as u64;
+
+    vec![ServiceItem {
+        id: id,
+        service_id: service_id,
+        network_id: network_id,
+        name: entry.name.to_string(),
+        service_type: 1,
+        channel: ChannelRef {
+            channel_type: mirakurun_type(&entry.channel_type).to_string(),
+            channel: entry.channel.to_string(),
+        },
+        sid: entry.sid.to_string(),
+        url: format!("{}/api/services/{}/stream", base_url, id),
+    }]
+
+}
+
+// サービス一覧(チャンネルマップ内のSIDが数値のもの)
+#[allow(dead_code)]
+pub fn service_list(entries: &[ChannelEntry], base_url: &str) -> Vec<ServiceItem> {
+
+    entries.iter().flat_map(|entry| entry_services(entry, base_url)).collect()
+
+}
+
+// サービスの検索(idはMirakurunのidまたはサービスID)
+#[allow(dead_code)]
+pub fn find_service(entries: &[ChannelEntry], id: &str) -> Option<ServiceItem> {
+
+    let id = id.parse::<u64>().ok()?;
+
+    let services = service_list(entries, "");
+    services.iter()
+        .find(|service| service.id == id)
+        .or_else(|| services.iter().find(|service| service.service_id as u64 == id))
+        .cloned()
+
+}
+
+// チャンネルの検索(Mirakurunのタイプとチャンネル指定)
+#[allow(dead_code)]
+pub fn find_channel<'a>(entries: &'a [ChannelEntry], mirakurun_type: &str, channel: &str) -> Option<&'a ChannelEntry> {
+
+    entries.iter().find(|entry| {
+        self::mirakurun_type(&entry.channel_type) == mirakurun_type && entry.channel.eq_ignore_ascii_case(channel)
+    })
+
+}
+
+// /api/servicesのJSON作成
+#[allow(dead_code)]
+pub fn services_json(services: &[ServiceItem]) -> String {
+
+    serde_json::to_string_pretty(services).unwrap_or_default()
+
+}
+
+// /api/services/{id}のJSON作成
+#[allow(dead_code)]
+pub fn service_json(service: &ServiceItem) -> String {
+
+    serde_json::to_string_pretty(service).unwrap_or_default()
+
+}
+
+// 優先度ヘッダーの解析(未指定はNone)
+#[allow(dead_code)]
+pub fn parse_priority(value: Option<&str>) -> Result<Option<i32>, String> {
+
+    match value {
+        None => Ok(None),
+        Some(value) => value.trim().parse::<i32>().map(Some).map_err(|_| format!("{}: {}", PRIORITY_HEADER, value)),
+    }
+
+}
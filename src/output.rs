@@ -0,0 +1,410 @@
+use log::{debug, error, info, warn};
+use nix::fcntl::{fallocate, posix_fadvise, FallocateFlags, PosixFadviseAdvice};
+use nix::sys::statvfs::statvfs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::commands::CommanLineOpt;
+
+// 空き容量の定期チェック間隔(秒)
+#[allow(dead_code)]
+pub const DISK_CHECK_INTERVAL: u64 = 60;
+
+// 録画継続に最低限必要な空き容量(想定ビットレートの秒数)
+#[allow(dead_code)]
+pub const DISK_RESERVE_SECS: u64 = 60;
+
+// チャンネルタイプ毎の想定ビットレート(byte/sec)
+#[allow(dead_code)]
+pub fn expected_bitrate(channel_type: &str) -> u64 {
+
+    match channel_type {
+        // ISDB-S (約24Mbps)
+        "BS" | "CS" => 24_000_000 / 8,
+        // ISDB-T (約17Mbps)
+        "T" | "CATV" => 17_000_000 / 8,
+        // 不明な場合は大きい方に合わせる
+        _ => 24_000_000 / 8,
+    }
+
+}
+
+// ディレクトリの空き容量取得処理(byte)
+#[allow(dead_code)]
+pub fn free_space(dir: &Path) -> Option<u64> {
+
+    match statvfs(dir) {
+        Ok(stat) => Some(stat.blocks_available() as u64 * stat.fragment_size() as u64),
+        Err(e) => {
+            warn!("statvfs failed ({}, {})", dir.display(), e);
+            None
+        },
+    }
+
+}
+
+// 出力ファイルのディレクトリ取得処理
+#[allow(dead_code)]
+fn parent_dir(path: &str) -> &Path {
+
+    match Path::new(path).parent() {
+        Some(dir) if dir.as_os_str().len() > 0 => dir,
+        _ => Path::new("."),
+    }
+
+}
+
+// フェイルオーバー対象のエラー判定(ENOSPC, EIO)
+#[allow(dead_code)]
+fn is_failover_error(e: &io::Error) -> bool {
+
+    matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EIO))
+
+}
+
+// 出力セグメント情報
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct OutputSegment {
+    pub path: String,
+    pub bytes: u64,
+    pub reason: String,
+}
+
+// 録画出力ファイル(容量チェック＆フェイルオーバー、書き込み制御対応)
+#[allow(dead_code)]
+pub struct OutputFile {
+    writer: BufWriter<File>,
+    buffer_size: usize,
+    use_fallback_dir: bool,
+    fallback_dir: String,
+    on_fallback: bool,
+    space_warned: bool,
+    last_check: SystemTime,
+    preallocate: bool,
+    preallocated: u64,
+    sync_interval: u64,
+    synced: u64,
+    dropped: u64,
+    pub segments: Vec<OutputSegment>,
+}
+
+#[allow(dead_code)]
+impl OutputFile {
+
+    // 出力ファイルの作成＆オープン
+    pub fn create(path: &str, command_opt: &CommanLineOpt) -> Result<OutputFile, io::Error> {
+
+        let file = File::create(path)?;
+
+        Ok(OutputFile {
+            writer: BufWriter::with_capacity(command_opt.buffer_size, file),
+            buffer_size: command_opt.buffer_size,
+            use_fallback_dir: command_opt.use_fallback_dir,
+            fallback_dir: command_opt.fallback_dir.to_string(),
+            on_fallback: false,
+            space_warned: false,
+            last_check: SystemTime::now(),
+            preallocate: command_opt.use_preallocate,
+            preallocated: 0,
+            sync_interval: command_opt.sync_interval * 1024 * 1024,
+            synced: 0,
+            dropped: 0,
+            segments: vec![OutputSegment {
+                path: path.to_string(),
+                bytes: 0,
+                reason: "start".to_string(),
+            }],
+        })
+
+    }
+
+    // 出力ファイルの事前領域確保(fallocate)
+    // ファイルサイズは変えずに領域のみ確保(途中で終了してもファイルの末尾に未書き込みの領域が残らない)
+    pub fn preallocate(&mut self, size: u64) {
+
+        if self.preallocate == false || size == 0 {
+            return;
+        };
+
+        // 空き容量(最低容量を除く)に収まらない場合は同時に録画中の他のファイルのために確保しない
+        let dir = parent_dir(self.path()).to_path_buf();
+        if let Some(free) = free_space(&dir) {
+            if size > free.saturating_sub(free / 10) {
+                info!("Preallocation skipped ({}, size={}MB, free={}MB)", self.path(), size / 1024 / 1024, free / 1024 / 1024);
+                return;
+            };
+        };
+
+        let fd = self.writer.get_ref().as_raw_fd();
+        match fallocate(fd, FallocateFlags::FALLOC_FL_KEEP_SIZE, 0, size as libc::off_t) {
+            Ok(_) => {
+                self.preallocated = size;
+                info!("Preallocated {}MB ({})", size / 1024 / 1024, self.path());
+            },
+            Err(e) => {
+                warn!("fallocate failed ({}, {})", self.path(), e);
+            },
+        };
+
+    }
+
+    // 現在の出力ファイル名
+    pub fn path(&self) -> &str {
+
+        &self.segments.last().unwrap().path
+
+    }
+
+    // 全セグメントの出力バイト数
+    pub fn total_bytes(&self) -> u64 {
+
+        self.segments.iter().map(|s| s.bytes).sum()
+
+    }
+
+    // 録画開始前の空き容量チェック
+    // 録画不可能な場合にfalseをリターン
+    pub fn check_space_before_start(&mut self, duration: u64, bitrate: u64) -> bool {
+
+        let required = duration.saturating_mul(bitrate);
+        let reserve = DISK_RESERVE_SECS.saturating_mul(bitrate);
+        let dir = parent_dir(self.path()).to_path_buf();
+
+        // 空き容量が取得できない場合はチェックしない
+        let free = match free_space(&dir) {
+            Some(free) => free,
+            None => return true,
+        };
+        info!("Disk free = {}MB (required about {}MB)", free / 1024 / 1024, required / 1024 / 1024);
+
+        if free >= required {
+            return true;
+        };
+
+        // フォールバック先が無い場合
+        if self.use_fallback_dir == false {
+            if free < reserve {
+                error!("Not enough disk space ({}, free={}MB)", dir.display(), free / 1024 / 1024);
+                return false;
+            };
+            warn!("Recording may not fit in {} (free={}MB, required={}MB)",
+                dir.display(), free / 1024 / 1024, required / 1024 / 1024);
+            return true;
+        };
+
+        // フォールバック先を含めた空き容量チェック
+        let fallback_free = free_space(Path::new(&self.fallback_dir)).unwrap_or(0);
+        if free + fallback_free < required {
+            warn!("Recording may not fit in {} + {} (free={}MB, required={}MB)",
+                dir.display(), self.fallback_dir, (free + fallback_free) / 1024 / 1024, required / 1024 / 1024);
+        }
+        else {
+            warn!("Recording will continue into {} when {} is full", self.fallback_dir, dir.display());
+        };
+
+        // 出力先の空きが無い場合は開始時点で切り替え
+        if free < reserve {
+            if let Err(e) = self.switch_to_fallback("not enough disk space at start") {
+                error!("Fallback output open error ({})", e);
+                return false;
+            };
+        };
+
+        true
+
+    }
+
+    // 録画中の空き容量チェック(DISK_CHECK_INTERVAL毎)
+    pub fn check_space(&mut self, remaining: u64, bitrate: u64) {
+
+        // チェック間隔の判定
+        let elapsed = SystemTime::now().duration_since(self.last_check).unwrap_or_default().as_secs();
+        if elapsed < DISK_CHECK_INTERVAL {
+            return;
+        };
+        self.last_check = SystemTime::now();
+
+        // 事前確保済みで未使用の領域は空き容量として扱う
+        let dir = parent_dir(self.path()).to_path_buf();
+        let free = match free_space(&dir) {
+            Some(free) => free + self.preallocated.saturating_sub(self.segments.last().unwrap().bytes),
+            None => return,
+        };
+        debug!("check_space dir={} , free={} , remaining={}sec", dir.display(), free, remaining);
+
+        // 残り時間分の空きが無い場合に警告(1回のみ)
+        if free < remaining.saturating_mul(bitrate) && self.space_warned == false {
+            warn!("Disk space is running low ({}, free={}MB, remaining={}sec)",
+                dir.display(), free / 1024 / 1024, remaining);
+            self.space_warned = true;
+        };
+
+        // 空きが最低容量を下回った場合はフォールバック先へ切り替え
+        if free < DISK_RESERVE_SECS * bitrate && self.use_fallback_dir && self.on_fallback == false {
+            if let Err(e) = self.switch_to_fallback("disk space low") {
+                error!("Fallback output open error ({})", e);
+            };
+        };
+
+    }
+
+    // データ出力処理(ENOSPC,EIO時はフォールバック先へ切り替えて継続)
+    pub fn write(&mut self, buffer: &[u8]) -> Result<(), io::Error> {
+
+        // バッファへ出力(エラー時はbufferは未出力、未書き込み分は内部バッファに残る)
+        if let Err(e) = self.writer.write_all(buffer) {
+            return self.failover(e, buffer);
+        };
+        self.segments.last_mut().unwrap().bytes += buffer.len() as u64;
+
+        // 書き込み済み領域のライトバック＆ページキャッシュ解放
+        self.writeback();
+
+        Ok(())
+
+    }
+
+    // 書き込みエラー時の切り替え処理
+    fn failover(&mut self, e: io::Error, buffer: &[u8]) -> Result<(), io::Error> {
+
+        if is_failover_error(&e) == false || self.use_fallback_dir == false || self.on_fallback == true {
+            return Err(e);
+        };
+        error!("Write error ({}, {})", self.path(), e);
+
+        // 新しいセグメントへ切り替えて書き込めなかったデータを出力
+        self.switch_to_fallback(&format!("write error: {}", e))?;
+        self.writer.write_all(buffer)?;
+        self.segments.last_mut().unwrap().bytes += buffer.len() as u64;
+
+        Ok(())
+
+    }
+
+    // 定期的なライトバック(sync_file_range)とページキャッシュ解放(posix_fadvise)
+    fn writeback(&mut self) {
+
+        if self.sync_interval == 0 {
+            return;
+        };
+
+        // ファイルへ書き込み済みのオフセット
+        let written = self.segments.last().unwrap().bytes - self.writer.buffer().len() as u64;
+        if written - self.synced < self.sync_interval {
+            return;
+        };
+
+        let fd = self.writer.get_ref().as_raw_fd();
+        unsafe {
+            // 今回の領域は非同期でライトバック開始
+            libc::sync_file_range(fd, self.synced as libc::off64_t, (written - self.synced) as libc::off64_t,
+                libc::SYNC_FILE_RANGE_WRITE);
+
+            // 前回の領域はライトバック完了を待ってからページキャッシュを解放
+            if self.synced > self.dropped {
+                libc::sync_file_range(fd, self.dropped as libc::off64_t, (self.synced - self.dropped) as libc::off64_t,
+                    libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER);
+            };
+        };
+        if self.synced > self.dropped {
+            if let Err(e) = posix_fadvise(fd, self.dropped as libc::off_t, (self.synced - self.dropped) as libc::off_t,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED) {
+                debug!("posix_fadvise failed ({})", e);
+            };
+        };
+        self.dropped = self.synced;
+        self.synced = written;
+
+    }
+
+    // セグメント終了処理(フラッシュ、事前確保領域の切り詰め、ページキャッシュ解放)
+    fn close_segment(&self, file: &File, bytes: u64) {
+
+        // 事前確保した未使用の領域を解放
+        if self.preallocated > bytes {
+            if let Err(e) = file.set_len(bytes) {
+                warn!("truncate failed ({}, {})", self.path(), e);
+            };
+        };
+
+        // ページキャッシュ解放
+        if self.sync_interval > 0 {
+            let _ = file.sync_data();
+            let _ = posix_fadvise(file.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED);
+        };
+
+    }
+
+    // 出力終了処理
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+
+        self.writer.flush()?;
+        self.close_segment(self.writer.get_ref(), self.segments.last().unwrap().bytes);
+
+        Ok(())
+
+    }
+
+    // フォールバック先へ出力を切り替え
+    fn switch_to_fallback(&mut self, reason: &str) -> Result<(), io::Error> {
+
+        // ファイル名はフォールバック先に同名、存在する場合は連番を付与
+        let file_name = Path::new(self.path()).file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(format!("{}.ts", crate::commands::PROGRAM_RECPT));
+        let mut new_path = format!("{}/{}", self.fallback_dir.trim_end_matches('/'), file_name);
+        let mut count = 1;
+        while Path::new(&new_path).exists() {
+            new_path = format!("{}/{}.{}", self.fallback_dir.trim_end_matches('/'), file_name, count);
+            count += 1;
+        };
+
+        let file = File::create(&new_path)?;
+
+        // 旧ファイルの未書き込みデータを取り出して旧セグメントを終了
+        let old = std::mem::replace(&mut self.writer, BufWriter::with_capacity(self.buffer_size, file));
+        let (old_file, pending) = old.into_parts();
+        let pending = pending.unwrap_or_default();
+        let segment = self.segments.last_mut().unwrap();
+        segment.bytes = segment.bytes.saturating_sub(pending.len() as u64);
+        let old_bytes = segment.bytes;
+        self.close_segment(&old_file, old_bytes);
+
+        warn!("Output switched: {} -> {} ({})", self.path(), new_path, reason);
+        self.segments.push(OutputSegment {
+            path: new_path,
+            bytes: 0,
+            reason: reason.to_string(),
+        });
+        self.on_fallback = true;
+        self.preallocated = 0;
+        self.synced = 0;
+        self.dropped = 0;
+
+        // 未書き込みデータを新しいセグメントへ出力
+        self.writer.write_all(&pending)?;
+        self.segments.last_mut().unwrap().bytes += pending.len() as u64;
+
+        Ok(())
+
+    }
+
+    // 出力サマリー表示
+    pub fn summary(&self) {
+
+        if self.segments.len() > 1 {
+            info!("Output segments = {}", self.segments.len());
+            for (count, segment) in self.segments.iter().enumerate() {
+                info!("  [{}] {} ({} bytes, {})", count, segment.path, segment.bytes, segment.reason);
+            };
+        };
+        info!("Output {} bytes", self.total_bytes());
+
+    }
+
+}
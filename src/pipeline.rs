@@ -0,0 +1,326 @@
+use log::{debug, warn};
+
+use crate::ts_splitter_core::{LENGTH_PACKET, MAX_PID, Splitter, get_pid, split_startup, split_select, split_ts,
+    TSS_ERROR, TSS_SUCCESS};
+
+// PCRのクロック周波数(27MHz)と周回値(33bit×300)
+#[allow(dead_code)]
+pub const PCR_CLOCK: f64 = 27_000_000.0;
+#[allow(dead_code)]
+pub const PCR_WRAP: u64 = (1u64 << 33) * 300;
+
+// PCRの不連続と判定する間隔(秒)
+#[allow(dead_code)]
+pub const PCR_MAX_GAP: f64 = 2.0;
+
+// パケットドロップチェック(パケット巡回カウンター)
+#[allow(dead_code)]
+pub struct DropCheck {
+    continuity_counter_flag: [i32; MAX_PID],
+    next_continuity_counter: [i32; MAX_PID],
+    pub drop_count: u64,
+}
+
+#[allow(dead_code)]
+impl DropCheck {
+
+    // 初期設定
+    pub fn new() -> DropCheck {
+
+        DropCheck {
+            continuity_counter_flag: [0; MAX_PID],
+            next_continuity_counter: [0; MAX_PID],
+            drop_count: 0,
+        }
+
+    }
+
+    // 巡回カウンターのリセット(ドロップ数は保持)
+    // チューナー再オープン後など、ストリームが途切れた箇所をドロップとして数えない場合に使用
+    pub fn reset(&mut self) {
+
+        self.continuity_counter_flag = [0; MAX_PID];
+        self.next_continuity_counter = [0; MAX_PID];
+
+    }
+
+    // バッファ内のパケットドロップチェック
+    // ドロップ検出時はon_drop(PID, 巡回カウンター, 期待値)を呼び出し、ドロップ数をリターン
+    pub fn check<F>(&mut self, sp: &Splitter, buffer: &[u8], mut on_drop: F) -> u64
+        where F: FnMut(usize, i32, i32) {
+
+        let mut drops = 0;
+
+        // バッファ終了までループ
+        for packet in buffer.chunks_exact(LENGTH_PACKET) {
+
+            // PID取得
+            let pid = get_pid(packet) as usize;
+
+            // パケット巡回カウンターの作成
+            let continuity_counter = (packet[3] & 0x0f) as i32;
+
+            // パケットドロップチェック
+            if (sp.pmt_pids[pid] > 0 || (sp.pids[pid] > 0 && pid < 0x100)) &&
+                self.continuity_counter_flag[pid] == 1 && continuity_counter != self.next_continuity_counter[pid] {
+
+                on_drop(pid, continuity_counter, self.next_continuity_counter[pid]);
+                drops += 1;
+
+            };
+
+            // 次パケット巡回カウンターの計算
+            self.next_continuity_counter[pid] = (continuity_counter + 1) & 0x0f;
+            self.continuity_counter_flag[pid] = 1;
+
+        };
+
+        self.drop_count += drops;
+
+        // リターン情報
+        drops
+
+    }
+
+}
+
+// TSパイプライン(PID確定＆SID分離)
+// 入力バッファは借用のまま処理し、分離結果は再利用する出力バッファへ格納する
+#[allow(dead_code)]
+pub struct TsPipeline {
+    pub sp: Splitter,
+    pub use_splitter: bool,
+    pub result: i32,
+    split_select_finish: i32,
+    split_buff: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl TsPipeline {
+
+    // 初期設定
+    pub fn new(sid_list: &str, use_splitter: bool) -> TsPipeline {
+
+        TsPipeline {
+            sp: split_startup(sid_list),
+            use_splitter: use_splitter,
+            result: TSS_SUCCESS,
+            split_select_finish: TSS_ERROR,
+            split_buff: Vec::new(),
+        }
+
+    }
+
+    // 対象PIDの確定処理(SID分離しない場合のドロップチェック用)
+    pub fn select(&mut self, buffer: &[u8]) {
+
+        if self.use_splitter == false && self.split_select_finish != TSS_SUCCESS {
+            self.split_select_finish = split_select(&mut self.sp, buffer);
+        };
+
+    }
+
+    // SID分離処理
+    // 分離しない場合は入力バッファをそのままリターン
+    pub fn process<'a>(&'a mut self, buffer: &'a [u8]) -> &'a [u8] {
+
+        if self.use_splitter == false || buffer.len() == 0 {
+            return buffer;
+        };
+
+        // buffer.lenチェック
+        if (buffer.len() % LENGTH_PACKET) != 0 {
+            warn!("buffer.len({})がLENGTH_PACKET({})の倍数ではありません。", buffer.len(), LENGTH_PACKET);
+        };
+
+        // 出力バッファのクリア(領域は再利用)
+        self.split_buff.clear();
+
+        // 処理するsidの取得
+        if self.split_select_finish != TSS_SUCCESS {
+            self.split_select_finish = split_select(&mut self.sp, buffer);
+        };
+
+        // sid取得OK時の処理
+        if self.split_select_finish == TSS_SUCCESS {
+
+            // sid split処理
+            self.result = split_ts(&mut self.sp, buffer, &mut self.split_buff);
+
+            if self.result != TSS_SUCCESS && self.split_buff.len() > 0 {
+                debug!("split_ts failed split_buff.len={}", self.split_buff.len());
+            };
+
+        };
+
+        // リターン情報
+        &self.split_buff
+
+    }
+
+}
+
+// PATの先頭パケット(PID=0かつpayload_unit_start_indicator=1)の位置検索
+// バッファはパケット境界から始まる前提で、見つからない場合はNone
+#[allow(dead_code)]
+pub fn find_pat_start(buffer: &[u8]) -> Option<usize> {
+
+    (0..buffer.len() / LENGTH_PACKET)
+        .map(|index| index * LENGTH_PACKET)
+        .find(|pos| {
+            let packet = &buffer[*pos..*pos + LENGTH_PACKET];
+            packet[0] == 0x47 && get_pid(packet) == 0 && (packet[1] & 0x40) != 0
+        })
+
+}
+
+// バッファ内の最後のPATのバージョン(version_number)
+#[allow(dead_code)]
+pub fn pat_version(buffer: &[u8]) -> Option<u8> {
+
+    buffer.chunks_exact(LENGTH_PACKET)
+        .filter(|packet| packet[0] == 0x47 && get_pid(packet) == 0 && (packet[1] & 0x40) != 0)
+        .filter_map(|packet| {
+            // ペイロードの開始位置(アダプテーションフィールドとpointer_fieldを除く)
+            let mut pos = 4;
+            if (packet[3] & 0x20) != 0 {
+                pos += 1 + packet[4] as usize;
+            };
+            if pos >= LENGTH_PACKET { return None; };
+            pos += 1 + packet[pos] as usize;
+            if pos + 6 > LENGTH_PACKET || packet[pos] != 0x00 { return None; };
+            Some((packet[pos + 5] & 0x3e) >> 1)
+        })
+        .last()
+
+}
+
+// スクランブルが解除されていないパケット数(transport_scrambling_controlが0以外)
+#[allow(dead_code)]
+pub fn scrambled_packets(buffer: &[u8]) -> u64 {
+
+    buffer.chunks_exact(LENGTH_PACKET)
+        .filter(|packet| packet[0] == 0x47 && (packet[3] & 0xc0) != 0)
+        .count() as u64
+
+}
+
+// アダプテーションフィールドの解析
+// (random_access_indicator , PCR)をリターン
+#[allow(dead_code)]
+pub fn adaptation_field(packet: &[u8]) -> (bool, Option<u64>) {
+
+    // adaptation_field_controlが2または3で、長さが1以上の場合のみ
+    if (packet[3] & 0x20) == 0 || packet[4] == 0 {
+        return (false, None);
+    };
+    let length = packet[4] as usize;
+    let flags = packet[5];
+    let random_access = (flags & 0x40) != 0;
+
+    // PCR(33bitのbase×300＋9bitのextension)
+    let pcr = match (flags & 0x10) != 0 && length >= 7 {
+        true => {
+            let base = ((packet[6] as u64) << 25) | ((packet[7] as u64) << 17) | ((packet[8] as u64) << 9)
+                | ((packet[9] as u64) << 1) | ((packet[10] as u64) >> 7);
+            let extension = (((packet[10] as u64) & 0x01) << 8) | packet[11] as u64;
+            Some(base * 300 + extension)
+        },
+        false => None,
+    };
+
+    (random_access, pcr)
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crc::{Crc, CRC_32_ISO_HDLC};
+
+    // 読み込み単位(選択完了前・後の境界を跨ぐよう小さめ)
+    const CHUNK: usize = LENGTH_PACKET * 20;
+
+    // TSパケット作成
+    fn packet(pid: u16, cc: &mut [u8; MAX_PID], pusi: bool, payload: &[u8]) -> Vec<u8> {
+
+        let mut p = vec![0xffu8; LENGTH_PACKET];
+        p[0] = 0x47;
+        p[1] = ((pid >> 8) as u8 & 0x1f) | if pusi { 0x40 } else { 0x00 };
+        p[2] = pid as u8;
+        p[3] = 0x10 | cc[pid as usize];
+        cc[pid as usize] = (cc[pid as usize] + 1) & 0x0f;
+        p[4..4 + payload.len()].copy_from_slice(payload);
+        p
+
+    }
+
+    // PMT(pointer_field付き、映像＋音声)
+    fn pmt(sid: u8, version: u8, pcr: u16, video: u16, audio: u16) -> Vec<u8> {
+
+        vec![
+            0x00, 0x02, 0xb0, 23, 0x00, sid, 0xc1 | (version << 1), 0x00, 0x00,
+            0xe0 | (pcr >> 8) as u8, pcr as u8, 0xf0, 0x00,
+            0x02, 0xe0 | (video >> 8) as u8, video as u8, 0xf0, 0x00,
+            0x0f, 0xe0 | (audio >> 8) as u8, audio as u8, 0xf0, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ]
+
+    }
+
+    // テスト用TS(PAT・PMTの前にES、途中でSID=101のPMTのバージョンと音声PIDが変わる、SID=102はPCRなし)
+    fn make_ts() -> Vec<u8> {
+
+        let mut cc = [0u8; MAX_PID];
+        let mut ts = vec![];
+        let pat = [
+            0x00, 0x00, 0xb0, 21, 0x7f, 0xe0, 0xc1, 0x00, 0x00,
+            0x00, 0x00, 0xe0, 0x10,
+            0x00, 0x65, 0xe1, 0x01,
+            0x00, 0x66, 0xe1, 0x02,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let payload = [0x5au8; LENGTH_PACKET - 4];
+
+        for count in 0..3000 {
+            let changed = count >= 1500;
+            let pids: [u16; 5] = [0x0111, 0x0121, if changed { 0x0113 } else { 0x0112 }, 0x0122, 0x0010];
+            match count % 50 {
+                30 => ts.extend(packet(0x0000, &mut cc, true, &pat)),
+                45 => ts.extend(packet(0x0101, &mut cc, true, &pmt(0x65, changed as u8, 0x0111, 0x0111, pids[2]))),
+                47 => ts.extend(packet(0x0102, &mut cc, true, &pmt(0x66, 0, 0x1fff, 0x0121, 0x0122))),
+                n => ts.extend(packet(pids[n % pids.len()], &mut cc, false, &payload)),
+            };
+        };
+
+        ts
+
+    }
+
+    // SID分離後の出力(長さ , CRC32)
+    fn split_digest(ts: &[u8], sid: &str) -> (usize, u32) {
+
+        let mut pipeline = TsPipeline::new(sid, true);
+        let mut output = vec![];
+        for buffer in ts.chunks(CHUNK) {
+            output.extend_from_slice(pipeline.process(buffer));
+        };
+
+        (output.len(), Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&output))
+
+    }
+
+    // ベースライン(入力バッファのPMTのPIDを書き換えていた実装)と同じ出力になること
+    // 期待値はベースラインのsplit_select・split_tsで同じ入力を分離した結果
+    #[test]
+    fn split_matches_baseline() {
+
+        let ts = make_ts();
+        assert_eq!(split_digest(&ts, "101"), (209432, 388946145));
+        assert_eq!(split_digest(&ts, "102"), (244964, 1969412149));
+        assert_eq!(split_digest(&ts, "101,102"), (443304, 366147629));
+
+    }
+
+}
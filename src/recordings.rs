@@ -0,0 +1,254 @@
+use log::debug;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::http::percent_encode;
+use crate::pipeline::{PCR_CLOCK, PCR_MAX_GAP, PCR_WRAP, adaptation_field};
+use crate::ts_splitter_core::{LENGTH_PACKET, get_pid};
+
+// 一覧に表示する録画ファイルの拡張子
+pub const RECORDING_EXTENSIONS: [&str; 3] = ["ts", "m2ts", "mts"];
+
+// タイムスタンプ(TP_extra_header 4バイト)付きのパケット長(.m2ts , .mts)
+pub const LENGTH_TIMESTAMPED_PACKET: usize = LENGTH_PACKET + 4;
+
+// 最終更新からこの秒数以内のファイルは録画中(追っかけ再生は更新が止まってこの秒数で終了)
+pub const RECORDING_IDLE: u64 = 10;
+
+// 録画ファイル
+#[derive(Serialize)]
+pub struct Recording {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    pub recording: bool,
+    pub url: String,
+    // メタデータ(同名の.jsonファイル)
+    pub metadata: Option<serde_json::Value>,
+}
+
+// 録画ファイルの一覧(新しい順)
+pub fn list_recordings(dir: &str, base_url: &str) -> Vec<Recording> {
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Recordings read error ({}, {})", dir, e);
+            return vec![];
+        },
+    };
+
+    let mut recordings: Vec<Recording> = entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let path = entry.path();
+            let metadata = entry.metadata().ok()?;
+            if name.starts_with('.') || metadata.is_file() == false || is_recording_file(&path) == false {
+                return None;
+            };
+            Some(Recording {
+                url: format!("{}/recordings/{}", base_url, percent_encode(&name)),
+                name: name,
+                size: metadata.len(),
+                modified: metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs()).unwrap_or(0),
+                recording: is_recording(&metadata),
+                metadata: read_metadata(&path),
+            })
+        })
+        .collect();
+    recordings.sort_by(|a, b| b.modified.cmp(&a.modified).then(a.name.cmp(&b.name)));
+
+    recordings
+
+}
+
+// /recordings/のJSON作成
+pub fn recordings_json(dir: &str, base_url: &str) -> String {
+
+    serde_json::to_string_pretty(&list_recordings(dir, base_url)).unwrap_or_default()
+
+}
+
+// 録画ディレクトリ内のファイルのパス(ディレクトリ外・隠しファイル・通常ファイル以外はNone)
+// シンボリックリンクは辿らない
+pub fn recording_path(dir: &str, name: &str) -> Option<PathBuf> {
+
+    if dir == "" || name == "" || name.starts_with('.') || name.contains('/') {
+        return None;
+    };
+
+    let path = Path::new(dir).join(name);
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_file() => Some(path),
+        _ => None,
+    }
+
+}
+
+// 録画ファイルの拡張子判定
+pub fn is_recording_file(path: &Path) -> bool {
+
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| RECORDING_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+
+}
+
+// 録画ファイルのパケット長(.m2ts・.mtsは192バイト、それ以外は188バイト)
+pub fn packet_length(path: &Path) -> usize {
+
+    match path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_ascii_lowercase()) {
+        Some(extension) if extension == "m2ts" || extension == "mts" => LENGTH_TIMESTAMPED_PACKET,
+        _ => LENGTH_PACKET,
+    }
+
+}
+
+// 録画中の判定(最終更新からRECORDING_IDLE秒以内)
+pub fn is_recording(metadata: &fs::Metadata) -> bool {
+
+    metadata.modified().ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|elapsed| elapsed.as_secs() < RECORDING_IDLE)
+        .unwrap_or(false)
+
+}
+
+// メタデータの読み込み(録画ファイル名＋.json または 拡張子を.jsonに置き換えたファイル)
+fn read_metadata(path: &Path) -> Option<serde_json::Value> {
+
+    let mut candidates = vec![PathBuf::from(format!("{}.json", path.display()))];
+    candidates.push(path.with_extension("json"));
+
+    candidates.iter()
+        .filter_map(|candidate| fs::read_to_string(candidate).ok())
+        .find_map(|text| match serde_json::from_str(&text) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                debug!("Recording metadata parse error ({}, {})", path.display(), e);
+                None
+            },
+        })
+
+}
+
+// Rangeヘッダーの解析(単一範囲のみ)
+// 範囲(開始 , 終了 ※終了を含む)をリターン、対象外の書式はNone、範囲外はエラー(416)
+pub fn parse_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if spec.contains(',') == false => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        // 末尾からのバイト数(bytes=-N)
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().map_err(|_| ())?;
+            if suffix == 0 || size == 0 { return Err(()); };
+            (size.saturating_sub(suffix), size - 1)
+        },
+        // 開始位置から末尾まで(bytes=N-)
+        (start, "") => (start.parse::<u64>().map_err(|_| ())?, size.saturating_sub(1)),
+        // 開始位置から終了位置まで(bytes=N-M)
+        (start, end) => {
+            let start = start.parse::<u64>().map_err(|_| ())?;
+            let end = end.parse::<u64>().map_err(|_| ())?;
+            if end < start { return Err(()); };
+            (start, end.min(size.saturating_sub(1)))
+        },
+    };
+    if range.0 >= size {
+        return Err(());
+    };
+
+    Ok(Some(range))
+
+}
+
+// 追っかけ再生で送信できるバイト数
+// 現在のファイルサイズのうち送信位置以降の、書き込みが完了したパケット単位の長さ
+// (事前領域確保はファイルサイズを変えないため、ファイルサイズまでが書き込み済み)
+pub fn written_length(size: u64, position: u64, packet_length: usize) -> u64 {
+
+    let packet_length = packet_length as u64;
+
+    size.saturating_sub(position) / packet_length * packet_length
+
+}
+
+// PCRによる送信ペース調整(実時間の配信を前提とするクライアント向け)
+#[allow(dead_code)]
+pub struct Pacer {
+    // パケット長(188または192バイト)
+    packet_length: usize,
+    // PCRを取得するPID(最初にPCRを検出したPID)
+    pcr_pid: Option<u16>,
+    // 基準のPCRと送信開始時刻
+    base: Option<(u64, Instant)>,
+    last_pcr: u64,
+}
+
+#[allow(dead_code)]
+impl Pacer {
+
+    // 初期設定(パケット長は188または192バイト)
+    pub fn new(packet_length: usize) -> Pacer {
+
+        Pacer {
+            packet_length: packet_length,
+            pcr_pid: None,
+            base: None,
+            last_pcr: 0,
+        }
+
+    }
+
+    // バッファを送信する前に待つ時間(PCRの経過時間が実時間より進んでいる分)
+    // PCRが不連続の場合は基準を取り直す
+    pub fn wait(&mut self, buffer: &[u8]) -> Duration {
+
+        let mut wait = Duration::ZERO;
+
+        for packet in buffer.chunks_exact(self.packet_length) {
+
+            // タイムスタンプ付きのパケットは先頭4バイトを除く
+            let packet = &packet[packet.len() - LENGTH_PACKET..];
+            if packet[0] != 0x47 { continue; };
+            let pcr = match adaptation_field(packet) {
+                (_, Some(pcr)) => pcr,
+                _ => continue,
+            };
+            let pid = get_pid(packet) as u16;
+            if *self.pcr_pid.get_or_insert(pid) != pid { continue; };
+
+            let delta = ((pcr + PCR_WRAP - self.last_pcr) % PCR_WRAP) as f64 / PCR_CLOCK;
+            self.last_pcr = pcr;
+            match self.base {
+                Some((base, started)) if delta <= PCR_MAX_GAP => {
+                    let stream = ((pcr + PCR_WRAP - base) % PCR_WRAP) as f64 / PCR_CLOCK;
+                    let elapsed = started.elapsed().as_secs_f64();
+                    wait = Duration::from_secs_f64((stream - elapsed).max(0.0));
+                },
+                _ => {
+                    self.base = Some((pcr, Instant::now()));
+                    wait = Duration::ZERO;
+                },
+            };
+
+        };
+
+        wait
+
+    }
+
+}
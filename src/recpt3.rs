@@ -7,24 +7,51 @@ use daemonize::Daemonize;
 use env_logger::{Builder, Env, Target};
 use getopts::Options;
 use log::info;
+use nix::unistd::{Gid, Group, User, dup2, initgroups, setgid, setuid};
 use std::env;
-use std::fs::File;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::AsRawFd;
+use std::path::Path;
 use std::io::Write;
 use std::process;
 
 // use crate::commands::{PROGRAM_RECPT, TRUE, FALSE, command_line_check, CommanLineOpt, DecoderOptions};
+use crate::access::{load_auth_file, parse_cidr_list};
 use crate::commands::{PROGRAM_RECPT, TRUE, FALSE, CommanLineOpt, DecoderOptions};
 
+mod access;
+mod api;
 mod arib_b25;
+mod channels;
 mod commands;
 mod decoder;
+mod dlna;
+mod events;
 mod ffi;
+mod hdhomerun;
+mod hls;
+mod http;
 mod http_daemon;
+mod metrics;
+mod mirakurun;
+mod output;
+mod pipeline;
+mod recordings;
+mod ring_queue;
+mod sd_notify;
+mod session;
+mod timeshift;
 mod ts_splitter_core;
+mod tls;
 mod tuner;
 
-use crate::http_daemon::http_daemon;
-use crate::tuner::{recording, show_channels};
+use crate::http_daemon::{IDLE_TIMEOUT, SEND_TIMEOUT, http_daemon};
+use crate::session::{SLOW_CLIENT_TIMEOUT, SUBSCRIBER_QUEUE_CAPACITY, SlowClientPolicy};
+use crate::tls::TlsAcceptor;
+use crate::ts_splitter_core::LENGTH_PACKET;
+use crate::tuner::{CAP, recording, show_channels};
 
 
 pub const VERSION: &str = env!("VERSION_RECPT3");
@@ -32,7 +59,7 @@ pub const VERSION: &str = env!("VERSION_RECPT3");
 // Usage出力
 pub fn show_usage(program: &str, opts: &Options) {
 
-    let brief = format!("Usage: {} [--b25 [--round N] [--strip] [--EMM]] [--http portnumber] [--device devicefile] [--lnb voltage] [--sid SID1,SID2,...] channel rectime outfile", program);
+    let brief = format!("Usage: {} [--b25 [--round N] [--strip] [--EMM]] [--http portnumber] [--device devicefile] [--lnb voltage] [--sid SID1,SID2,...] [--fallback-dir directory] [--preallocate] [--buffer-size bytes] [--sync-interval MB] [--stall-timeout seconds] [--linger seconds] [--max-sessions N] [--client-queue N] [--slow-client drop-oldest|disconnect|skip-to-pat] [--slow-client-timeout seconds] [--idle-timeout seconds] [--send-timeout seconds] [--hls-segment seconds] [--hls-window N] [--hls-dir directory] [--timeshift minutes [--timeshift-dir directory]] [--recordings directory] [--metrics-file path] [--hdhomerun [--hdhomerun-discover]] [--dlna [--dlna-interface address]] [--foreground] [--pidfile path] [--log-file path] [--user name] [--group name] [--bind address] [--allow CIDR,...] [--deny CIDR,...] [--auth-file path] [--tls-cert path --tls-key path] channel rectime outfile", program);
     eprintln!("{}", opts.usage(&brief));
 
 }
@@ -59,6 +86,41 @@ pub(crate) fn command_line_check(program: &str) -> (CommanLineOpt, DecoderOption
     let mut duration: u64 = 0;
     let mut _infile: String = "".to_string();
     let mut outfile: String = "".to_string();
+    let mut use_fallback_dir: bool = false;
+    let mut fallback_dir: String = "".to_string();
+    let mut use_preallocate: bool = false;
+    let mut buffer_size: usize = CAP;
+    let mut sync_interval: u64 = 0;
+    let mut stall_timeout: u64 = 0;
+    let mut linger: u64 = 5;
+    let mut max_sessions: usize = 0;
+    let mut client_queue: usize = SUBSCRIBER_QUEUE_CAPACITY;
+    let mut slow_client: String = "drop-oldest".to_string();
+    let mut slow_client_timeout: u64 = SLOW_CLIENT_TIMEOUT;
+    let mut idle_timeout: u64 = IDLE_TIMEOUT;
+    let mut send_timeout: u64 = SEND_TIMEOUT;
+    let mut hls_segment: u64 = 4;
+    let mut hls_window: usize = 6;
+    let mut hls_dir: String = "".to_string();
+    let mut timeshift: u64 = 0;
+    let mut timeshift_dir: String = "".to_string();
+    let mut recordings_dir: String = "".to_string();
+    let mut metrics_file: String = "".to_string();
+    let mut use_hdhomerun: bool = false;
+    let mut use_hdhomerun_discover: bool = false;
+    let mut use_dlna: bool = false;
+    let mut dlna_interface: String = "".to_string();
+    let mut foreground: bool = false;
+    let mut pidfile: String = "".to_string();
+    let mut log_file: String = "".to_string();
+    let mut user: String = "".to_string();
+    let mut group: String = "".to_string();
+    let mut bind: String = "::".to_string();
+    let mut allow: String = "".to_string();
+    let mut deny: String = "".to_string();
+    let mut auth_file: String = "".to_string();
+    let mut tls_cert: String = "".to_string();
+    let mut tls_key: String = "".to_string();
 
     let mut dopt = DecoderOptions {
         round: 4,
@@ -84,6 +146,40 @@ pub(crate) fn command_line_check(program: &str) -> (CommanLineOpt, DecoderOption
     opts.optflag("o","reverse_device","Reverse Device Order");
     opts.optopt("n","lnb","Specify LNB voltage (0, 11, 15)","voltage");
     opts.optopt("","sid","Specify SID number in CSV format (101,102,...)","SID1,SID2,...");
+    opts.optopt("","fallback-dir","Continue recording into directory when the output disk is full","directory");
+    opts.optflag("","preallocate","Preallocate output file with fallocate");
+    opts.optopt("","buffer-size","Specify output buffer size","bytes");
+    opts.optopt("","sync-interval","Write back and drop page cache every N MB","MB");
+    opts.optopt("","stall-timeout","Reopen the tuner when no data arrives for N seconds","seconds");
+    opts.optopt("","linger","Keep the tuner open for N seconds after the last http client leaves (default 5)","seconds");
+    opts.optopt("","max-sessions","Limit the number of tuner sessions opened by the http daemon (0 = no limit)","N");
+    opts.optopt("","client-queue","Send queue size per http client in chunks (default 256)","N");
+    opts.optopt("","slow-client","What to do when an http client falls behind (default drop-oldest)","drop-oldest|disconnect|skip-to-pat");
+    opts.optopt("","slow-client-timeout","Disconnect a slow http client after N seconds behind (default 10)","seconds");
+    opts.optopt("","idle-timeout","Close http connections that send no request within N seconds (default 10)","seconds");
+    opts.optopt("","send-timeout","Close http connections that accept no data for N seconds (default 30)","seconds");
+    opts.optopt("","hls-segment","Target HLS segment length (default 4)","seconds");
+    opts.optopt("","hls-window","Number of segments in the HLS playlist (default 6)","N");
+    opts.optopt("","hls-dir","Keep HLS segments in directory instead of memory","directory");
+    opts.optopt("","timeshift","Keep a time-shift buffer of each session (default 0=disabled)","minutes");
+    opts.optopt("","timeshift-dir","Keep the time-shift buffer in directory instead of memory","directory");
+    opts.optopt("","recordings","Serve recordings in directory at /recordings/","directory");
+    opts.optopt("","metrics-file","Write recording metrics to a node_exporter textfile (*.prom)","path");
+    opts.optflag("","hdhomerun","Emulate the HDHomeRun HTTP API (with --http)");
+    opts.optflag("","hdhomerun-discover","Also answer HDHomeRun UDP discovery on port 65001 (implies --hdhomerun)");
+    opts.optflag("","dlna","Advertise channels as a UPnP/DLNA media server (SSDP on udp/1900)");
+    opts.optopt("","dlna-interface","Join the SSDP multicast group on the interface with address (implies --dlna)","address");
+    opts.optflag("","foreground","Run the http daemon in the foreground (systemd Type=simple/notify, containers)");
+    opts.optopt("","pidfile","Write the http daemon pid to file","path");
+    opts.optopt("","log-file","Write the http daemon log to file (default /tmp/recpt3.daemon.out, .err)","path");
+    opts.optopt("","user","Run the http daemon as user","name");
+    opts.optopt("","group","Run the http daemon as group","name");
+    opts.optopt("","bind","Bind the http daemon to address (default ::)","address");
+    opts.optopt("","allow","Allow http clients only from CIDR list","CIDR,...");
+    opts.optopt("","deny","Deny http clients from CIDR list","CIDR,...");
+    opts.optopt("","auth-file","Require Basic or Bearer authentication listed in file","path");
+    opts.optopt("","tls-cert","Serve HTTPS with the certificate chain in PEM file (reloaded on SIGHUP)","path");
+    opts.optopt("","tls-key","Private key PEM file for --tls-cert","path");
     opts.optflag("h","help","Show this help");
     opts.optflag("v","version","Show version");
     opts.optflag("l","list","Show channel list");
@@ -166,6 +262,158 @@ pub(crate) fn command_line_check(program: &str) -> (CommanLineOpt, DecoderOption
         sid_list = matches.opt_str("sid").unwrap().to_string();
     };
 
+    // ディスクフル時の出力先ディレクトリを設定
+    if matches.opt_present("fallback-dir") {
+        use_fallback_dir = true;
+        fallback_dir = matches.opt_str("fallback-dir").unwrap().to_string();
+        info!("fallback directory: {}", fallback_dir);
+    };
+
+    // 出力ファイルの事前領域確保の有効設定
+    if matches.opt_present("preallocate") {
+        use_preallocate = true;
+        info!("enable preallocate");
+    };
+
+    // 出力バッファサイズの設定
+    if matches.opt_present("buffer-size") {
+        buffer_size = matches.opt_str("buffer-size").unwrap().parse::<usize>().unwrap_or(CAP);
+        if buffer_size < LENGTH_PACKET { buffer_size = CAP; };
+        info!("buffer size = {}", buffer_size);
+    };
+
+    // ライトバック間隔の設定
+    if matches.opt_present("sync-interval") {
+        sync_interval = matches.opt_str("sync-interval").unwrap().parse::<u64>().unwrap_or(0);
+        info!("sync interval = {}MB", sync_interval);
+    };
+
+    // データ途絶監視時間の設定
+    if matches.opt_present("stall-timeout") {
+        stall_timeout = matches.opt_str("stall-timeout").unwrap().parse::<u64>().unwrap_or(0);
+        info!("stall timeout = {}sec", stall_timeout);
+    };
+
+    // HTTPセッションの待機時間の設定
+    if matches.opt_present("linger") {
+        linger = matches.opt_str("linger").unwrap().parse::<u64>().unwrap_or(5);
+        info!("linger = {}sec", linger);
+    };
+
+    // HTTPセッション数の上限の設定
+    if matches.opt_present("max-sessions") {
+        max_sessions = matches.opt_str("max-sessions").unwrap().parse::<usize>().unwrap_or(0);
+        info!("max sessions = {}", max_sessions);
+    };
+
+    // HTTPクライアント毎の送信キューの容量の設定
+    if matches.opt_present("client-queue") {
+        client_queue = matches.opt_str("client-queue").unwrap().parse::<usize>().unwrap_or(0);
+        if client_queue == 0 {
+            eprintln!("Error: bad client queue size {}", matches.opt_str("client-queue").unwrap());
+            process::exit(-1);
+        };
+        info!("client queue = {}", client_queue);
+    };
+
+    // 遅延したHTTPクライアントの処理方法の設定
+    if matches.opt_present("slow-client") {
+        slow_client = matches.opt_str("slow-client").unwrap().to_string();
+        if SlowClientPolicy::parse(&slow_client, 0).is_none() {
+            eprintln!("Error: bad slow client policy {}", slow_client);
+            process::exit(-1);
+        };
+        info!("slow client = {}", slow_client);
+    };
+    if matches.opt_present("slow-client-timeout") {
+        slow_client_timeout = matches.opt_str("slow-client-timeout").unwrap().parse::<u64>().unwrap_or(SLOW_CLIENT_TIMEOUT);
+        info!("slow client timeout = {}sec", slow_client_timeout);
+    };
+
+    // HTTP接続のタイムアウトの設定(リクエスト受信、送信の待ち合わせ)
+    if matches.opt_present("idle-timeout") {
+        idle_timeout = matches.opt_str("idle-timeout").unwrap().parse::<u64>().unwrap_or(IDLE_TIMEOUT).max(1);
+        info!("idle timeout = {}sec", idle_timeout);
+    };
+    if matches.opt_present("send-timeout") {
+        send_timeout = matches.opt_str("send-timeout").unwrap().parse::<u64>().unwrap_or(SEND_TIMEOUT).max(1);
+        info!("send timeout = {}sec", send_timeout);
+    };
+
+    // HLSのセグメント長・プレイリストのセグメント数・セグメントの保存先の設定
+    if matches.opt_present("hls-segment") {
+        hls_segment = matches.opt_str("hls-segment").unwrap().parse::<u64>().unwrap_or(4).max(1);
+        info!("hls segment = {}sec", hls_segment);
+    };
+    if matches.opt_present("hls-window") {
+        hls_window = matches.opt_str("hls-window").unwrap().parse::<usize>().unwrap_or(6).max(2);
+        info!("hls window = {}", hls_window);
+    };
+    if matches.opt_present("hls-dir") {
+        hls_dir = matches.opt_str("hls-dir").unwrap().to_string();
+        if Path::new(&hls_dir).is_dir() == false {
+            eprintln!("Error: bad hls directory {}", hls_dir);
+            process::exit(-1);
+        };
+        info!("hls dir = {}", hls_dir);
+    };
+
+    // タイムシフトバッファの保持時間・保存先の設定
+    if matches.opt_present("timeshift") {
+        timeshift = matches.opt_str("timeshift").unwrap().parse::<u64>().unwrap_or(0);
+        info!("timeshift = {}min", timeshift);
+    };
+    if matches.opt_present("timeshift-dir") {
+        timeshift_dir = matches.opt_str("timeshift-dir").unwrap().to_string();
+        if Path::new(&timeshift_dir).is_dir() == false {
+            eprintln!("Error: bad timeshift directory {}", timeshift_dir);
+            process::exit(-1);
+        };
+        info!("timeshift dir = {}", timeshift_dir);
+    };
+
+    // HTTPで配信する録画ディレクトリの設定
+    if matches.opt_present("recordings") {
+        recordings_dir = matches.opt_str("recordings").unwrap().to_string();
+        if Path::new(&recordings_dir).is_dir() == false {
+            eprintln!("Error: bad recordings directory {}", recordings_dir);
+            process::exit(-1);
+        };
+        info!("recordings dir = {}", recordings_dir);
+    };
+
+    // メトリクス出力ファイル(node_exporter textfile)の設定
+    if matches.opt_present("metrics-file") {
+        metrics_file = matches.opt_str("metrics-file").unwrap().to_string();
+        info!("metrics file = {}", metrics_file);
+    };
+
+    // HDHomeRunエミュレーションの設定
+    if matches.opt_present("hdhomerun") {
+        use_hdhomerun = true;
+        info!("HDHomeRun emulation = on");
+    };
+    if matches.opt_present("hdhomerun-discover") {
+        use_hdhomerun = true;
+        use_hdhomerun_discover = true;
+        info!("HDHomeRun discover = on");
+    };
+
+    // UPnP/DLNAメディアサーバーの設定
+    if matches.opt_present("dlna") {
+        use_dlna = true;
+        info!("DLNA = on");
+    };
+    if matches.opt_present("dlna-interface") {
+        use_dlna = true;
+        dlna_interface = matches.opt_str("dlna-interface").unwrap().to_string();
+        if dlna_interface.parse::<Ipv4Addr>().is_err() {
+            eprintln!("Error: bad dlna interface address {}", dlna_interface);
+            process::exit(-1);
+        };
+        info!("DLNA interface = {}", dlna_interface);
+    };
+
     // HTTP Broadcastingの有効設定
     if matches.opt_present("http") {
         use_http = true;
@@ -173,6 +421,87 @@ pub(crate) fn command_line_check(program: &str) -> (CommanLineOpt, DecoderOption
         info!("creating a http daemon");
     };
 
+    // http daemonのフォアグラウンド実行の設定
+    if matches.opt_present("foreground") {
+        foreground = true;
+        info!("foreground = on");
+    };
+
+    // http daemonのpidファイルの設定
+    if matches.opt_present("pidfile") {
+        pidfile = matches.opt_str("pidfile").unwrap().to_string();
+        info!("pid file = {}", pidfile);
+    };
+
+    // http daemonのログファイルの設定
+    if matches.opt_present("log-file") {
+        log_file = matches.opt_str("log-file").unwrap().to_string();
+        info!("log file = {}", log_file);
+    };
+
+    // http daemonの実行ユーザー・グループの設定
+    if matches.opt_present("user") {
+        user = matches.opt_str("user").unwrap().to_string();
+        info!("user = {}", user);
+    };
+    if matches.opt_present("group") {
+        group = matches.opt_str("group").unwrap().to_string();
+        info!("group = {}", group);
+    };
+
+    // http daemonの待ち受けアドレスの設定
+    if matches.opt_present("bind") {
+        bind = matches.opt_str("bind").unwrap().to_string();
+        if bind.parse::<IpAddr>().is_err() {
+            eprintln!("Error: bad bind address {}", bind);
+            process::exit(-1);
+        };
+        info!("bind = {}", bind);
+    };
+
+    // http daemonのアクセス許可・拒否アドレスの設定
+    if matches.opt_present("allow") {
+        allow = matches.opt_str("allow").unwrap().to_string();
+        if let Err(message) = parse_cidr_list(&allow) {
+            eprintln!("Error: --allow {}", message);
+            process::exit(-1);
+        };
+        info!("allow = {}", allow);
+    };
+    if matches.opt_present("deny") {
+        deny = matches.opt_str("deny").unwrap().to_string();
+        if let Err(message) = parse_cidr_list(&deny) {
+            eprintln!("Error: --deny {}", message);
+            process::exit(-1);
+        };
+        info!("deny = {}", deny);
+    };
+
+    // http daemonの認証ファイルの設定
+    if matches.opt_present("auth-file") {
+        auth_file = matches.opt_str("auth-file").unwrap().to_string();
+        if let Err(message) = load_auth_file(&auth_file) {
+            eprintln!("Error: --auth-file {}", message);
+            process::exit(-1);
+        };
+        info!("auth file = {}", auth_file);
+    };
+
+    // HTTPSの証明書・秘密鍵の設定(両方の指定が必要)
+    if matches.opt_present("tls-cert") || matches.opt_present("tls-key") {
+        tls_cert = matches.opt_str("tls-cert").unwrap_or("".to_string());
+        tls_key = matches.opt_str("tls-key").unwrap_or("".to_string());
+        if tls_cert == "" || tls_key == "" {
+            eprintln!("Error: --tls-cert and --tls-key must be specified together");
+            process::exit(-1);
+        };
+        if let Err(message) = TlsAcceptor::new(&tls_cert, &tls_key) {
+            eprintln!("Error: TLS {}", message);
+            process::exit(-1);
+        };
+        info!("tls cert = {} , key = {}", tls_cert, tls_key);
+    };
+
     // 処理時のラウンド係数を設定
     if matches.opt_present("round") {
         use_round = true;
@@ -251,6 +580,43 @@ pub(crate) fn command_line_check(program: &str) -> (CommanLineOpt, DecoderOption
             duration: duration,
             infile: _infile.to_string(),
             outfile: outfile.to_string(),
+            use_fallback_dir: use_fallback_dir,
+            fallback_dir: fallback_dir.to_string(),
+            use_preallocate: use_preallocate,
+            buffer_size: buffer_size,
+            sync_interval: sync_interval,
+            stall_timeout: stall_timeout,
+            linger: linger,
+            metrics_file: metrics_file.to_string(),
+            use_hdhomerun: use_hdhomerun,
+            use_hdhomerun_discover: use_hdhomerun_discover,
+            use_dlna: use_dlna,
+            dlna_interface: dlna_interface.to_string(),
+            foreground: foreground,
+            pidfile: pidfile.to_string(),
+            log_file: log_file.to_string(),
+            user: user.to_string(),
+            group: group.to_string(),
+            bind: bind.to_string(),
+            allow: allow.to_string(),
+            deny: deny.to_string(),
+            auth_file: auth_file.to_string(),
+            tls_cert: tls_cert.to_string(),
+            tls_key: tls_key.to_string(),
+            max_sessions: max_sessions,
+            client_queue: client_queue,
+            slow_client: slow_client.to_string(),
+            slow_client_timeout: slow_client_timeout,
+            idle_timeout: idle_timeout,
+            send_timeout: send_timeout,
+            hls_segment: hls_segment,
+            hls_window: hls_window,
+            hls_dir: hls_dir.to_string(),
+            timeshift: timeshift,
+            timeshift_dir: timeshift_dir.to_string(),
+            recordings_dir: recordings_dir.to_string(),
+            offset: 0,
+            resume: 0,
         },
         DecoderOptions {
             round: dopt.round,
@@ -261,6 +627,88 @@ pub(crate) fn command_line_check(program: &str) -> (CommanLineOpt, DecoderOption
     )
 }
 
+// stdout,stderrをログファイル(追記)へ切り替え
+fn redirect_output(path: &str) -> bool {
+
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error, log file {} ({})", path, e);
+            return false;
+        },
+    };
+
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if let Err(e) = dup2(file.as_raw_fd(), fd) {
+            eprintln!("Error, log file {} ({})", path, e);
+            return false;
+        };
+    };
+
+    true
+
+}
+
+// 実行ユーザー・グループの変更(フォアグラウンド実行時、デーモン化した子プロセス)
+// ユーザー指定時は補助グループ(videoなど)も設定する
+fn drop_privileges(user: &str, group: &str) -> bool {
+
+    // ユーザー情報の取得
+    let user_info = if user != "" {
+        match User::from_name(user) {
+            Ok(Some(user_info)) => Some(user_info),
+            _ => {
+                eprintln!("Error, unknown user {}", user);
+                return false;
+            },
+        }
+    }
+    else {
+        None
+    };
+
+    // グループの決定(グループ指定が無い場合はユーザーのプライマリグループ)
+    let gid = if group != "" {
+        match Group::from_name(group) {
+            Ok(Some(group_info)) => Some(group_info.gid),
+            _ => {
+                eprintln!("Error, unknown group {}", group);
+                return false;
+            },
+        }
+    }
+    else {
+        user_info.as_ref().map(|user_info| user_info.gid)
+    };
+
+    // グループの変更
+    if let Some(gid) = gid {
+        if let Some(user_info) = &user_info {
+            let name = CString::new(user_info.name.as_bytes()).unwrap_or_default();
+            if let Err(e) = initgroups(&name, gid) {
+                eprintln!("Error, initgroups {} ({})", user_info.name, e);
+                return false;
+            };
+        };
+        if let Err(e) = setgid(gid) {
+            eprintln!("Error, setgid {} ({})", gid, e);
+            return false;
+        };
+    };
+
+    // ユーザーの変更
+    if let Some(user_info) = &user_info {
+        if let Err(e) = setuid(user_info.uid) {
+            eprintln!("Error, setuid {} ({})", user_info.name, e);
+            return false;
+        };
+        info!("Running as {} (uid={} , gid={})", user_info.name, user_info.uid, Gid::current());
+    };
+
+    true
+
+}
+
 // メイン処理
 fn main() {
 
@@ -299,20 +747,64 @@ fn main() {
     // http daemon処理
     if opt._use_http {
 
-        // Daemon起動時のstdout,stderrファイルを設定
-        let stdout = File::create(format!("/tmp/{}.daemon.out", program)).unwrap();
-        let stderr = File::create(format!("/tmp/{}.daemon.err", program)).unwrap();
+        // フォアグラウンド実行(systemd Type=simple/notify,コンテナ向け)
+        if opt.foreground == true {
+
+            // ログ出力先の変更
+            if opt.log_file != "" && redirect_output(&opt.log_file) == false {
+                process::exit(-1);
+            };
+
+            // pidファイルの作成
+            if opt.pidfile != "" {
+                if let Err(e) = std::fs::write(&opt.pidfile, format!("{}\n", process::id())) {
+                    eprintln!("Error, pid file {} ({})", opt.pidfile, e);
+                    process::exit(-1);
+                };
+            };
+
+            // 実行ユーザー・グループの変更
+            if drop_privileges(&opt.user, &opt.group) == false {
+                process::exit(-1);
+            };
+
+            http_daemon(opt.clone(), dopt.clone());
+            return;
+
+        };
+
+        // Daemon起動時のstdout,stderrファイルを設定(ログファイル指定時は両方をログファイルへ出力)
+        let (stdout, stderr) = if opt.log_file != "" {
+            let stdout = match OpenOptions::new().create(true).append(true).open(&opt.log_file) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Error, log file {} ({})", opt.log_file, e);
+                    process::exit(-1);
+                },
+            };
+            let stderr = stdout.try_clone().unwrap();
+            (stdout, stderr)
+        }
+        else {
+            (File::create(format!("/tmp/{}.daemon.out", program)).unwrap(), File::create(format!("/tmp/{}.daemon.err", program)).unwrap())
+        };
 
         // daemonize情報作成
-        let daemonize = Daemonize::new()
+        let mut daemonize = Daemonize::new()
             .stdout(stdout)
-            .stderr(stderr)
-            .privileged_action(|| "Executed before drop privileges");
+            .stderr(stderr);
+        if opt.pidfile != "" {
+            daemonize = daemonize.pid_file(&opt.pidfile);
+        };
+        let daemonize = daemonize.privileged_action(|| "Executed before drop privileges");
 
         // daemonize
         match daemonize.start() {
-        // daemonize開始
+        // daemonize開始(pidファイル作成後に実行ユーザー・グループを変更)
             Ok(_v) => {
+                if drop_privileges(&opt.user, &opt.group) == false {
+                    process::exit(-1);
+                };
                 http_daemon(opt.clone(), dopt.clone());
             },
             // daemonizeエラー処理
@@ -0,0 +1,245 @@
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// キューの状態
+struct RingState<T> {
+    items: VecDeque<T>,
+    closed: bool,
+    high_water: usize,
+    overflows: u64,
+}
+
+// スレッド間の有界リングバッファ
+// 満杯時のpushは空きができるまで待ち合わせ、待ち合わせた回数をオーバーフローとして記録する
+#[allow(dead_code)]
+pub struct RingQueue<T> {
+    name: String,
+    capacity: usize,
+    state: Mutex<RingState<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+#[allow(dead_code)]
+impl<T> RingQueue<T> {
+
+    // キューの作成
+    pub fn new(name: &str, capacity: usize) -> RingQueue<T> {
+
+        RingQueue {
+            name: name.to_string(),
+            capacity: capacity,
+            state: Mutex::new(RingState {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+                high_water: 0,
+                overflows: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+
+    }
+
+    // キューへ追加(満杯時は待ち合わせ)
+    // キューがクローズされている場合はfalseをリターン
+    pub fn push(&self, item: T) -> bool {
+
+        let mut state = self.state.lock().unwrap();
+
+        // 満杯時はオーバーフローを記録して空き待ち
+        if state.items.len() >= self.capacity && state.closed == false {
+            state.overflows += 1;
+            warn!("{} queue overflow (capacity={}, overflows={})", self.name, self.capacity, state.overflows);
+            while state.items.len() >= self.capacity && state.closed == false {
+                state = self.not_full.wait(state).unwrap();
+            };
+        };
+
+        if state.closed == true {
+            return false;
+        };
+
+        state.items.push_back(item);
+        if state.items.len() > state.high_water {
+            state.high_water = state.items.len();
+        };
+        self.not_empty.notify_one();
+
+        true
+
+    }
+
+    // キューへ追加(満杯時は待ち合わせずにfalseをリターン)
+    // 満杯で追加できなかった場合はオーバーフローとして記録する
+    pub fn try_push(&self, item: T) -> bool {
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed == true {
+            return false;
+        };
+        if state.items.len() >= self.capacity {
+            state.overflows += 1;
+            return false;
+        };
+
+        state.items.push_back(item);
+        if state.items.len() > state.high_water {
+            state.high_water = state.items.len();
+        };
+        self.not_empty.notify_one();
+
+        true
+
+    }
+
+    // キューへ追加(満杯時は最も古いデータを破棄して追加)
+    // 破棄したデータ数をリターン(クローズ済みの場合は追加せずに0)
+    pub fn push_drop_oldest(&self, item: T) -> usize {
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed == true {
+            return 0;
+        };
+
+        let mut dropped = 0;
+        while state.items.len() >= self.capacity && state.items.pop_front().is_some() {
+            dropped += 1;
+        };
+        if dropped > 0 {
+            state.overflows += 1;
+        };
+
+        state.items.push_back(item);
+        if state.items.len() > state.high_water {
+            state.high_water = state.items.len();
+        };
+        self.not_empty.notify_one();
+
+        dropped
+
+    }
+
+    // キューから取り出し(空の場合は待ち合わせ)
+    // クローズ済みで空の場合はNoneをリターン
+    pub fn pop(&self) -> Option<T> {
+
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            };
+            if state.closed == true {
+                return None;
+            };
+            state = self.not_empty.wait(state).unwrap();
+        }
+
+    }
+
+    // キューから取り出し(タイムアウト付き)
+    // タイムアウト時、クローズ済みで空の場合はNoneをリターン
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.items.len() == 0 && state.closed == false {
+            state = self.not_empty.wait_timeout(state, timeout).unwrap().0;
+        };
+
+        let item = state.items.pop_front();
+        if item.is_some() {
+            self.not_full.notify_one();
+        };
+
+        item
+
+    }
+
+    // キューから取り出し(空の場合は待ち合わせずにNone)
+    pub fn try_pop(&self) -> Option<T> {
+
+        let mut state = self.state.lock().unwrap();
+
+        let item = state.items.pop_front();
+        if item.is_some() {
+            self.not_full.notify_one();
+        };
+
+        item
+
+    }
+
+    // キューのクローズ(残りのデータは取り出し可能)
+    pub fn close(&self) {
+
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+
+    }
+
+    // キュー内のデータを全て破棄
+    // 破棄したデータ数をリターン
+    pub fn clear(&self) -> usize {
+
+        let mut state = self.state.lock().unwrap();
+        let cleared = state.items.len();
+        state.items.clear();
+        self.not_full.notify_all();
+
+        cleared
+
+    }
+
+    // クローズ済み判定
+    pub fn is_closed(&self) -> bool {
+
+        self.state.lock().unwrap().closed
+
+    }
+
+    // 現在のデータ数
+    pub fn len(&self) -> usize {
+
+        self.state.lock().unwrap().items.len()
+
+    }
+
+    // キューの容量
+    pub fn capacity(&self) -> usize {
+
+        self.capacity
+
+    }
+
+    // 最大データ数(ハイウォーターマーク)
+    pub fn high_water(&self) -> usize {
+
+        self.state.lock().unwrap().high_water
+
+    }
+
+    // オーバーフロー回数
+    pub fn overflows(&self) -> u64 {
+
+        self.state.lock().unwrap().overflows
+
+    }
+
+    // キュー統計のログ出力
+    pub fn report(&self) {
+
+        let state = self.state.lock().unwrap();
+        info!("{} queue high water = {}/{} , overflows = {}", self.name, state.high_water, self.capacity, state.overflows);
+
+    }
+
+}
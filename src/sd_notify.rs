@@ -0,0 +1,157 @@
+use log::{debug, warn};
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+
+// systemdへの状態通知($NOTIFY_SOCKETが無い場合は何もしない)
+#[allow(dead_code)]
+pub fn notify(state: &str) -> bool {
+
+    match env::var("NOTIFY_SOCKET") {
+        Ok(path) if path != "" => notify_socket(&path, state),
+        _ => false,
+    }
+
+}
+
+// 指定ソケットへの状態通知
+// ソケット名が@で始まる場合は抽象名前空間のソケット
+#[allow(dead_code)]
+pub fn notify_socket(path: &str, state: &str) -> bool {
+
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path),
+    };
+    let addr = match addr {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("NOTIFY_SOCKET address error ({}, {})", path, e);
+            return false;
+        },
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(state.as_bytes(), &addr));
+    match result {
+        Ok(_) => {
+            debug!("sd_notify {}", state.replace('\n', " "));
+            true
+        },
+        Err(e) => {
+            warn!("sd_notify error ({}, {})", path, e);
+            false
+        },
+    }
+
+}
+
+// ウォッチドッグの通知間隔(WATCHDOG_USECの半分、対象外のプロセスの場合はNone)
+#[allow(dead_code)]
+pub fn watchdog_interval() -> Option<Duration> {
+
+    watchdog_interval_of(env::var("WATCHDOG_USEC").ok().as_deref(), env::var("WATCHDOG_PID").ok().as_deref(), process::id())
+
+}
+
+// WATCHDOG_USEC・WATCHDOG_PIDの値からウォッチドッグの通知間隔を算出
+#[allow(dead_code)]
+pub fn watchdog_interval_of(usec: Option<&str>, pid: Option<&str>, current_pid: u32) -> Option<Duration> {
+
+    let usec = usec?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    };
+
+    // WATCHDOG_PIDが指定されている場合は自プロセスのみ
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != current_pid {
+            return None;
+        };
+    };
+
+    Some(Duration::from_micros(usec / 2))
+
+}
+
+// 起動完了の通知
+#[allow(dead_code)]
+pub fn notify_ready(status: &str) -> bool {
+
+    notify(&ready_state(status))
+
+}
+
+// 起動完了の通知内容
+fn ready_state(status: &str) -> String {
+
+    format!("READY=1\nSTATUS={}\nMAINPID={}", status, process::id())
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+
+    // パス名のソケットへの通知
+    #[test]
+    fn notify_pathname_socket() {
+
+        let path = env::temp_dir().join(format!("recpt3-notify-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let path_name = path.to_string_lossy().to_string();
+
+        assert!(notify_socket(&path_name, &ready_state("test")));
+        let mut buffer = [0u8; 256];
+        let length = socket.recv(&mut buffer).unwrap();
+        let message = String::from_utf8_lossy(&buffer[..length]).to_string();
+        assert!(message.lines().any(|line| line == "READY=1"));
+        assert!(message.lines().any(|line| line == "STATUS=test"));
+        assert!(message.lines().any(|line| line == format!("MAINPID={}", process::id())));
+
+        assert!(notify_socket(&path_name, "WATCHDOG=1"));
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"WATCHDOG=1");
+
+        let _ = fs::remove_file(&path);
+
+        // 受信側が無い場合は失敗
+        assert!(notify_socket(&path_name, "READY=1") == false);
+
+    }
+
+    // 抽象名前空間のソケットへの通知
+    #[test]
+    fn notify_abstract_socket() {
+
+        let name = format!("recpt3-notify-{}", process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        assert!(notify_socket(&format!("@{}", name), "STOPPING=1"));
+        let mut buffer = [0u8; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"STOPPING=1");
+
+    }
+
+    // ウォッチドッグの通知間隔
+    #[test]
+    fn watchdog_interval_values() {
+
+        assert_eq!(watchdog_interval_of(Some("10000000"), None, 100), Some(Duration::from_secs(5)));
+        assert_eq!(watchdog_interval_of(Some("10000000"), Some("100"), 100), Some(Duration::from_secs(5)));
+        assert_eq!(watchdog_interval_of(Some("10000000"), Some("101"), 100), None);
+        assert_eq!(watchdog_interval_of(Some("10000000"), Some("x"), 100), None);
+        assert_eq!(watchdog_interval_of(Some("0"), None, 100), None);
+        assert_eq!(watchdog_interval_of(Some("abc"), None, 100), None);
+        assert_eq!(watchdog_interval_of(None, Some("100"), 100), None);
+
+    }
+
+}
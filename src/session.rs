@@ -0,0 +1,1186 @@
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd};
+use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::AtomicU32, atomic::AtomicU64, atomic::Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
+
+use crate::arib_b25::ARIB_STD_B25_BUFFER;
+use crate::commands::{CommanLineOpt, DecoderOptions};
+use crate::decoder::{B25Context, b25_startup, b25_shutdown};
+use crate::events::{Event, EventBus};
+use crate::pipeline::{DropCheck, TsPipeline, find_pat_start, pat_version, scrambled_packets};
+use crate::ring_queue::RingQueue;
+use crate::timeshift::TimeShiftBuffer;
+use crate::tuner::{CAP, channel_type, ptx_disable_lnb, signal_get, start_rec, stop_rec, try_tune, tuner_device, tuner_list};
+
+// 購読者毎の送信キューの容量の既定値(チャンク数)
+pub const SUBSCRIBER_QUEUE_CAPACITY: usize = 256;
+
+// 遅延クライアントを切断するまでの秒数の既定値
+pub const SLOW_CLIENT_TIMEOUT: u64 = 10;
+
+// C/Nの取得・監視イベントの集計間隔(秒)
+const SIGNAL_INTERVAL: u64 = 1;
+
+// デバイスのデータ到着待ちのタイムアウト(ミリ秒)
+const POLL_TIMEOUT: u16 = 1000;
+
+// セッション開始エラー
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum SessionError {
+    BadChannel,
+    NoDevice,
+    TuneFailed,
+    B25Failed,
+    SessionLimit,
+}
+
+// 購読要求元のクライアント情報
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    // 優先度(X-Mirakurun-Priority、未指定は0)
+    pub priority: i32,
+    // User-Agent
+    pub agent: String,
+}
+
+// 送信キューが満杯になった(クライアントの受信が遅れた)場合の処理方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum SlowClientPolicy {
+    // 最も古いチャンクを破棄して最新のデータを追加
+    DropOldest,
+    // 満杯の状態が指定時間続いたら切断
+    Disconnect(Duration),
+    // キューを破棄して次のPATから送信を再開
+    SkipToPat,
+}
+
+#[allow(dead_code)]
+impl SlowClientPolicy {
+
+    // 処理方法の解析(drop-oldest|disconnect|skip-to-pat)
+    pub fn parse(name: &str, timeout: u64) -> Option<SlowClientPolicy> {
+
+        match name {
+            "drop-oldest" => Some(SlowClientPolicy::DropOldest),
+            "disconnect" => Some(SlowClientPolicy::Disconnect(Duration::from_secs(timeout))),
+            "skip-to-pat" => Some(SlowClientPolicy::SkipToPat),
+            _ => None,
+        }
+
+    }
+
+    // 処理方法の名前
+    pub fn name(&self) -> &'static str {
+
+        match self {
+            SlowClientPolicy::DropOldest => "drop-oldest",
+            SlowClientPolicy::Disconnect(_) => "disconnect",
+            SlowClientPolicy::SkipToPat => "skip-to-pat",
+        }
+
+    }
+
+}
+
+// 配信データ(送信遅延の計測用に配信時刻を保持)
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct Chunk {
+    pub data: Arc<Vec<u8>>,
+    pub published: Instant,
+    // タイムシフトバッファのシーケンス番号(バッファ無しは0)
+    pub sequence: u64,
+}
+
+// 購読者(HTTPクライアント)
+#[allow(dead_code)]
+pub struct Subscriber {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub priority: i32,
+    pub agent: String,
+    pub sid: String,
+    pub started: SystemTime,
+    pub sent: AtomicU64,
+    pub skipped: AtomicU64,
+    pub policy: SlowClientPolicy,
+    // 直近・最大の送信遅延(配信から送信完了までのミリ秒)
+    pub lag_ms: AtomicU64,
+    pub max_lag_ms: AtomicU64,
+    // PATからの再開回数(skip-to-pat)
+    pub resyncs: AtomicU64,
+    // 遅延による切断(disconnect)
+    pub disconnected: AtomicBool,
+    pub queue: RingQueue<Chunk>,
+    // 送信キューへの追加・クローズの通知(非同期の送信タスク用)
+    pub ready: Notify,
+    // 送信済みのタイムシフトバッファの位置(次のシーケンス番号)
+    pub position: AtomicU64,
+    // タイムシフトバッファからの再生位置(ライブ配信中はNone)
+    cursor: Mutex<Option<u64>>,
+    // 送信キューが満杯になった時刻(disconnect)
+    behind_since: Mutex<Option<Instant>>,
+    // 次のPAT待ち(skip-to-pat)
+    resyncing: AtomicBool,
+}
+
+#[allow(dead_code)]
+impl Subscriber {
+
+    // 送信完了したチャンクの送信遅延・タイムシフトバッファの位置を記録
+    pub fn record_lag(&self, chunk: &Chunk) {
+
+        let lag = chunk.published.elapsed().as_millis() as u64;
+        self.lag_ms.store(lag, Ordering::Relaxed);
+        self.max_lag_ms.fetch_max(lag, Ordering::Relaxed);
+        if chunk.sequence > 0 {
+            self.position.store(chunk.sequence + 1, Ordering::Relaxed);
+        };
+
+    }
+
+    // タイムシフト再生中の判定
+    pub fn is_shifted(&self) -> bool {
+
+        self.cursor.lock().unwrap().is_some()
+
+    }
+
+    // 送信キューのクローズ＆送信タスクへ通知
+    pub fn close(&self) {
+
+        self.queue.close();
+        self.ready.notify_one();
+
+    }
+
+    // 遅延時の処理方法に従って送信キューへ追加
+    fn deliver(&self, chunk: &Chunk) {
+
+        self.push(chunk);
+        self.ready.notify_one();
+
+    }
+
+    // 送信キューへの追加
+    fn push(&self, chunk: &Chunk) {
+
+        match self.policy {
+            SlowClientPolicy::DropOldest => {
+                let dropped = self.queue.push_drop_oldest(chunk.clone());
+                if dropped > 0 {
+                    self.count_skipped(dropped as u64, "oldest chunk dropped");
+                };
+            },
+            SlowClientPolicy::Disconnect(timeout) => {
+                let mut behind_since = self.behind_since.lock().unwrap();
+                if self.queue.try_push(chunk.clone()) {
+                    *behind_since = None;
+                    return;
+                };
+                self.count_skipped(1, "chunk skipped");
+
+                // 満杯の状態がtimeout続いたら切断
+                let since = *behind_since.get_or_insert(Instant::now());
+                if since.elapsed() >= timeout {
+                    warn!("Subscriber {} ({}) is {}sec behind, disconnected", self.id, self.addr, timeout.as_secs());
+                    self.disconnected.store(true, Ordering::Release);
+                    self.queue.close();
+                };
+            },
+            SlowClientPolicy::SkipToPat => {
+                // 満杯時はキューを破棄してPAT待ち
+                if self.resyncing.load(Ordering::Relaxed) == false {
+                    if self.queue.try_push(chunk.clone()) {
+                        return;
+                    };
+                    let cleared = self.queue.clear();
+                    self.resyncing.store(true, Ordering::Relaxed);
+                    self.resyncs.fetch_add(1, Ordering::Relaxed);
+                    self.count_skipped(cleared as u64, "queue cleared, waiting for PAT");
+                };
+
+                // PATのパケットから送信を再開
+                match find_pat_start(&chunk.data) {
+                    Some(pos) => {
+                        self.resyncing.store(false, Ordering::Relaxed);
+                        let data = if pos == 0 { Arc::clone(&chunk.data) } else { Arc::new(chunk.data[pos..].to_vec()) };
+                        self.queue.try_push(Chunk { data: data, published: chunk.published, sequence: chunk.sequence });
+                    },
+                    None => {
+                        self.skipped.fetch_add(1, Ordering::Relaxed);
+                    },
+                };
+            },
+        };
+
+    }
+
+    // タイムシフトバッファの有るセッションでの配信
+    // 送信キューが満杯(一時停止・回線の瞬断)の場合は処理方法に関わらずバッファからの再生へ切り替える
+    // バッファからの読み込みは送信タスク側(next_chunk)で行い、チューナーの読み込みスレッドでは行わない
+    fn deliver_shifted(&self, chunk: &Chunk) {
+
+        let mut cursor = self.cursor.lock().unwrap();
+        if cursor.is_none() && self.queue.try_push(chunk.clone()) == false {
+            info!("Subscriber {} ({}) fell behind, playing from time-shift buffer", self.id, self.addr);
+            *cursor = Some(chunk.sequence);
+        };
+        self.ready.notify_one();
+
+    }
+
+    // 送信するチャンクの取り出し(取り出せるチャンクが無い場合はNone)
+    // タイムシフト再生中は送信キューの残りを送信後、バッファから再生位置のチャンクを読み込み、
+    // 最新のデータに追いついたらライブ配信(送信キュー)へ戻る
+    pub fn next_chunk(&self, session: &TunerSession) -> Option<Chunk> {
+
+        if let Some(chunk) = self.queue.try_pop() {
+            return Some(chunk);
+        };
+        let buffer = match &session.timeshift {
+            Some(buffer) if self.queue.is_closed() == false => buffer,
+            _ => return None,
+        };
+        let mut next = (*self.cursor.lock().unwrap())?;
+
+        loop {
+
+            // 最新のデータまで送信済みの場合はライブ配信へ(以降のチャンクは送信キューへ追加される)
+            let (sequence, data) = match buffer.read(next) {
+                Some(read) => read,
+                None => {
+                    let mut cursor = self.cursor.lock().unwrap();
+                    if buffer.next_sequence() > next {
+                        continue;
+                    };
+                    info!("Subscriber {} ({}) caught up with live", self.id, self.addr);
+                    *cursor = None;
+                    return None;
+                },
+            };
+            *self.cursor.lock().unwrap() = Some(sequence + 1);
+
+            // 保持時間を過ぎて破棄された分・読み込めなかったチャンクは読み飛ばして次のPATから再開
+            if sequence > next || data.len() == 0 {
+                let skipped = (sequence - next).max(1);
+                self.count_skipped(skipped, "time-shift position expired");
+                self.resyncs.fetch_add(1, Ordering::Relaxed);
+                self.resyncing.store(true, Ordering::Relaxed);
+            };
+            next = sequence + 1;
+            let data = match self.resyncing.load(Ordering::Relaxed) {
+                true => match find_pat_start(&data) {
+                    Some(pos) => {
+                        self.resyncing.store(false, Ordering::Relaxed);
+                        if pos == 0 { data } else { Arc::new(data[pos..].to_vec()) }
+                    },
+                    None => continue,
+                },
+                false => data,
+            };
+
+            return Some(Chunk { data: data, published: Instant::now(), sequence: sequence });
+
+        }
+
+    }
+
+    // タイムシフト再生の開始位置の設定(?offset=秒 または ?resume=ID)
+    // 開始位置は次のPATに合わせる
+    fn seek(&self, buffer: &TimeShiftBuffer, offset: i64, resume: u64) {
+
+        let start = if resume > 0 {
+            let start = buffer.take_resume(resume);
+            if start.is_none() {
+                warn!("Unknown time-shift id {}, starting live ({})", resume, self.addr);
+            };
+            start
+        }
+        else if offset < 0 {
+            buffer.seek(offset as f64)
+        }
+        else {
+            None
+        };
+
+        if let Some(start) = start {
+            info!("Subscriber {} ({}) starts {:.1}sec behind live", self.id, self.addr, buffer.delay(start));
+            self.resyncing.store(true, Ordering::Relaxed);
+            *self.cursor.lock().unwrap() = Some(start);
+        };
+
+    }
+
+    // スキップしたチャンク数の記録(初回と1000チャンク毎にログ出力)
+    fn count_skipped(&self, count: u64, reason: &str) {
+
+        let before = self.skipped.fetch_add(count, Ordering::Relaxed);
+        let skipped = before + count;
+        if before == 0 || before / 1000 != skipped / 1000 {
+            warn!("Subscriber {} ({}) is too slow, {} (total={})", self.id, self.addr, reason, skipped);
+        };
+
+    }
+
+}
+
+// セッションが使用中のチューナー・デコーダー
+// どの経路で終了しても(切断、エラー、パニック、デーモン終了)破棄時に
+// 録画終了コマンド出力・LNB停止・B25解放・デバイス解放を行う
+struct TunerGuard {
+    device: String,
+    file: File,
+    // 録画開始コマンド出力済み
+    recording: bool,
+    // LNB電源を有効化済み
+    lnb: bool,
+    b25: Option<B25Context>,
+}
+
+impl Drop for TunerGuard {
+
+    fn drop(&mut self) {
+
+        let fd = self.file.as_raw_fd();
+
+        // 録画終了コマンド出力
+        if self.recording == true {
+            if let Err(e) = unsafe { stop_rec(fd) } {
+                warn!("stop_rec error ({}, {})", self.device, e);
+            };
+        };
+
+        // LNB電源停止
+        if self.lnb == true {
+            match unsafe { ptx_disable_lnb(fd) } {
+                Ok(_) => debug!("LNB off ({})", self.device),
+                Err(e) => warn!("Power off LNB failed ({}, {})", self.device, e),
+            };
+        };
+
+        // B-CASリーダーシャットダウン
+        if let Some(b25) = self.b25.take() {
+            unsafe { b25_shutdown(b25.dec, b25.bcas) };
+            info!("B25 shutdown");
+        };
+
+        debug!("Tuner released ({})", self.device);
+
+    }
+
+}
+
+// チューナーセッション
+// 1つのチューナー(選局＋B25デコード)を複数の購読者で共有する
+#[allow(dead_code)]
+pub struct TunerSession {
+    pub id: u64,
+    pub key: String,
+    pub channel: String,
+    pub channel_type: String,
+    pub device: String,
+    pub started: SystemTime,
+    pub bytes: AtomicU64,
+    pub drops: AtomicU64,
+    signal: AtomicU32,
+    // タイムシフトバッファ(--timeshift指定時)
+    pub timeshift: Option<TimeShiftBuffer>,
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+    stop: AtomicBool,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[allow(dead_code)]
+impl TunerSession {
+
+    // 購読者数
+    pub fn subscriber_count(&self) -> usize {
+
+        self.subscribers.lock().unwrap().len()
+
+    }
+
+    // 購読者一覧
+    pub fn subscribers(&self) -> Vec<Arc<Subscriber>> {
+
+        self.subscribers.lock().unwrap().clone()
+
+    }
+
+    // 直近のC/N(dB)
+    pub fn signal(&self) -> f32 {
+
+        f32::from_bits(self.signal.load(Ordering::Relaxed))
+
+    }
+
+    // セッションの優先度(購読者の優先度の最大値、購読者なしはi32::MIN)
+    pub fn priority(&self) -> i32 {
+
+        self.subscribers.lock().unwrap().iter().map(|subscriber| subscriber.priority).max().unwrap_or(i32::MIN)
+
+    }
+
+    // 停止判定
+    pub fn is_stopped(&self) -> bool {
+
+        self.stop.load(Ordering::Acquire)
+
+    }
+
+    // 全購読者へデコード済みデータを配信
+    // 送信キューが満杯の購読者は購読者毎の処理方法(SlowClientPolicy)に従う
+    // タイムシフトバッファが有る場合はバッファへ追加し、満杯の購読者は送信タスク側でバッファから再生する
+    fn publish(&self, data: Arc<Vec<u8>>) {
+
+        let sequence = match &self.timeshift {
+            Some(buffer) => buffer.append(&data),
+            None => 0,
+        };
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        // 切断済みの購読者を除外
+        subscribers.retain(|subscriber| subscriber.queue.is_closed() == false);
+
+        let chunk = Chunk { data: data, published: Instant::now(), sequence: sequence };
+        for subscriber in subscribers.iter() {
+            match &self.timeshift {
+                Some(_) => subscriber.deliver_shifted(&chunk),
+                None => subscriber.deliver(&chunk),
+            };
+        };
+
+    }
+
+}
+
+// セッションブローカー
+// 同じチャンネル・デコード設定の要求は既存のセッションへ参加させ、
+// 最後の購読者が切断してからlinger秒経過後にチューナーを解放する
+#[allow(dead_code)]
+pub struct SessionBroker {
+    pub started: SystemTime,
+    pub b25_failures: AtomicU64,
+    pub preemptions: AtomicU64,
+    pub max_sessions: usize,
+    // 監視イベント(/api/events)
+    pub events: EventBus,
+    linger: Duration,
+    sessions: Mutex<HashMap<String, Arc<TunerSession>>>,
+    open_lock: Mutex<()>,
+    next_id: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl SessionBroker {
+
+    // ブローカーの作成(max_sessions=0は上限なし)
+    pub fn new(linger: u64, max_sessions: usize) -> Arc<SessionBroker> {
+
+        Arc::new(SessionBroker {
+            started: SystemTime::now(),
+            b25_failures: AtomicU64::new(0),
+            preemptions: AtomicU64::new(0),
+            max_sessions: max_sessions,
+            events: EventBus::new(),
+            linger: Duration::from_secs(linger),
+            sessions: Mutex::new(HashMap::new()),
+            open_lock: Mutex::new(()),
+            next_id: AtomicU64::new(1),
+        })
+
+    }
+
+    // セッション一覧
+    pub fn sessions(&self) -> Vec<Arc<TunerSession>> {
+
+        let mut sessions: Vec<Arc<TunerSession>> = self.sessions.lock().unwrap().values().cloned().collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+
+    }
+
+    // チャンネルの購読開始
+    // 既存セッションが無い場合はチューナーをオープンして新しいセッションを開始する
+    pub fn subscribe(self: &Arc<Self>, client: &ClientInfo, channel: &String, sid: &String,
+        command_opt: &CommanLineOpt, decoder_opt: &DecoderOptions) -> Result<(Arc<TunerSession>, Arc<Subscriber>), SessionError> {
+
+        // セッション作成の排他
+        let _open = self.open_lock.lock().unwrap();
+
+        // 既存セッションへの参加
+        let key = session_key(channel, command_opt, decoder_opt);
+        if let Some(joined) = self.attach(&key, client, sid, command_opt) {
+            return Ok(joined);
+        };
+
+        // セッション数の上限(待機中のセッション、優先度の低いセッションの順に解放)
+        if self.max_sessions > 0 && self.sessions.lock().unwrap().len() >= self.max_sessions {
+            if self.reclaim_idle() == 0 && self.preempt(client, channel, command_opt, false) == false {
+                warn!("Session limit reached ({})", self.max_sessions);
+                return Err(SessionError::SessionLimit);
+            };
+        };
+
+        // 新規セッションの開始
+        // 空きチューナーが無い場合は待機中のセッション、優先度の低いセッションの順に解放して再試行
+        let subscriber = self.new_subscriber(client, sid, command_opt);
+        let mut result = self.open(&key, channel, &subscriber, command_opt, decoder_opt);
+        if matches!(result, Err(SessionError::NoDevice)) && self.reclaim_idle() > 0 {
+            result = self.open(&key, channel, &subscriber, command_opt, decoder_opt);
+        };
+        if matches!(result, Err(SessionError::NoDevice)) && self.preempt(client, channel, command_opt, true) {
+            result = self.open(&key, channel, &subscriber, command_opt, decoder_opt);
+        };
+        let session = result?;
+        info!("Session {} started (channel={} , device={} , subscriber={})", session.id, session.channel, session.device, client.addr);
+        self.events.publish(Event::SessionStarted {
+            session: session.id, channel: session.channel.to_string(), device: session.device.to_string(),
+        });
+
+        Ok((session, subscriber))
+
+    }
+
+    // チャンネルの購読終了
+    // タイムシフトバッファが有る場合は?resume=購読者IDで再開できるよう送信済みの位置を保存
+    pub fn unsubscribe(&self, session: &Arc<TunerSession>, subscriber: &Arc<Subscriber>) {
+
+        subscriber.close();
+        if let Some(buffer) = &session.timeshift {
+            buffer.remember(subscriber.id, subscriber.position.load(Ordering::Relaxed));
+        };
+
+        let mut subscribers = session.subscribers.lock().unwrap();
+        subscribers.retain(|s| s.id != subscriber.id);
+        info!("Session {} unsubscribed {} (remaining={})", session.id, subscriber.addr, subscribers.len());
+
+    }
+
+    // 購読者の作成(送信キューの容量・遅延時の処理方法はオプションから設定)
+    fn new_subscriber(&self, client: &ClientInfo, sid: &String, command_opt: &CommanLineOpt) -> Arc<Subscriber> {
+
+        let policy = SlowClientPolicy::parse(&command_opt.slow_client, command_opt.slow_client_timeout)
+            .unwrap_or(SlowClientPolicy::DropOldest);
+        let capacity = if command_opt.client_queue > 0 { command_opt.client_queue } else { SUBSCRIBER_QUEUE_CAPACITY };
+
+        Arc::new(Subscriber {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr: client.addr,
+            priority: client.priority,
+            agent: client.agent.to_string(),
+            sid: sid.to_string(),
+            started: SystemTime::now(),
+            sent: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            policy: policy,
+            lag_ms: AtomicU64::new(0),
+            max_lag_ms: AtomicU64::new(0),
+            resyncs: AtomicU64::new(0),
+            disconnected: AtomicBool::new(false),
+            queue: RingQueue::new("subscriber", capacity),
+            ready: Notify::new(),
+            position: AtomicU64::new(0),
+            cursor: Mutex::new(None),
+            behind_since: Mutex::new(None),
+            resyncing: AtomicBool::new(false),
+        })
+
+    }
+
+    // 既存セッションへの参加
+    // デバイス指定が無い要求はどのデバイスのセッションにも参加し、デバイス指定が有る要求はそのデバイスのセッションのみ参加する
+    fn attach(&self, key: &String, client: &ClientInfo, sid: &String, command_opt: &CommanLineOpt) -> Option<(Arc<TunerSession>, Arc<Subscriber>)> {
+
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.values().find(|session| {
+            session.key == device_key(key, &session.device) && session.is_stopped() == false &&
+                (command_opt._use_device == false || session.device == command_opt.device)
+        })?;
+
+        let subscriber = self.new_subscriber(client, sid, command_opt);
+        if let Some(buffer) = &session.timeshift {
+            subscriber.seek(buffer, command_opt.offset, command_opt.resume);
+        };
+        let mut subscribers = session.subscribers.lock().unwrap();
+        subscribers.push(Arc::clone(&subscriber));
+        info!("Session {} joined {} (channel={} , subscribers={})", session.id, client.addr, session.channel, subscribers.len());
+
+        Some((Arc::clone(session), subscriber))
+
+    }
+
+    // チューナーのオープン＆選局＆セッションスレッド起動
+    // 最初の購読者を登録した状態でセッションを開始する
+    fn open(self: &Arc<Self>, key: &String, channel: &String, subscriber: &Arc<Subscriber>,
+        command_opt: &CommanLineOpt, decoder_opt: &DecoderOptions) -> Result<Arc<TunerSession>, SessionError> {
+
+        // チャンネル情報からチャンネルタイプ,チャンネル番号,Slot番号の設定
+        let (channel_type, _freq) = channel_type(channel.to_string());
+        if channel_type == "" {
+            warn!("Bad Channel !! ({})", channel);
+            return Err(SessionError::BadChannel);
+        };
+
+        // チューナーデバイスの検索
+        let (device, file) = tuner_device(&command_opt.device, channel, &command_opt.reverse_device_order);
+        let device_file = match file {
+            Ok(file) if device != "" => file,
+            _ => {
+                warn!("No devices available");
+                return Err(SessionError::NoDevice);
+            },
+        };
+
+        // 以降のエラー時はガードの破棄でチューナー・デコーダーを解放
+        let mut guard = TunerGuard {
+            device: device.to_string(),
+            file: device_file,
+            recording: false,
+            lnb: matches!(command_opt._lnb, 1 | 2),
+            b25: None,
+        };
+
+        // チューナーの設定処理を呼び出し
+        if try_tune(&device, &guard.file, channel, &command_opt._lnb) == false {
+            return Err(SessionError::TuneFailed);
+        };
+
+        let signal = signal_get(&guard.file, &channel_type);
+        info!("C/N = {:.6} dB", signal);
+
+        // B25デコード処理(初期化エラー時はセッションを開始しない)
+        if command_opt.use_b25 == true {
+            let (result, dec, bcas) = unsafe { b25_startup(decoder_opt.round, decoder_opt.strip, decoder_opt.emm) };
+            guard.b25 = Some(B25Context::new(dec, bcas));
+            if result < 0 {
+                error!("B25 startup error ({})", result);
+                self.b25_failures.fetch_add(1, Ordering::Relaxed);
+                return Err(SessionError::B25Failed);
+            };
+        };
+
+        // 録画開始コマンド出力
+        if let Err(e) = unsafe { start_rec(guard.file.as_raw_fd()) } {
+            error!("start_rec error ({}, {})", device, e);
+            return Err(SessionError::TuneFailed);
+        };
+        guard.recording = true;
+
+        // セッションの作成
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let session = Arc::new(TunerSession {
+            id: id,
+            key: device_key(key, &device),
+            channel: channel.to_string(),
+            channel_type: channel_type,
+            device: device,
+            started: SystemTime::now(),
+            bytes: AtomicU64::new(0),
+            drops: AtomicU64::new(0),
+            signal: AtomicU32::new(signal.to_bits()),
+            timeshift: match command_opt.timeshift {
+                0 => None,
+                minutes => Some(TimeShiftBuffer::new(id, minutes, &command_opt.timeshift_dir)),
+            },
+            subscribers: Mutex::new(vec![Arc::clone(subscriber)]),
+            stop: AtomicBool::new(false),
+            handle: Mutex::new(None),
+        });
+
+        // セッションスレッド起動
+        let handle = {
+            let broker = Arc::clone(self);
+            let session = Arc::clone(&session);
+            thread::spawn(move || {
+                broker.run(&session, guard);
+            })
+        };
+        *session.handle.lock().unwrap() = Some(handle);
+
+        self.sessions.lock().unwrap().insert(session.key.to_string(), Arc::clone(&session));
+
+        Ok(session)
+
+    }
+
+    // 全セッションの停止(デーモン終了時)
+    // セッションスレッドで録画終了コマンド出力・B25解放・購読者の切断を行い、終了を待ち合わせる
+    pub fn shutdown(&self) {
+
+        let sessions: Vec<Arc<TunerSession>> = self.sessions.lock().unwrap().drain().map(|(_, session)| session).collect();
+
+        for session in &sessions {
+            session.stop.store(true, Ordering::Release);
+        };
+        for session in &sessions {
+            if let Some(handle) = session.handle.lock().unwrap().take() {
+                handle.join().unwrap_or(());
+            };
+        };
+        info!("All sessions stopped ({})", sessions.len());
+        self.events.publish(Event::DaemonStopping {});
+
+    }
+
+    // 待機中(購読者なし)のセッションを解放
+    // 解放したセッション数をリターン
+    fn reclaim_idle(&self) -> usize {
+
+        // 待機中のセッションを一覧から削除して停止要求
+        let idle: Vec<Arc<TunerSession>> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let keys: Vec<String> = sessions.iter()
+                .filter(|(_, session)| session.subscriber_count() == 0)
+                .map(|(key, _)| key.to_string())
+                .collect();
+            keys.iter().filter_map(|key| sessions.remove(key)).collect()
+        };
+
+        // セッションスレッドの終了待ち(チューナーデバイスの解放)
+        for session in &idle {
+            info!("Session {} reclaimed (channel={} , device={})", session.id, session.channel, session.device);
+            session.stop.store(true, Ordering::Release);
+            if let Some(handle) = session.handle.lock().unwrap().take() {
+                handle.join().unwrap_or(());
+            };
+        };
+
+        idle.len()
+
+    }
+
+    // 優先度の低いセッションを停止してチューナーを解放(プリエンプション)
+    // 要求元より優先度の低いセッションのうち、優先度が最も低く購読者の少ない新しいセッションを停止する
+    // need_device=trueの場合は要求チャンネルを選局できるデバイスのセッションのみ対象
+    fn preempt(&self, client: &ClientInfo, channel: &String, command_opt: &CommanLineOpt, need_device: bool) -> bool {
+
+        let (channel_type, _freq) = channel_type(channel.to_string());
+        let devices: Vec<String> = tuner_list().into_iter()
+            .filter(|(_, types)| types.contains(&&channel_type[..]))
+            .map(|(device, _)| device)
+            .collect();
+
+        // 停止するセッションの選択＆一覧から削除
+        let victim = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let victim = sessions.values()
+                .filter(|session| session.is_stopped() == false && session.priority() < client.priority)
+                .filter(|session| need_device == false || devices.contains(&session.device))
+                .filter(|session| need_device == false || command_opt._use_device == false || session.device == command_opt.device)
+                .min_by_key(|session| (session.priority(), session.subscriber_count(), std::cmp::Reverse(session.started)))
+                .cloned();
+            match victim {
+                Some(victim) => {
+                    self.remove(&mut sessions, &victim);
+                    victim
+                },
+                None => return false,
+            }
+        };
+
+        // セッションスレッドの終了待ち(購読者の切断＆チューナーデバイスの解放)
+        warn!("Session {} preempted by {} (priority {} > {} , channel={} , device={})",
+            victim.id, client.addr, client.priority, victim.priority(), victim.channel, victim.device);
+        self.preemptions.fetch_add(1, Ordering::Relaxed);
+        victim.stop.store(true, Ordering::Release);
+        if let Some(handle) = victim.handle.lock().unwrap().take() {
+            handle.join().unwrap_or(());
+        };
+
+        true
+
+    }
+
+    // 購読者不在のセッションの終了判定
+    // 不在がlinger秒続いたら終了するが、タイムシフトバッファに再開位置(?resume=ID)が残っている間は
+    // 最大でバッファの保持時間まで継続する(他のチャンネルの選局でチューナーが必要な場合はreclaim_idleで解放)
+    fn expired(&self, session: &TunerSession, idle: Duration) -> bool {
+
+        if idle < self.linger {
+            return false;
+        };
+
+        match &session.timeshift {
+            Some(buffer) => buffer.resumable() == false,
+            None => true,
+        }
+
+    }
+
+    // 購読者がいない場合にセッションを一覧から削除して停止要求
+    fn release(&self, session: &Arc<TunerSession>) -> bool {
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if session.subscriber_count() > 0 {
+            return false;
+        };
+
+        self.remove(&mut sessions, session);
+        session.stop.store(true, Ordering::Release);
+
+        true
+
+    }
+
+    // セッション一覧からの削除(同じキーの別セッションは削除しない)
+    fn remove(&self, sessions: &mut HashMap<String, Arc<TunerSession>>, session: &Arc<TunerSession>) {
+
+        if let Some(current) = sessions.get(&session.key) {
+            if Arc::ptr_eq(current, session) {
+                sessions.remove(&session.key);
+            };
+        };
+
+    }
+
+    // セッションスレッド(チューナーからの読み込み＆B25デコード＆配信)
+    fn run(&self, session: &Arc<TunerSession>, guard: TunerGuard) {
+
+        // ドロップチェック対象PIDの確定用
+        let mut pipeline = TsPipeline::new("", false);
+
+        // パケットドロップチェック用のパケット巡回カウンター
+        let mut drop_check = DropCheck::new();
+
+        // 購読者不在の開始時刻
+        let mut idle_since: Option<SystemTime> = None;
+
+        // C/Nの取得時刻
+        let mut signal_time = SystemTime::now();
+
+        // 監視イベントの集計(PID毎のドロップ数 , B25デコード失敗回数 , スクランブル未解除パケット数 , PATのバージョン)
+        let mut cc_errors: HashMap<u16, u64> = HashMap::new();
+        let mut b25_failed: u64 = 0;
+        let mut scrambled: u64 = 0;
+        let mut last_pat_version: Option<u8> = None;
+
+        let mut guard = guard;
+
+        loop {
+
+            // 停止要求
+            if session.is_stopped() { break; };
+
+            // 購読者がいない状態がlinger秒続いたらセッション終了(タイムシフトの再開待ちの間は継続)
+            if session.subscriber_count() == 0 {
+                let since = *idle_since.get_or_insert(SystemTime::now());
+                if self.expired(session, SystemTime::now().duration_since(since).unwrap_or_default()) {
+                    if self.release(session) { break; };
+                };
+            }
+            else {
+                idle_since = None;
+            };
+
+            // C/Nの定期取得＆集計した監視イベントの送信
+            if SystemTime::now().duration_since(signal_time).unwrap_or_default().as_secs() >= SIGNAL_INTERVAL {
+                let signal = signal_get(&guard.file, &session.channel_type);
+                session.signal.store(signal.to_bits(), Ordering::Relaxed);
+                signal_time = SystemTime::now();
+                self.publish_events(session, signal, &mut cc_errors, &mut b25_failed, &mut scrambled);
+            };
+
+            // データ到着待ち(停止要求・購読者不在を確認できるよう1秒でタイムアウト)
+            let ready = {
+                let mut fds = [PollFd::new(guard.file.as_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, POLL_TIMEOUT) {
+                    Ok(ready) => ready > 0,
+                    Err(Errno::EINTR) => false,
+                    Err(e) => {
+                        error!("Device poll error ({}, {})", session.device, e);
+                        break;
+                    },
+                }
+            };
+            if ready == false { continue; };
+
+            // バッファへ読み込み
+            let mut read_buffer: Vec<u8> = vec![0; CAP];
+            let length = match guard.file.read(&mut read_buffer) {
+                Ok(length) => length,
+                Err(e) => {
+                    error!("Device read error ({}, {})", session.device, e);
+                    break;
+                },
+            };
+            if length == 0 { continue; };
+            read_buffer.truncate(length);
+
+            // 対象PID取得処理
+            pipeline.select(&read_buffer);
+
+            // パケットドロップチェック
+            drop_check.check(&pipeline.sp, &read_buffer, |pid, continuity_counter, next_continuity_counter| {
+
+                let signal = signal_get(&guard.file, &session.channel_type);
+                debug!("パケットドロップ PID={}(0x{:04x}) , continuity_counter={} , next_continuity_counter={} signel={}",
+                    pid, pid, continuity_counter, next_continuity_counter, signal);
+                *cc_errors.entry(pid as u16).or_insert(0) += 1;
+
+            });
+            session.drops.store(drop_check.drop_count, Ordering::Relaxed);
+
+            // B25デコード処理
+            let chunk = match &mut guard.b25 {
+                Some(b25) => {
+                    let b25_buff = ARIB_STD_B25_BUFFER {
+                        data: read_buffer.as_ptr() as *mut u8,
+                        size: read_buffer.len() as u32,
+                    };
+                    let errors = b25.errors;
+                    let (buffer, len) = unsafe { b25.decode(&b25_buff) };
+                    b25_failed += b25.errors - errors;
+                    scrambled += scrambled_packets(&buffer[..len as usize]);
+                    buffer[..len as usize].to_vec()
+                },
+                None => read_buffer,
+            };
+
+            // PATのバージョン変更
+            if let Some(version) = pat_version(&chunk) {
+                if last_pat_version.map(|last| last != version).unwrap_or(false) {
+                    info!("Session {} PAT version changed ({})", session.id, version);
+                    self.events.publish(Event::PsiChanged {
+                        session: session.id, channel: session.channel.to_string(), table: "PAT".to_string(),
+                        pid: 0, version: version, client: None,
+                    });
+                };
+                last_pat_version = Some(version);
+            };
+
+            // 購読者へ配信
+            if chunk.len() > 0 {
+                session.bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                session.publish(Arc::new(chunk));
+            };
+
+        };
+
+        // 録画終了コマンド出力＆LNB停止＆B25解放＆チューナーデバイス解放
+        drop(guard);
+
+        // 全購読者の切断＆セッション一覧から削除
+        session.stop.store(true, Ordering::Release);
+        for subscriber in session.subscribers() {
+            subscriber.close();
+        };
+        self.remove(&mut self.sessions.lock().unwrap(), session);
+
+        info!("Session {} closed (channel={} , device={} , bytes={} , drops={})",
+            session.id, session.channel, session.device,
+            session.bytes.load(Ordering::Relaxed), session.drops.load(Ordering::Relaxed));
+        self.events.publish(Event::SessionStopped {
+            session: session.id, channel: session.channel.to_string(), device: session.device.to_string(),
+            bytes: session.bytes.load(Ordering::Relaxed), drops: session.drops.load(Ordering::Relaxed),
+        });
+
+    }
+
+    // 監視イベントの送信(C/N , 集計間隔内のPID毎のドロップ数 , B25エラー)
+    fn publish_events(&self, session: &TunerSession, signal: f32,
+        cc_errors: &mut HashMap<u16, u64>, b25_failed: &mut u64, scrambled: &mut u64) {
+
+        let channel = session.channel.to_string();
+        let device = session.device.to_string();
+
+        self.events.publish(Event::Signal { session: session.id, channel: channel.to_string(), device: device.to_string(), cn: signal });
+
+        let mut pids: Vec<(u16, u64)> = cc_errors.drain().collect();
+        pids.sort();
+        for (pid, count) in pids {
+            self.events.publish(Event::ContinuityErrors {
+                session: session.id, channel: channel.to_string(), device: device.to_string(), pid: pid, count: count,
+            });
+        };
+
+        if *b25_failed > 0 || *scrambled > 0 {
+            self.events.publish(Event::B25Errors {
+                session: session.id, channel: channel, device: device, decode_errors: *b25_failed, scrambled: *scrambled,
+            });
+        };
+        *b25_failed = 0;
+        *scrambled = 0;
+
+    }
+
+}
+
+// セッションキーの作成(チャンネル＋チューナー・デコード設定が同じ要求は同じセッションを共有)
+// デバイスは含めず、セッション一覧では実際にオープンしたデバイスを付加したキー(device_key)で管理する
+pub fn session_key(channel: &String, command_opt: &CommanLineOpt, decoder_opt: &DecoderOptions) -> String {
+
+    format!("{}|lnb={}|b25={}|round={}|strip={}|emm={}",
+        channel, command_opt._lnb, command_opt.use_b25,
+        decoder_opt.round, decoder_opt.strip, decoder_opt.emm)
+
+}
+
+// セッション一覧のキー(セッションキー＋オープンしたデバイス)
+pub fn device_key(key: &str, device: &str) -> String {
+
+    format!("{}|device={}", key, device)
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ts_splitter_core::LENGTH_PACKET;
+
+    // タイムシフトバッファの有るセッション(チューナー無し)
+    fn shifted_session(minutes: u64) -> Arc<TunerSession> {
+
+        Arc::new(TunerSession {
+            id: 1,
+            key: "test".to_string(),
+            channel: "test".to_string(),
+            channel_type: "".to_string(),
+            device: "".to_string(),
+            started: SystemTime::now(),
+            bytes: AtomicU64::new(0),
+            drops: AtomicU64::new(0),
+            signal: AtomicU32::new(0),
+            timeshift: Some(TimeShiftBuffer::new(1, minutes, "")),
+            subscribers: Mutex::new(vec![]),
+            stop: AtomicBool::new(false),
+            handle: Mutex::new(None),
+        })
+
+    }
+
+    // セッションへの購読者の追加
+    fn join(session: &TunerSession, id: u64) -> Arc<Subscriber> {
+
+        let subscriber = Arc::new(Subscriber {
+            id: id,
+            addr: "127.0.0.1:0".parse().unwrap(),
+            priority: 0,
+            agent: "".to_string(),
+            sid: "".to_string(),
+            started: SystemTime::now(),
+            sent: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            policy: SlowClientPolicy::DropOldest,
+            lag_ms: AtomicU64::new(0),
+            max_lag_ms: AtomicU64::new(0),
+            resyncs: AtomicU64::new(0),
+            disconnected: AtomicBool::new(false),
+            queue: RingQueue::new("subscriber", 4),
+            ready: Notify::new(),
+            position: AtomicU64::new(0),
+            cursor: Mutex::new(None),
+            behind_since: Mutex::new(None),
+            resyncing: AtomicBool::new(false),
+        });
+        session.subscribers.lock().unwrap().push(Arc::clone(&subscriber));
+
+        subscriber
+
+    }
+
+    // PATから始まるチャンク(PCRは番号の秒数、パケットの末尾に番号)
+    fn chunk(number: u8) -> Arc<Vec<u8>> {
+
+        let base = number as u64 * 90_000;
+        let mut packet = vec![0xff; LENGTH_PACKET];
+        packet[..12].copy_from_slice(&[0x47, 0x40, 0x00, 0x30, 7, 0x10,
+            (base >> 25) as u8, (base >> 17) as u8, (base >> 9) as u8, (base >> 1) as u8, ((base & 1) << 7) as u8 | 0x7e, 0x00]);
+        packet[LENGTH_PACKET - 1] = number;
+        Arc::new(packet)
+
+    }
+
+    // 送信タスクと同様に取り出せるチャンクを全て送信済みにし、ペイロードの番号をリターン
+    fn drain(session: &TunerSession, subscriber: &Subscriber) -> Vec<u8> {
+
+        let mut numbers = vec![];
+        while let Some(chunk) = subscriber.next_chunk(session) {
+            subscriber.record_lag(&chunk);
+            numbers.push(chunk.data[LENGTH_PACKET - 1]);
+        };
+
+        numbers
+
+    }
+
+    // 最後の購読者が切断してlinger秒を過ぎても再開位置が残っている間はセッションを継続し、続きから再生できること
+    #[test]
+    fn resume_after_last_subscriber_left() {
+
+        let broker = SessionBroker::new(5, 0);
+        let session = shifted_session(1);
+
+        // 送信キュー(4チャンク)を超えた分はバッファから再生
+        let subscriber = join(&session, 1);
+        for number in 0..10 {
+            session.publish(chunk(number));
+        };
+        assert!(subscriber.is_shifted());
+        assert_eq!(drain(&session, &subscriber), (0..10).collect::<Vec<u8>>());
+        assert!(subscriber.is_shifted() == false);
+        session.publish(chunk(10));
+        session.publish(chunk(11));
+        let sent = subscriber.next_chunk(&session).unwrap();
+        subscriber.record_lag(&sent);
+        assert_eq!(sent.data[LENGTH_PACKET - 1], 10);
+        broker.unsubscribe(&session, &subscriber);
+        assert_eq!(session.subscriber_count(), 0);
+
+        // 購読者不在のままチューナーからの配信は続く
+        for number in 12..20 {
+            session.publish(chunk(number));
+        };
+        assert!(broker.expired(&session, Duration::from_secs(4)) == false);
+        assert!(broker.expired(&session, Duration::from_secs(60)) == false);
+
+        // ?resume=IDで送信済みの続きから再生
+        let resumed = join(&session, 2);
+        resumed.seek(session.timeshift.as_ref().unwrap(), 0, subscriber.id);
+        assert_eq!(drain(&session, &resumed), (11..20).collect::<Vec<u8>>());
+        session.publish(chunk(20));
+        assert_eq!(drain(&session, &resumed), vec![20]);
+
+    }
+
+    // 再開位置が保持時間(ここでは0秒)を過ぎて破棄された場合・再開位置が無い場合はlinger秒でセッションを終了
+    #[test]
+    fn release_after_resume_expired() {
+
+        let broker = SessionBroker::new(5, 0);
+        let session = shifted_session(0);
+
+        let subscriber = join(&session, 1);
+        session.publish(chunk(0));
+        assert_eq!(drain(&session, &subscriber), vec![0]);
+        broker.unsubscribe(&session, &subscriber);
+        assert!(broker.expired(&session, Duration::from_secs(60)) == false);
+
+        for number in 1..3 {
+            session.publish(chunk(number));
+        };
+        assert!(broker.expired(&session, Duration::from_secs(4)) == false);
+        assert!(broker.expired(&session, Duration::from_secs(60)));
+
+        let live = shifted_session(1);
+        assert!(broker.expired(&live, Duration::from_secs(60)));
+
+    }
+
+}
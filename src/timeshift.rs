@@ -0,0 +1,529 @@
+use log::{debug, error, info};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::ring_queue::RingQueue;
+use crate::pipeline::{PCR_CLOCK, PCR_MAX_GAP, PCR_WRAP, adaptation_field};
+use crate::ts_splitter_core::{LENGTH_PACKET, get_pid};
+
+// タイムシフトの再開用IDのレスポンスヘッダー(?resume=IDで続きから再生)
+pub const TIMESHIFT_ID_HEADER: &str = "X-Recpt3-Timeshift-Id";
+
+// TOT/TDTのPID
+const TOT_PID: i16 = 0x14;
+
+// ディスク保存時の1ファイルの時間(秒)
+const FILE_SPAN: f64 = 60.0;
+
+// ディスクへの書き込み待ちの最大チャンク数(超えた分はメモリ上に保持)
+const WRITE_QUEUE_CAPACITY: usize = 1024;
+
+// 保持する再開位置の最大数
+const RESUME_LIMIT: usize = 1024;
+
+// MJDの1970/01/01とJSTの時差(秒)
+const MJD_UNIX_EPOCH: i64 = 40587;
+const JST_OFFSET: i64 = 9 * 3600;
+
+// ストリーム時刻(PCRで進める秒数)とTOTの時刻
+// PCRが無い・不連続な区間は実時間で進める
+#[allow(dead_code)]
+pub struct StreamClock {
+    // PCRを取得するPID(最初にPCRを検出したPID)
+    pcr_pid: Option<u16>,
+    last_pcr: Option<u64>,
+    // セッション開始からのストリーム時刻(秒)
+    time: f64,
+    // 直近のストリーム時刻の更新
+    updated: Instant,
+    // 直近のTOT(JSTのunix秒 , 受信時のストリーム時刻)
+    tot: Option<(i64, f64)>,
+}
+
+#[allow(dead_code)]
+impl StreamClock {
+
+    // 初期設定
+    pub fn new() -> StreamClock {
+
+        StreamClock {
+            pcr_pid: None,
+            last_pcr: None,
+            time: 0.0,
+            updated: Instant::now(),
+            tot: None,
+        }
+
+    }
+
+    // TSパケットからストリーム時刻を更新
+    // バッファ末尾のストリーム時刻(秒)をリターン
+    pub fn update(&mut self, buffer: &[u8]) -> f64 {
+
+        for packet in buffer.chunks_exact(LENGTH_PACKET) {
+
+            if packet[0] != 0x47 { continue; };
+            let pid = get_pid(packet);
+
+            // TOT/TDTの時刻
+            if pid == TOT_PID && (packet[1] & 0x40) != 0 {
+                if let Some(tot) = tot_time(packet) {
+                    self.tot = Some((tot, self.time));
+                };
+            };
+
+            // PCRの差分でストリーム時刻を進める(PCR_MAX_GAPを超える不連続の場合は実時間)
+            let (_, pcr) = adaptation_field(packet);
+            if let Some(pcr) = pcr {
+                if *self.pcr_pid.get_or_insert(pid as u16) != pid as u16 { continue; };
+                let delta = match self.last_pcr {
+                    Some(last) => ((pcr + PCR_WRAP - last) % PCR_WRAP) as f64 / PCR_CLOCK,
+                    None => PCR_MAX_GAP + 1.0,
+                };
+                self.time += if delta <= PCR_MAX_GAP { delta } else { self.updated.elapsed().as_secs_f64() };
+                self.last_pcr = Some(pcr);
+                self.updated = Instant::now();
+            };
+
+        };
+
+        // PCRが無い状態が続いた場合は実時間で進める
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        if elapsed > PCR_MAX_GAP {
+            self.time += elapsed;
+            self.last_pcr = None;
+            self.updated = Instant::now();
+        };
+
+        self.time
+
+    }
+
+    // ストリーム時刻に対応するTOTの時刻(unix秒、TOT受信前はNone)
+    pub fn wall_time(&self, time: f64) -> Option<i64> {
+
+        self.tot.map(|(tot, tot_time)| tot + (time - tot_time).round() as i64)
+
+    }
+
+}
+
+// TOT/TDTのJST_time(unix秒)
+fn tot_time(packet: &[u8]) -> Option<i64> {
+
+    // ペイロードの開始位置(アダプテーションフィールドとpointer_fieldを除く)
+    let mut pos = 4;
+    if (packet[3] & 0x20) != 0 {
+        pos += 1 + packet[4] as usize;
+    };
+    if pos >= LENGTH_PACKET { return None; };
+    pos += 1 + packet[pos] as usize;
+
+    // table_id(0x70=TDT , 0x73=TOT)とJST_time(MJD 16bit＋BCDの時分秒 24bit)
+    if pos + 8 > LENGTH_PACKET || (packet[pos] != 0x70 && packet[pos] != 0x73) {
+        return None;
+    };
+    let mjd = ((packet[pos + 3] as i64) << 8) | packet[pos + 4] as i64;
+    let bcd = |value: u8| ((value >> 4) * 10 + (value & 0x0F)) as i64;
+    let (hour, minute, second) = (bcd(packet[pos + 5]), bcd(packet[pos + 6]), bcd(packet[pos + 7]));
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    };
+
+    Some((mjd - MJD_UNIX_EPOCH) * 86400 + hour * 3600 + minute * 60 + second - JST_OFFSET)
+
+}
+
+// バッファのデータ(メモリ上またはディスクのファイル)
+#[allow(dead_code)]
+enum ShiftData {
+    Memory(Arc<Vec<u8>>),
+    File { number: u64, offset: u64, length: usize },
+}
+
+// バッファのエントリー(チャンク単位)
+struct ShiftEntry {
+    sequence: u64,
+    // ストリーム時刻(秒)
+    time: f64,
+    data: ShiftData,
+}
+
+// ディスク保存時のファイル
+struct ShiftFile {
+    number: u64,
+    path: PathBuf,
+    // 読み込みはロック外で行うため共有
+    file: Arc<File>,
+    length: u64,
+}
+
+// バッファの状態
+struct ShiftState {
+    entries: VecDeque<ShiftEntry>,
+    next_sequence: u64,
+    clock: StreamClock,
+    files: VecDeque<ShiftFile>,
+    next_file: u64,
+    bytes: u64,
+    // 切断した購読者の再開位置(購読者ID → シーケンス番号)
+    resume: HashMap<u64, u64>,
+}
+
+// ディスクへの書き込み待ちのチャンク(シーケンス番号 , ストリーム時刻 , データ)
+type PendingWrite = (u64, f64, Arc<Vec<u8>>);
+
+// タイムシフトバッファ
+// チューナーセッションのデコード済みデータを指定時間分保持し、
+// 遅れて参加・一時停止・再接続した購読者へ過去のデータを配信する
+// ディスク保存時は一旦メモリ上に追加し、書き込みスレッドでファイルへ移す(チューナーの読み込みを止めない)
+#[allow(dead_code)]
+pub struct TimeShiftBuffer {
+    // 保持する時間(秒)
+    pub window: f64,
+    dir: Option<PathBuf>,
+    name: String,
+    state: Arc<Mutex<ShiftState>>,
+    writer: Option<(Arc<RingQueue<PendingWrite>>, JoinHandle<()>)>,
+}
+
+#[allow(dead_code)]
+impl TimeShiftBuffer {
+
+    // バッファの作成(保持時間は分、dirが空の場合はメモリ上に保持)
+    pub fn new(session_id: u64, minutes: u64, dir: &str) -> TimeShiftBuffer {
+
+        let dir = if dir == "" { None } else { Some(PathBuf::from(dir)) };
+        let name = format!("recpt3-timeshift-{}-{}", std::process::id(), session_id);
+        let state = Arc::new(Mutex::new(ShiftState {
+            entries: VecDeque::new(),
+            next_sequence: 1,
+            clock: StreamClock::new(),
+            files: VecDeque::new(),
+            next_file: 0,
+            bytes: 0,
+            resume: HashMap::new(),
+        }));
+
+        // ディスク保存時は書き込みスレッド起動
+        let writer = dir.as_ref().map(|dir| {
+            let queue = Arc::new(RingQueue::new("time-shift writer", WRITE_QUEUE_CAPACITY));
+            let handle = {
+                let (queue, state, dir, name) = (Arc::clone(&queue), Arc::clone(&state), dir.clone(), name.clone());
+                thread::spawn(move || write_loop(&queue, &state, &dir, &name))
+            };
+            (queue, handle)
+        });
+
+        TimeShiftBuffer {
+            window: (minutes * 60) as f64,
+            dir: dir,
+            name: name,
+            state: state,
+            writer: writer,
+        }
+
+    }
+
+    // チャンクの追加＆保持時間を過ぎたデータの破棄
+    // チャンクのシーケンス番号をリターン
+    // ディスク保存時のファイルへの書き込みは書き込みスレッドで行う
+    pub fn append(&self, data: &Arc<Vec<u8>>) -> u64 {
+
+        let mut state = self.state.lock().unwrap();
+        let time = state.clock.update(data);
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+
+        // データの保存(書き込み待ちが溢れた場合はメモリ上に保持)
+        state.entries.push_back(ShiftEntry { sequence: sequence, time: time, data: ShiftData::Memory(Arc::clone(data)) });
+        state.bytes += data.len() as u64;
+        if let Some((queue, _)) = &self.writer {
+            if queue.try_push((sequence, time, Arc::clone(data))) == false {
+                debug!("Time-shift write queue full, kept in memory (sequence={})", sequence);
+            };
+        };
+
+        // 保持時間を過ぎたデータの破棄
+        while state.entries.front().map(|entry| entry.time < time - self.window).unwrap_or(false) {
+            if let Some(entry) = state.entries.pop_front() {
+                if let ShiftData::Memory(data) = &entry.data {
+                    state.bytes -= data.len() as u64;
+                };
+            };
+        };
+
+        // 参照されなくなったファイルの削除(書き込み中の最新のファイルは残す)
+        let oldest_file = match state.entries.front().map(|entry| &entry.data) {
+            Some(ShiftData::File { number, .. }) => *number,
+            _ => state.files.back().map(|file| file.number).unwrap_or(0),
+        };
+        while state.files.front().map(|file| file.number < oldest_file).unwrap_or(false) {
+            if let Some(file) = state.files.pop_front() {
+                state.bytes -= file.length;
+                remove_file(&file.path);
+            };
+        };
+
+        sequence
+
+    }
+
+    // 指定シーケンス番号以降の最初のチャンクの読み込み
+    // (シーケンス番号 , データ)をリターン(最新まで読み込み済みの場合はNone、読み込みエラーは空のデータ)
+    // ディスク保存時はファイル・位置をロック中に取得し、読み込みはロック外で行う
+    pub fn read(&self, sequence: u64) -> Option<(u64, Arc<Vec<u8>>)> {
+
+        let (sequence, file, path, offset, length) = {
+            let state = self.state.lock().unwrap();
+            let index = state.entries.partition_point(|entry| entry.sequence < sequence);
+            let entry = state.entries.get(index)?;
+            match &entry.data {
+                ShiftData::Memory(data) => return Some((entry.sequence, Arc::clone(data))),
+                ShiftData::File { number, offset, length } => match state.files.iter().find(|file| file.number == *number) {
+                    Some(file) => (entry.sequence, Arc::clone(&file.file), file.path.clone(), *offset, *length),
+                    None => return Some((entry.sequence, Arc::new(vec![]))),
+                },
+            }
+        };
+
+        // ファイルが削除済みでもオープン中のハンドルから読み込める
+        let mut data = vec![0; length];
+        if let Err(e) = file.read_exact_at(&mut data, offset) {
+            error!("Time-shift read error ({}, {})", path.display(), e);
+            return Some((sequence, Arc::new(vec![])));
+        };
+
+        Some((sequence, Arc::new(data)))
+
+    }
+
+    // 最新のデータからoffset秒(負の値)前のシーケンス番号(保持時間より前は最も古いデータ)
+    pub fn seek(&self, offset: f64) -> Option<u64> {
+
+        let state = self.state.lock().unwrap();
+        let latest = state.entries.back()?.time;
+        let index = state.entries.partition_point(|entry| entry.time < latest + offset);
+
+        state.entries.get(index).map(|entry| entry.sequence)
+
+    }
+
+    // 次に追加するチャンクのシーケンス番号
+    pub fn next_sequence(&self) -> u64 {
+
+        self.state.lock().unwrap().next_sequence
+
+    }
+
+    // 指定シーケンス番号の最新のデータからの遅れ(秒)
+    pub fn delay(&self, sequence: u64) -> f64 {
+
+        let state = self.state.lock().unwrap();
+        let latest = match state.entries.back() {
+            Some(entry) if entry.sequence >= sequence => entry.time,
+            _ => return 0.0,
+        };
+        let index = state.entries.partition_point(|entry| entry.sequence < sequence);
+
+        state.entries.get(index).map(|entry| latest - entry.time).unwrap_or(0.0)
+
+    }
+
+    // 保持しているデータの(時間(秒) , 開始時刻 , 終了時刻 , バイト数)
+    // 時刻はTOTから求めたunix秒(TOT受信前はNone)
+    pub fn range(&self) -> (f64, Option<i64>, Option<i64>, u64) {
+
+        let state = self.state.lock().unwrap();
+        match (state.entries.front(), state.entries.back()) {
+            (Some(first), Some(last)) => (last.time - first.time,
+                state.clock.wall_time(first.time), state.clock.wall_time(last.time), state.bytes),
+            _ => (0.0, None, None, 0),
+        }
+
+    }
+
+    // 切断した購読者の再開位置を保存(上限を超えた場合は古い購読者から破棄)
+    pub fn remember(&self, id: u64, sequence: u64) {
+
+        if sequence == 0 { return; };
+
+        let mut state = self.state.lock().unwrap();
+        if state.resume.len() >= RESUME_LIMIT {
+            if let Some(oldest) = state.resume.keys().min().cloned() {
+                state.resume.remove(&oldest);
+            };
+        };
+        state.resume.insert(id, sequence);
+        debug!("Time-shift position saved (id={} , sequence={})", id, sequence);
+
+    }
+
+    // 保持しているデータの範囲内の再開位置が有るか判定
+    pub fn resumable(&self) -> bool {
+
+        let state = self.state.lock().unwrap();
+        let oldest = state.entries.front().map(|entry| entry.sequence).unwrap_or(state.next_sequence);
+
+        state.resume.values().any(|sequence| *sequence >= oldest)
+
+    }
+
+    // 再開位置の取り出し(保持時間を過ぎた位置は最も古いデータから再開)
+    pub fn take_resume(&self, id: u64) -> Option<u64> {
+
+        let mut state = self.state.lock().unwrap();
+        let sequence = state.resume.remove(&id)?;
+        let oldest = state.entries.front().map(|entry| entry.sequence).unwrap_or(sequence);
+
+        Some(sequence.max(oldest))
+
+    }
+
+}
+
+impl Drop for TimeShiftBuffer {
+
+    // 書き込みスレッドの終了＆ディスク保存時のファイルを削除
+    fn drop(&mut self) {
+
+        if let Some((queue, handle)) = self.writer.take() {
+            queue.close();
+            queue.clear();
+            let _ = handle.join();
+        };
+
+        let mut state = self.state.lock().unwrap();
+        for file in state.files.drain(..) {
+            remove_file(&file.path);
+        };
+        if self.dir.is_some() {
+            info!("Time-shift files removed ({})", self.name);
+        };
+
+    }
+
+}
+
+// 書き込みスレッド
+// 書き込み待ちのチャンクをファイルへ書き込み(FILE_SPAN秒毎に新しいファイル)、バッファのデータをファイルの位置へ置き換える
+// 書き込みエラー・保持時間を過ぎたチャンクはメモリ上のまま(破棄済み)とする
+fn write_loop(queue: &RingQueue<PendingWrite>, state: &Mutex<ShiftState>, dir: &PathBuf, name: &str) {
+
+    // 書き込み中のファイル(番号 , ファイル , 開始時刻 , 書き込み位置)
+    let mut current: Option<(u64, Arc<File>, f64, u64)> = None;
+
+    while let Some((sequence, time, data)) = queue.pop() {
+
+        // 新しいファイルの作成
+        if current.as_ref().map(|(_, _, started, _)| time - started >= FILE_SPAN).unwrap_or(true) {
+            let number = state.lock().unwrap().next_file;
+            let path = dir.join(format!("{}-{}.ts", name, number));
+            let file = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path) {
+                Ok(file) => Arc::new(file),
+                Err(e) => {
+                    error!("Time-shift write error ({}, {})", path.display(), e);
+                    continue;
+                },
+            };
+            debug!("Time-shift file created ({})", path.display());
+            let mut state = state.lock().unwrap();
+            state.files.push_back(ShiftFile { number: number, path: path, file: Arc::clone(&file), length: 0 });
+            state.next_file += 1;
+            current = Some((number, file, time, 0));
+        };
+
+        // ファイルへの書き込み(ロック外で行う)
+        let (number, file, _, length) = current.as_mut().unwrap();
+        if let Err(e) = file.write_all_at(&data, *length) {
+            error!("Time-shift write error ({}, {})", dir.display(), e);
+            continue;
+        };
+        let offset = *length;
+        *length += data.len() as u64;
+
+        // バッファのデータをファイルの位置へ置き換え
+        let mut state = state.lock().unwrap();
+        match state.files.iter_mut().find(|file| file.number == *number) {
+            Some(file) => file.length += data.len() as u64,
+            None => continue,
+        };
+        let index = state.entries.partition_point(|entry| entry.sequence < sequence);
+        match state.entries.get_mut(index) {
+            Some(entry) if entry.sequence == sequence => {
+                entry.data = ShiftData::File { number: *number, offset: offset, length: data.len() };
+            },
+            _ => state.bytes += data.len() as u64,
+        };
+
+    };
+
+}
+
+// ファイルの削除
+fn remove_file(path: &PathBuf) {
+
+    if let Err(e) = fs::remove_file(path) {
+        debug!("Time-shift file remove error ({}, {})", path.display(), e);
+    };
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    // 書き込み待ちのチャンクがファイルへ移るまで待ち合わせ
+    fn wait_written(buffer: &TimeShiftBuffer) -> bool {
+
+        for _ in 0..500 {
+            let state = buffer.state.lock().unwrap();
+            if state.entries.iter().all(|entry| matches!(entry.data, ShiftData::File { .. })) {
+                return true;
+            };
+            drop(state);
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        false
+
+    }
+
+    // ディスク保存時は書き込みスレッドでファイルへ移し、同じデータを読み込めること＆破棄時にファイルを削除
+    #[test]
+    fn disk_append_read() {
+
+        let dir = env::temp_dir().join(format!("recpt3-timeshift-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let buffer = TimeShiftBuffer::new(1, 1, &dir.to_string_lossy());
+
+        let chunks: Vec<Arc<Vec<u8>>> = (0..100u8).map(|n| Arc::new(vec![n; LENGTH_PACKET * 4])).collect();
+        let sequences: Vec<u64> = chunks.iter().map(|chunk| buffer.append(chunk)).collect();
+        assert_eq!(sequences, (1..=100).collect::<Vec<u64>>());
+        assert_eq!(buffer.next_sequence(), 101);
+        assert!(wait_written(&buffer));
+
+        for (sequence, chunk) in sequences.iter().zip(chunks.iter()) {
+            let (read, data) = buffer.read(*sequence).unwrap();
+            assert_eq!(read, *sequence);
+            assert_eq!(data, *chunk);
+        };
+        assert!(buffer.read(101).is_none());
+        assert_eq!(buffer.range().3, 100 * LENGTH_PACKET as u64 * 4);
+
+        drop(buffer);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+
+    }
+
+}
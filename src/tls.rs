@@ -0,0 +1,307 @@
+use log::{error, info, warn};
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+// ALPNで受け付けるプロトコル
+const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+// クライアント接続(平文またはTLS)
+// TLS接続の終了時はshutdownでclose_notifyを送信する
+#[allow(dead_code)]
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+#[allow(dead_code)]
+impl ClientStream {
+
+    // 下位のTCPストリーム
+    pub fn tcp(&self) -> &TcpStream {
+
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls(stream) => stream.get_ref().0,
+        }
+
+    }
+
+    // 接続元アドレス
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+
+        self.tcp().peer_addr()
+
+    }
+
+    // 待ち受け側アドレス
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+
+        self.tcp().local_addr()
+
+    }
+
+    // TLS接続か
+    pub fn is_tls(&self) -> bool {
+
+        matches!(self, ClientStream::Tls(_))
+
+    }
+
+}
+
+impl AsyncRead for ClientStream {
+
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+
+    }
+
+}
+
+impl AsyncWrite for ClientStream {
+
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+
+    }
+
+}
+
+// TLS設定(SIGHUPで証明書・秘密鍵を再読み込み)
+#[allow(dead_code)]
+pub struct TlsAcceptor {
+    cert_path: String,
+    key_path: String,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+#[allow(dead_code)]
+impl TlsAcceptor {
+
+    // 証明書・秘密鍵を読み込んでTLS設定を作成
+    pub fn new(cert_path: &str, key_path: &str) -> Result<Arc<TlsAcceptor>, String> {
+
+        let config = load_config(cert_path, key_path)?;
+
+        Ok(Arc::new(TlsAcceptor {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            config: RwLock::new(config),
+        }))
+
+    }
+
+    // 証明書・秘密鍵の再読み込み(失敗時は現在の設定を継続)
+    pub fn reload(&self) -> bool {
+
+        match load_config(&self.cert_path, &self.key_path) {
+            Ok(config) => {
+                *self.config.write().unwrap() = config;
+                info!("TLS certificate reloaded ({})", self.cert_path);
+                true
+            },
+            Err(message) => {
+                error!("TLS certificate reload error ({}), keeping the current certificate", message);
+                false
+            },
+        }
+
+    }
+
+    // SIGHUP受信時の再読み込みスレッドの起動
+    pub fn reload_on_sighup(self: &Arc<Self>) -> () {
+
+        let mut signals = match Signals::new([SIGHUP]) {
+            Ok(signals) => signals,
+            Err(e) => {
+                warn!("SIGHUP handler error ({})", e);
+                return;
+            },
+        };
+
+        let acceptor = Arc::clone(self);
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("SIGHUP received. reloading TLS certificate...");
+                acceptor.reload();
+            };
+        });
+
+    }
+
+    // TLS接続の作成(ハンドシェイク完了まで待ち合わせ)
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<ClientStream> {
+
+        let config = Arc::clone(&self.config.read().unwrap());
+        let stream = tokio_rustls::TlsAcceptor::from(config).accept(stream).await?;
+
+        Ok(ClientStream::Tls(Box::new(stream)))
+
+    }
+
+}
+
+// 証明書チェーン・秘密鍵(PEM)の読み込み
+fn load_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, String> {
+
+    // 証明書チェーン
+    let cert_file = File::open(cert_path).map_err(|e| format!("{} ({})", cert_path, e))?;
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("{} ({})", cert_path, e))?;
+    if certs.len() == 0 {
+        return Err(format!("{} (no certificate)", cert_path));
+    };
+
+    // 秘密鍵(PKCS#8, PKCS#1, SEC1)
+    let key_file = File::open(key_path).map_err(|e| format!("{} ({})", key_path, e))?;
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .map_err(|e| format!("{} ({})", key_path, e))?
+        .ok_or(format!("{} (no private key)", key_path))?;
+
+    // TLS設定(暗号はring)
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{} , {} ({})", cert_path, key_path, e))?;
+    config.alpn_protocols = vec![ALPN_HTTP11.to_vec()];
+
+    Ok(Arc::new(config))
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rustls::ClientConfig;
+    use rustls::RootCertStore;
+    use rustls::pki_types::ServerName;
+    use std::fs;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // 自己署名証明書・秘密鍵(PEM)を一時ディレクトリへ書き出し、証明書(DER)を返す
+    fn write_certificate(dir: &PathBuf) -> CertificateDer<'static> {
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+
+        certified.cert.der().clone()
+
+    }
+
+    // 指定の証明書のみを信頼するクライアントでハンドシェイクし、1往復の送受信を確認
+    async fn round_trip(acceptor: &Arc<TlsAcceptor>, trusted: CertificateDer<'static>) -> io::Result<Vec<u8>> {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_acceptor = Arc::clone(acceptor);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = server_acceptor.accept(stream).await?;
+            assert!(stream.is_tls());
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).await?;
+            stream.write_all(&buffer).await?;
+            stream.shutdown().await?;
+            Ok::<(), io::Error>(())
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![ALPN_HTTP11.to_vec()];
+
+        let stream = TcpStream::connect(addr).await?;
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream).await?;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(ALPN_HTTP11));
+        stream.write_all(b"ping").await?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+
+        server.await.unwrap()?;
+
+        Ok(response)
+
+    }
+
+    // ハンドシェイクの完了と、再読み込み後は新しい証明書が使われることを確認
+    #[tokio::test]
+    async fn handshake_and_reload() {
+
+        let dir = std::env::temp_dir().join(format!("recpt3-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem").to_string_lossy().to_string();
+        let key_path = dir.join("key.pem").to_string_lossy().to_string();
+
+        let first = write_certificate(&dir);
+        let acceptor = TlsAcceptor::new(&cert_path, &key_path).unwrap();
+        assert_eq!(round_trip(&acceptor, first.clone()).await.unwrap(), b"ping");
+
+        // 証明書を差し替えて再読み込み
+        let second = write_certificate(&dir);
+        assert!(acceptor.reload());
+        assert_eq!(round_trip(&acceptor, second.clone()).await.unwrap(), b"ping");
+        assert!(round_trip(&acceptor, first).await.is_err());
+
+        // 読み込めない場合は現在の設定を継続
+        fs::write(&key_path, "").unwrap();
+        assert!(acceptor.reload() == false);
+        assert_eq!(round_trip(&acceptor, second).await.unwrap(), b"ping");
+
+        let _ = fs::remove_dir_all(&dir);
+
+    }
+
+}
@@ -20,6 +20,10 @@ mod arib_b25;
 mod commands;
 mod decoder;
 mod ffi;
+mod metrics;
+mod output;
+mod pipeline;
+mod ring_queue;
 mod ts_splitter_core;
 mod tuner;
 
@@ -220,6 +224,7 @@ fn ts_split(command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions) -> ()
 
     // ts splitterの初期化処理
     let mut sp = split_startup(&command_opt.sid_list);
+    let mut split_buff: Vec<u8> = Vec::with_capacity(CAP);
 
     // 入力ファイルオープン
     let file = File::open(command_opt.infile.to_string()).unwrap();
@@ -307,24 +312,22 @@ fn ts_split(command_opt: &mut CommanLineOpt, decoder_opt: &DecoderOptions) -> ()
             };
 
             // ts splitter向け変数
-            let mut split_buff: Vec<u8> = vec![];
             let mut _result = 0;
 
             // ts splitter処理
             if command_opt.use_splitter == true && len > 0 {
 
-                // データバッファ作成
-                let mut data_buff: Vec<u8> = vec![0; buffer.len()];
-                data_buff[..buffer.len()].copy_from_slice(&buffer[..buffer.len()]);
+                // 出力バッファのクリア(領域は再利用)
+                split_buff.clear();
 
                 // 処理するsidの取得
-                _result = split_select(&mut sp, &mut data_buff);
+                _result = split_select(&mut sp, &buffer[..len as usize]);
 
                 // sid取得OK時の処理
                 if _result == TSS_SUCCESS {
 
                     // sid split処理
-                    _result = split_ts(&mut sp, &mut data_buff, &mut split_buff);
+                    _result = split_ts(&mut sp, &buffer[..len as usize], &mut split_buff);
 
                 };
 
@@ -28,6 +28,15 @@ pub struct Splitter {
     pmt_version: PmtVersion,
     section_remain: [u16; MAX_PID],
     packet_seq: [u8; MAX_PID],
+    // read_tsでPMT解析済みのパケット位置(同じバッファのsplit_tsでは出力しない)
+    seen_pmt: Vec<usize>,
+    // rescan_pidの開始回数と直近の契機になったPMTのPID・バージョン
+    #[allow(dead_code)]
+    pub rescans: u64,
+    #[allow(dead_code)]
+    pub rescan_pid: i16,
+    #[allow(dead_code)]
+    pub rescan_version: u8,
 }
 
 // Ts Splitterの初期設定
@@ -50,6 +59,10 @@ pub fn split_startup(sid: &str) -> Splitter {
         },
         section_remain: [0; MAX_PID],
         packet_seq: [0; MAX_PID],
+        seen_pmt: vec![],
+        rescans: 0,
+        rescan_pid: 0,
+        rescan_version: 0,
     };
 
     sp
@@ -57,7 +70,7 @@ pub fn split_startup(sid: &str) -> Splitter {
 }
 
 // ドロップするPIDの確定処理
-pub fn split_select(mut sp: &mut Splitter, buff: &mut Vec<u8>) -> i32 {
+pub fn split_select(mut sp: &mut Splitter, buff: &[u8]) -> i32 {
 
     // TS解析
     let result = read_ts(&mut sp, buff);
@@ -68,20 +81,30 @@ pub fn split_select(mut sp: &mut Splitter, buff: &mut Vec<u8>) -> i32 {
 }
 
 // TS 分離処理
-pub fn split_ts(mut sp: &mut Splitter, buff: &mut [u8], split_buff: &mut Vec<u8>) -> i32 {
+// 残すパケットはsplit_buffへまとめてコピーする(split_buffは呼び出し側で再利用)
+pub fn split_ts(mut sp: &mut Splitter, buff: &[u8], split_buff: &mut Vec<u8>) -> i32 {
 
     //debug!("Called split_ts buff.len={}", buff.len());
     let mut result = TSS_SUCCESS ;
-    let length = buff.len();
-    let mut in_index = 0;
     let mut version = 0;
-    let mut _drop = 0;
+
+    // 出力バッファの領域確保(最大で入力と同じサイズ)
+    split_buff.reserve(buff.len());
+
+    // 直前のread_tsでPMT解析済みのパケット(ヌルパケットとして扱う)
+    let seen_pmt = std::mem::take(&mut sp.seen_pmt);
 
     // バッファエンドまでループ
-    while (length as i16 - in_index as i16 - LENGTH_PACKET as i16) >= 0 {
+    for (count, packet) in buff.chunks_exact(LENGTH_PACKET).enumerate() {
+
+        // パケット先頭インデックス
+        let in_index = count * LENGTH_PACKET;
 
         // PID取得
-        let pid = get_pid(&buff[in_index..in_index + LENGTH_PACKET - 1]);
+        let pid = match seen_pmt.contains(&in_index) {
+            true => 0x1fff,
+            false => get_pid(packet),
+        };
 
         // PID判定
         match pid {
@@ -99,9 +122,7 @@ pub fn split_ts(mut sp: &mut Splitter, buff: &mut [u8], split_buff: &mut Vec<u8>
                 sp.pat[3] = sp.pat_count;
 
                 // Splitバッファーに新しいPATを作成
-                for cnt in 0..LENGTH_PACKET {
-                    split_buff.push(sp.pat[cnt]);
-                }
+                split_buff.extend_from_slice(&sp.pat);
             },
             // PAT以外の処理
             _ => {
@@ -109,7 +130,7 @@ pub fn split_ts(mut sp: &mut Splitter, buff: &mut [u8], split_buff: &mut Vec<u8>
                 if sp.pmt_pids[pid as usize] != 0 {
 
                     // PMT (PES開始インジケータ)
-                    if (buff[in_index + 1] & 0x40) == 0x40 {
+                    if (packet[1] & 0x40) == 0x40 {
 
                         // バージョンチェック
                         for pmts in 0..sp.pmt_retain {
@@ -118,11 +139,11 @@ pub fn split_ts(mut sp: &mut Splitter, buff: &mut [u8], split_buff: &mut Vec<u8>
                             }
                         }
 
-                        if version != buff[in_index + 10] & 0x3e ||
+                        if version != packet[10] & 0x3e ||
                             sp.pmt_retain != sp.pmt_counter {
                             // 再チェック
                             debug!("split_ts Call rescan_pid is Version Check NG version={}, buff[{}]={}, sp.pmt_retain={}, sp.pmt_counter={}",
-                                version, in_index + 10 & 0x3e, buff[in_index + 10] & 0x3e, sp.pmt_retain, sp.pmt_counter);
+                                version, in_index + 10, packet[10] & 0x3e, sp.pmt_retain, sp.pmt_counter);
                             result = rescan_pid(&mut sp, &buff[in_index..]);
                         }
                     }
@@ -137,17 +158,19 @@ pub fn split_ts(mut sp: &mut Splitter, buff: &mut [u8], split_buff: &mut Vec<u8>
 
                 // sp.pids[pid]が「1」のパケットは残すパケット
                 if sp.pids[pid as usize] != 0 {
-                    for cnt in 0..LENGTH_PACKET {
-                        // Splitバッファー作成
-                        split_buff.push(buff[cnt + in_index]);
+                    // Splitバッファー作成(PMT解析済みのパケットはPIDを0x1fffに書き換えて出力)
+                    if pid == 0x1fff && seen_pmt.contains(&in_index) {
+                        split_buff.push(packet[0]);
+                        split_buff.extend_from_slice(&[0xff, 0xff]);
+                        split_buff.extend_from_slice(&packet[3..]);
                     }
+                    else {
+                        split_buff.extend_from_slice(packet);
+                    };
                 };
             },
         }
 
-        // ループカウンタをパケットサイズ数分カウントアップ
-        in_index += LENGTH_PACKET;
-
     }
 
     // リターン情報
@@ -179,6 +202,11 @@ pub fn rescan_pid(mut sp: &mut Splitter, buff: &[u8]) -> i32 {
             sp.section_remain[cnt] = 0;
             sp.packet_seq[cnt] = 0;
         };
+
+        // 再スキャンの記録
+        sp.rescans += 1;
+        sp.rescan_pid = pid;
+        sp.rescan_version = (buff[10] & 0x3e) >> 1;
         warn!("Rescan PID");
 
     };
@@ -215,13 +243,14 @@ pub fn rescan_pid(mut sp: &mut Splitter, buff: &[u8]) -> i32 {
 
 // TS 解析処理
 // 対象のチャンネル番号のみの PAT の再構築と出力対象 PID の抽出を行う
-pub fn read_ts(mut sp: &mut Splitter, data: &mut [u8]) -> i32 {
+pub fn read_ts(mut sp: &mut Splitter, data: &[u8]) -> i32 {
 
     let mut result = TSS_ERROR;
 
     // 変数初期化
     let length = data.len();
     let mut index = 0;
+    sp.seen_pmt.clear();
 
     // バッファエンドまでループ
     while (length as i32 - index as i32 - LENGTH_PACKET as i32) >= 0 {
@@ -248,8 +277,7 @@ pub fn read_ts(mut sp: &mut Splitter, data: &mut [u8]) -> i32 {
             if analyze_result == TSS_SUCCESS {
                 sp.pmt_pids[pid as usize] += 1;
                 sp.pmt_counter += 1;
-                data[index + 1] = 0xff;
-                data[index + 2] = 0xff;
+                sp.seen_pmt.push(index);
                 debug!("read_ts sp.pmt_pids[{}(0x{:04x}]={} , sp.pmt_counter={}", pid, pid, sp.pmt_pids[pid as usize], sp.pmt_counter);
             }
 
@@ -5,17 +5,17 @@ use std::env;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Write, BufReader, BufWriter};
 use std::io::prelude::*;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsFd, AsRawFd};
 use std::path::Path;
 use std::process;
 use std::result::Result;
 use sscanf::sscanf;
-use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering, mpsc};
+use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::AtomicU32, atomic::AtomicU64, atomic::Ordering, mpsc};
 use std::thread;
 use std::time::{Duration, SystemTime};
 use posix_mq::{Name,Queue};
+use nix::poll::{poll, PollFd, PollFlags};
 
 nix::ioctl_write_buf!(set_ch, 0x8d, 0x01,IoctlFreq);
 nix::ioctl_none!(start_rec, 0x8d, 0x02);
@@ -28,9 +28,11 @@ nix::ioctl_write_int!(ptx_set_sys_mode, 0x8d, 0x0b);
 use crate::arib_b25::{ARIB_STD_B25, ARIB_STD_B25_BUFFER, B_CAS_CARD};
 use crate::commands::{CommanLineOpt, DecoderOptions, PROGRAM_RECPT};
 //use crate::commands::TRUE;
-use crate::decoder::{b25_startup, b25_decode, b25_shutdown};
-use crate::ts_splitter_core::{LENGTH_PACKET, MAX_PID, get_pid, split_startup, split_select, split_ts,
-    TSS_ERROR, TSS_SUCCESS};
+use crate::decoder::{B25Context, b25_startup, b25_decode, b25_decode_errors, b25_shutdown};
+use crate::metrics::{METRICS_INTERVAL, Metric, MetricType, write_textfile};
+use crate::output::{OutputFile, expected_bitrate};
+use crate::pipeline::{DropCheck, TsPipeline};
+use crate::ring_queue::RingQueue;
 
 // BSデバイスファイル名
 const BSDEV: [&str; 118] = [
@@ -284,6 +286,10 @@ const ISDB_T_DEV: [&str; 118] = [
 #[allow(dead_code)]
 pub const CAP: usize = 188 * 87;
 
+// スレッド間キューのサイズ(CAP単位、約5秒分)
+#[allow(dead_code)]
+pub const QUEUE_CAPACITY: usize = 1024;
+
 // ioctl freq 
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
@@ -293,51 +299,70 @@ pub struct IoctlFreq {
 }
 
 #[derive(Debug, Clone)]
-struct BsChannel {
-    channel: i32,
-    set_freq: i32,
-    slot: i32,
+#[allow(dead_code)]
+pub struct BsChannel {
+    pub channel: i32,
+    pub set_freq: i32,
+    pub slot: i32,
+    pub name: &'static str,
 }
 
 // BSチャンネル一覧
-const BS_CHANNELS: [BsChannel; 26] = [
-    BsChannel{channel: 151, set_freq:  0, slot: 0}, // 151ch：BS朝日
-    BsChannel{channel: 161, set_freq:  0, slot: 1}, // 161ch：BS-TBS
-    BsChannel{channel: 171, set_freq:  0, slot: 2}, // 171ch：BSテレ東
-    BsChannel{channel: 191, set_freq:  1, slot: 0}, // 191ch：WOWOWプライム
-    BsChannel{channel: 236, set_freq:  1, slot: 1}, // 236ch：BSアニマックス
-    BsChannel{channel: 251, set_freq:  1, slot: 2}, // 251ch：BS釣りビジョン
-    BsChannel{channel: 192, set_freq:  2, slot: 0}, // 192ch：WOWOWライブ
-    BsChannel{channel: 193, set_freq:  2, slot: 1}, // 193ch：WOWOWシネマ
-    BsChannel{channel: 211, set_freq:  4, slot: 0}, // 211ch：BS11イレブン
-    BsChannel{channel: 222, set_freq:  4, slot: 1}, // 222ch：BS12TwellV
-    BsChannel{channel: 141, set_freq:  6, slot: 0}, // 141ch：BS日テレ
-    BsChannel{channel: 181, set_freq:  6, slot: 1}, // 181ch：BSフジ
-    BsChannel{channel: 231, set_freq:  6, slot: 2}, // 231ch：放送大学
-    BsChannel{channel: 101, set_freq:  7, slot: 0}, // 101ch：NHKBS1
-    BsChannel{channel: 201, set_freq:  7, slot: 1}, // 201ch：BS10スターch
-    BsChannel{channel: 200, set_freq:  7, slot: 2}, // 200ch：BS10
-    BsChannel{channel: 245, set_freq:  9, slot: 0}, // 245ch：J SPORTS 4
-    BsChannel{channel: 242, set_freq:  9, slot: 1}, // 242ch：J SPORTS 1
-    BsChannel{channel: 243, set_freq:  9, slot: 2}, // 243ch：J SPORTS 2
-    BsChannel{channel: 244, set_freq:  9, slot: 3}, // 244ch：J SPORTS 3
-    BsChannel{channel: 252, set_freq: 10, slot: 0}, // 252ch：WOWOWプラス
-    BsChannel{channel: 255, set_freq: 10, slot: 1}, // 255ch：日本映画専門ch
-    BsChannel{channel: 234, set_freq: 10, slot: 2}, // 234ch：グリーンch
-    BsChannel{channel: 256, set_freq: 11, slot: 0}, // 256ch：ディズニーch
-    BsChannel{channel: 265, set_freq: 11, slot: 1}, // 265ch：BSよしもと
-    BsChannel{channel: 260, set_freq: 11, slot: 2}, // 260ch：BS松竹東急
+#[allow(dead_code)]
+pub const BS_CHANNELS: [BsChannel; 26] = [
+    BsChannel{channel: 151, set_freq:  0, slot: 0, name: "BS朝日"}, // 151ch：BS朝日
+    BsChannel{channel: 161, set_freq:  0, slot: 1, name: "BS-TBS"}, // 161ch：BS-TBS
+    BsChannel{channel: 171, set_freq:  0, slot: 2, name: "BSテレ東"}, // 171ch：BSテレ東
+    BsChannel{channel: 191, set_freq:  1, slot: 0, name: "WOWOWプライム"}, // 191ch：WOWOWプライム
+    BsChannel{channel: 236, set_freq:  1, slot: 1, name: "BSアニマックス"}, // 236ch：BSアニマックス
+    BsChannel{channel: 251, set_freq:  1, slot: 2, name: "BS釣りビジョン"}, // 251ch：BS釣りビジョン
+    BsChannel{channel: 192, set_freq:  2, slot: 0, name: "WOWOWライブ"}, // 192ch：WOWOWライブ
+    BsChannel{channel: 193, set_freq:  2, slot: 1, name: "WOWOWシネマ"}, // 193ch：WOWOWシネマ
+    BsChannel{channel: 211, set_freq:  4, slot: 0, name: "BS11イレブン"}, // 211ch：BS11イレブン
+    BsChannel{channel: 222, set_freq:  4, slot: 1, name: "BS12トゥエルビ"}, // 222ch：BS12TwellV
+    BsChannel{channel: 141, set_freq:  6, slot: 0, name: "BS日テレ"}, // 141ch：BS日テレ
+    BsChannel{channel: 181, set_freq:  6, slot: 1, name: "BSフジ"}, // 181ch：BSフジ
+    BsChannel{channel: 231, set_freq:  6, slot: 2, name: "放送大学"}, // 231ch：放送大学
+    BsChannel{channel: 101, set_freq:  7, slot: 0, name: "NHKBS1"}, // 101ch：NHKBS1
+    BsChannel{channel: 201, set_freq:  7, slot: 1, name: "ＢＳ１０スターｃｈ"}, // 201ch：BS10スターch
+    BsChannel{channel: 200, set_freq:  7, slot: 2, name: "ＢＳ１０"}, // 200ch：BS10
+    BsChannel{channel: 245, set_freq:  9, slot: 0, name: "J SPORTS 4"}, // 245ch：J SPORTS 4
+    BsChannel{channel: 242, set_freq:  9, slot: 1, name: "J SPORTS 1"}, // 242ch：J SPORTS 1
+    BsChannel{channel: 243, set_freq:  9, slot: 2, name: "J SPORTS 2"}, // 243ch：J SPORTS 2
+    BsChannel{channel: 244, set_freq:  9, slot: 3, name: "J SPORTS 3"}, // 244ch：J SPORTS 3
+    BsChannel{channel: 252, set_freq: 10, slot: 0, name: "WOWOWプラス"}, // 252ch：WOWOWプラス
+    BsChannel{channel: 255, set_freq: 10, slot: 1, name: "日本映画専門ch"}, // 255ch：日本映画専門ch
+    BsChannel{channel: 234, set_freq: 10, slot: 2, name: "グリーンチャンネル"}, // 234ch：グリーンch
+    BsChannel{channel: 256, set_freq: 11, slot: 0, name: "ディズニーch"}, // 256ch：ディズニーch
+    BsChannel{channel: 265, set_freq: 11, slot: 1, name: "BSよしもと"}, // 265ch：BSよしもと
+    BsChannel{channel: 260, set_freq: 11, slot: 2, name: "BS松竹東急"}, // 260ch：BS松竹東急
 ];
 
+// BSチャンネルのチャンネル指定名(BSxx_y)
+#[allow(dead_code)]
+pub fn bs_channel_name(bs: &BsChannel) -> String {
+
+    format!("BS{:02}_{}", bs.set_freq * 2 + 1, bs.slot)
+
+}
+
+// ユーザーチャンネルファイル名(~/.recpt3-channels)
+#[allow(dead_code)]
+pub fn channel_file_path() -> String {
+
+    // Homeディレクトリーの設定(実行時の環境変数を優先)
+    let home = env::var("HOME").unwrap_or(env!("HOME").to_string());
+
+    format!("{}/.{}-channels", home, PROGRAM_RECPT)
+
+}
+
 // Show Channel Function
 #[allow(dead_code)]
 pub(crate)fn show_channels() {
 
-    // Homeディレクトリーの設定
-    let home: &str = env!("HOME");
-
     // チャンネルファイル名の設定
-    let channel_file: String = format!("{}/.{}-channels", home, PROGRAM_RECPT);    
+    let channel_file: String = channel_file_path();
 
     // チャンネルファイル名のリード
     match fs::read_to_string(channel_file) {
@@ -346,32 +371,9 @@ pub(crate)fn show_channels() {
     }
 
     // チャンネルリストの表示
-    eprintln!("BS01_0: BS朝日");
-    eprintln!("BS01_1: BS-TBS");
-    eprintln!("BS01_2: BSテレ東");
-    eprintln!("BS03_0: WOWOWプライム");
-    eprintln!("BS03_1: BSアニマックス");
-    eprintln!("BS03_2: BS釣りビジョン");
-    eprintln!("BS05_0: WOWOWライブ");
-    eprintln!("BS05_1: WOWOWシネマ");
-    eprintln!("BS09_0: BS11イレブン");
-    eprintln!("BS09_1: BS12トゥエルビ");
-    eprintln!("BS13_0: BS日テレ");
-    eprintln!("BS13_1: BSフジ");
-    eprintln!("BS13_2: 放送大学");
-    eprintln!("BS15_0: NHKBS1");
-    eprintln!("BS15_1: ＢＳ１０スターｃｈ");
-    eprintln!("BS15_2: ＢＳ１０");
-    eprintln!("BS19_0: J SPORTS 4");
-    eprintln!("BS19_1: J SPORTS 1");
-    eprintln!("BS19_2: J SPORTS 2");
-    eprintln!("BS19_3: J SPORTS 3");
-    eprintln!("BS21_0: WOWOWプラス");
-    eprintln!("BS21_1: 日本映画専門ch");
-    eprintln!("BS21_2: グリーンチャンネル");
-    eprintln!("BS23_0: ディズニーch");
-    eprintln!("BS23_1: BSよしもと");
-    eprintln!("BS23_2: BS松竹東急");
+    for bs in BS_CHANNELS.iter() {
+        eprintln!("{}: {}", bs_channel_name(bs), bs.name);
+    };
     eprintln!("C13-C63: CATV Channels");
     eprintln!("CS2-CS24: CS Channels");
 
@@ -391,10 +393,13 @@ pub fn channel_type(channel: String) -> (String, IoctlFreq) {
         let channel_info: Vec<&str> = channel_info.split('_').collect();
         debug!("channel_type channel_info = {:?}",channel_info);
 
-        if (1..=23).contains(&channel_info[0].replace("BS","").parse::<i32>().unwrap()) {
+        // BSxx_y以外の書式は対象外
+        let number = channel_info[0].replace("BS","").parse::<i32>().unwrap_or(0);
+        let slot = channel_info.get(1).and_then(|slot| slot.parse::<i32>().ok());
+        if let (true, Some(slot)) = ((1..=23).contains(&number), slot) {
             channel_type = "BS".to_string();
-            channel_num = channel_info[0].replace("BS","").parse::<i32>().unwrap() / 2;
-            slot_num = channel_info[1].parse::<i32>().unwrap();
+            channel_num = number / 2;
+            slot_num = slot;
         }
     }
 
@@ -402,21 +407,22 @@ pub fn channel_type(channel: String) -> (String, IoctlFreq) {
     if channel.to_uppercase().starts_with("CS") {
         let channel_info = channel.to_uppercase().to_string();
 
-        if (2..=24).contains(&channel_info.replace("CS","").parse::<i32>().unwrap()) {
+        let number = channel_info.replace("CS","").parse::<i32>().unwrap_or(0);
+        if (2..=24).contains(&number) {
             channel_type = "CS".to_string();
-            channel_num = channel_info.replace("CS","").parse::<i32>().unwrap() / 2 + 11;
+            channel_num = number / 2 + 11;
             slot_num = 0;
         }
     }
 
     // CATVタイプの判定
-    if channel.to_uppercase().chars().nth(0).unwrap() == 'C' && 
-       matches!(channel.to_uppercase().chars().nth(1).unwrap(),'0'..='9') {
+    if channel.to_uppercase().chars().nth(0) == Some('C') &&
+       matches!(channel.to_uppercase().chars().nth(1), Some('0'..='9')) {
 
         let channel_info = channel.to_uppercase().to_string();
         //let channel_info: Vec<&str> = channel_info.split('_').collect();
 
-        (channel_type, channel_num) = match channel_info.replace("C","").parse::<i32>().unwrap() {
+        (channel_type, channel_num) = match channel_info.replace("C","").parse::<i32>().unwrap_or(0) {
             13..=22 => {
                 ("CATV".to_string(), channel_info.replace("C","").parse::<i32>().unwrap() - 10)
             },
@@ -552,6 +558,26 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
     // チューナーデバイスファイルの作成
     let device_file = file.unwrap();
 
+    // 出力ファイルの作成＆オープン(失敗時に解放が不要なように選局・B25初期化の前に実施)
+    let mut outfile = match OutputFile::create(&command_opt.outfile, command_opt) {
+        Ok(outfile) => outfile,
+        Err(e) => {
+            error!("Output file open error ({}, {})", command_opt.outfile, e);
+            return false;
+        },
+    };
+
+    // 空き容量チェック(想定ビットレート×録画時間)
+    let bitrate = expected_bitrate(&channel_type);
+    if outfile.check_space_before_start(command_opt.duration, bitrate) == false {
+        return false;
+    };
+
+    // 出力ファイルの事前領域確保(録画時間の指定が無い場合は確保しない)
+    if command_opt.duration > 0 {
+        outfile.preallocate(command_opt.duration.saturating_mul(bitrate));
+    };
+
     // チューナーの設定処理を呼び出し
     tune(&device, &device_file, &command_opt.channel, &command_opt._lnb);
 
@@ -578,13 +604,6 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
         error!("Disabled B25...");
     };
 
-    // ts splitterの初期化処理
-    let mut sp = split_startup(&command_opt.sid_list);
-    let mut split_select_finish = TSS_ERROR;
-
-    // 出力ファイルの作成＆オープン
-    let mut outfile = BufWriter::with_capacity(CAP, File::create(command_opt.outfile.to_string()).unwrap());
-
     // 録画開始時刻の取得
     let start_time = SystemTime::now();
 
@@ -592,9 +611,6 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
     unsafe { start_rec(device_file.as_raw_fd()).unwrap() };
     info!("Recording...");
 
-    // 出力用のバッファ作成
-    let mut data_reader = BufReader::with_capacity(CAP, &device_file);
-
     // SIGNAL処理用の変数設定
     let loop_exit = Arc::new(AtomicBool::new(false));
     let loop_exit2 = Arc::clone(&loop_exit);
@@ -696,75 +712,268 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
         }
     });
 
-    // パケットドロップチェック用のパケット巡回カウンター変数の作成
-    let mut continuity_counter: i32;
-    let mut continuity_counter_flag: [i32; MAX_PID] = [0; MAX_PID];
-    let mut next_continuity_counter: [i32; MAX_PID] = [0; MAX_PID];
+    // スレッド間キューの作成(リード → デコード＆分離 → ファイル出力)
+    let read_queue: Arc<RingQueue<Vec<u8>>> = Arc::new(RingQueue::new("reader->decoder", QUEUE_CAPACITY));
+    let write_queue: Arc<RingQueue<Vec<u8>>> = Arc::new(RingQueue::new("decoder->writer", QUEUE_CAPACITY));
+
+    // 使用済みバッファの返却キュー(リード・デコードで再利用し、チャンク毎の確保をしない)
+    let free_queue: Arc<RingQueue<Vec<u8>>> = Arc::new(RingQueue::new("free buffers", QUEUE_CAPACITY));
+
+    // スレッド間の制御用変数
+    let stop_request = Arc::new(AtomicBool::new(false));
+    let write_error = Arc::new(AtomicBool::new(false));
+    let duration = Arc::new(AtomicU64::new(command_opt.duration));
+
+    // 録画中の統計情報
+    let stats = Arc::new(RecordingStats {
+        device: Mutex::new(device.to_string()),
+        bytes: AtomicU64::new(0),
+        drops: AtomicU64::new(0),
+        signal: AtomicU32::new(signal.to_bits()),
+        gaps: AtomicU64::new(0),
+    });
 
-    // データレシーブカウンター初期化
-    let mut rcount = 0;
+    // リードスレッド(チューナーからの読み込み＆パケットドロップチェック＆データ途絶監視)
+    let reader = {
+        let read_queue = Arc::clone(&read_queue);
+        let free_queue = Arc::clone(&free_queue);
+        let stop_request = Arc::clone(&stop_request);
+        let loop_exit = Arc::clone(&loop_exit);
+        let channel_type = channel_type.clone();
+        let sid_list = command_opt.sid_list.clone();
+        let channel = command_opt.channel.clone();
+        let lnb = command_opt._lnb;
+        let use_device = command_opt._use_device;
+        let reverse_device_order = command_opt.reverse_device_order;
+        let stall_timeout = command_opt.stall_timeout;
+        let stats = Arc::clone(&stats);
 
-    // 録画ストップコマンド実行フラグ
-    let mut stop_command_flag = 0;
+        thread::spawn(move || {
 
-    // 録画ループ（録画時間が経過するまでループ）
-    rec_time = {
-        loop {
+            // ドロップチェック対象PIDの確定用
+            let mut pipeline = TsPipeline::new(&sid_list, false);
 
-            // バッファへ読み込み、ファイル出力
-            let length = {
-                let read_buffer = data_reader.fill_buf().unwrap();
-
-                // use_splitterがfalseの場合に対象PID取得処理をここで実施
-                if command_opt.use_splitter == false {
-                    // パケットドロップチェック用のデータバッファ作成
-                    let mut check_data_buff: Vec<u8> = vec![0; read_buffer.len()];
-                    check_data_buff[..read_buffer.len()].copy_from_slice(&read_buffer[..read_buffer.len()]);
-                    if split_select_finish != TSS_SUCCESS {
-                        split_select_finish = split_select(&mut sp, &mut check_data_buff);
-                    }
+            // パケットドロップチェック用のパケット巡回カウンター
+            let mut drop_check = DropCheck::new();
+
+            // データレシーブカウンター初期化
+            let mut rcount = 0;
+
+            // デコードが遅れて破棄したチャンク数
+            let mut queue_drops = 0;
+
+            // 録画ストップコマンド実行フラグ
+            let mut stop_command_flag = 0;
+
+            // チューナーデバイス(再オープン失敗中はNone)
+            let mut device = device;
+            let mut device_file = Some(device_file);
+
+            // データ途絶監視用の変数
+            let mut last_data = SystemTime::now();
+            let mut stall_start: Option<SystemTime> = None;
+            let mut gaps: Vec<StallGap> = vec![];
+
+            // C/Nの取得時刻
+            let mut signal_time = SystemTime::now();
+
+            loop {
+
+                // 録画終了要求時は録画終了コマンド出力
+                if stop_request.load(Ordering::Acquire) == true && stop_command_flag == 0 {
+                    debug!("call stop_rec");
+                    if let Some(file) = &device_file {
+                        unsafe { stop_rec(file.as_raw_fd()).unwrap() };
+                    };
+                    stop_command_flag = 1;
+                };
+
+                // SIGNAL受信
+                if loop_exit.load(Ordering::Relaxed) == true { break; };
+
+                // 途絶経過時間
+                let stalled = SystemTime::now().duration_since(last_data).unwrap_or_default().as_secs();
+
+                // 再オープン失敗中は途絶監視時間毎にリトライ
+                let file = match &device_file {
+                    Some(file) => file,
+                    None => {
+                        if stop_command_flag == 1 { break; };
+                        if stalled >= stall_timeout {
+                            if let Some((new_device, new_file)) = reopen_tuner(&device, &channel, &lnb, use_device, &reverse_device_order) {
+                                *stats.device.lock().unwrap() = new_device.to_string();
+                                device = new_device;
+                                device_file = Some(new_file);
+                                drop_check.reset();
+                            };
+                            last_data = SystemTime::now();
+                        };
+                        thread::sleep(Duration::from_secs(1));
+                        continue;
+                    },
+                };
+
+                // データ途絶監視(1秒毎にデータ到着を確認)
+                if stall_timeout > 0 && stop_command_flag == 0 {
+                    let ready = {
+                        let mut fds = [PollFd::new(file.as_fd(), PollFlags::POLLIN)];
+                        poll(&mut fds, 1000u16).unwrap_or(1) > 0
+                    };
+                    if ready == false {
+                        if stalled >= stall_timeout {
+
+                            // 途絶開始時刻の記録
+                            if stall_start.is_none() {
+                                stall_start = Some(last_data);
+                            };
+
+                            // 途絶時の状態出力
+                            let signal = signal_get(file, &channel_type);
+                            warn!("No data for {}sec ({} , C/N = {:.6} dB)", stalled, device, signal);
+
+                            // 録画停止＆チューナーデバイス解放
+                            unsafe { let _ = stop_rec(file.as_raw_fd()); };
+                            device_file = None;
+
+                            // チューナーの再オープン＆再選局
+                            if let Some((new_device, new_file)) = reopen_tuner(&device, &channel, &lnb, use_device, &reverse_device_order) {
+                                *stats.device.lock().unwrap() = new_device.to_string();
+                                device = new_device;
+                                device_file = Some(new_file);
+                                drop_check.reset();
+                            };
+                            last_data = SystemTime::now();
+
+                        };
+                        continue;
+                    };
+                };
+
+                // バッファへ読み込み
+                let mut reader: &File = file;
+                let mut read_buffer = free_queue.try_pop().unwrap_or_else(|| Vec::with_capacity(CAP));
+                read_buffer.resize(CAP, 0);
+                let length = match reader.read(&mut read_buffer) {
+                    Ok(length) => length,
+                    Err(e) => {
+                        error!("Device read error ({})", e);
+                        break;
+                    },
+                };
+
+                // 録画ストップしデータが読み込まなくなったら終了
+                if length == 0 {
+                    if stop_command_flag == 1 { break; };
+                    continue;
+                };
+                read_buffer.truncate(length);
+
+                // データ途絶からの復帰時は途絶区間を記録
+                last_data = SystemTime::now();
+                if let Some(stall_time) = stall_start.take() {
+                    let gap = StallGap {
+                        offset: stall_time.duration_since(start_time).unwrap_or_default().as_secs(),
+                        seconds: last_data.duration_since(stall_time).unwrap_or_default().as_secs(),
+                        device: device.to_string(),
+                    };
+                    info!("Data resumed after {}sec ({})", gap.seconds, gap.device);
+                    gaps.push(gap);
+                    stats.gaps.store(gaps.len() as u64, Ordering::Relaxed);
+                };
+
+                // C/Nの定期取得
+                if last_data.duration_since(signal_time).unwrap_or_default().as_secs() >= METRICS_INTERVAL {
+                    stats.signal.store(signal_get(file, &channel_type).to_bits(), Ordering::Relaxed);
+                    signal_time = last_data;
                 };
 
                 // データレシーブカウンターアップ
                 rcount += 1;
 
                 // データ長がCAPと違う場合はデバッグ出力
-                if read_buffer.len() != CAP {
+                if length != CAP {
 
-                    debug!("録画終了？ (CAP={} , read_buffer.len={})", CAP, read_buffer.len());
+                    debug!("録画終了？ (CAP={} , read_buffer.len={})", CAP, length);
 
                 };
 
-                // パケットドロップチェック用のバッファ処理インデックス
-                let mut index = 0;
+                // 対象PID取得処理
+                pipeline.select(&read_buffer);
 
-                // バッファ終了までループ(パケットドロップチェック)
-                while (read_buffer.len() as i32 - index as i32 - LENGTH_PACKET as i32) >= 0 {
+                // パケットドロップチェック
+                drop_check.check(&pipeline.sp, &read_buffer, |pid, continuity_counter, next_continuity_counter| {
 
-                    // PID取得
-                    let pid = get_pid(&read_buffer[index..index + LENGTH_PACKET - 1]) as usize;
+                    // signal取得
+                    let signal = signal_get(file, &channel_type);
+                    debug!("パケットドロップ PID={}(0x{:04x}) , continuity_counter={} , next_continuity_counter={} , rcount={} , signel={}",
+                        pid, pid, continuity_counter, next_continuity_counter, rcount, signal);
 
-                    // パケット巡回カウンターの作成
-                    continuity_counter = (read_buffer[index + 3] & 0x0f) as i32;
+                });
+                stats.drops.store(drop_check.drop_count, Ordering::Relaxed);
 
-                    // パケットドロップチェック
-                    if (sp.pmt_pids[pid] > 0 || (sp.pids[pid] > 0 && pid < 0x100)) &&
-                        continuity_counter_flag[pid] == 1 && continuity_counter != next_continuity_counter[pid] {
+                // デコードスレッドへ送信(満杯時はリードを止めずに最も古いチャンクを破棄、後段が終了している場合はループ終了)
+                let dropped = read_queue.push_drop_oldest(read_buffer);
+                if dropped > 0 {
+                    queue_drops += dropped;
+                    warn!("Decoder is falling behind, dropped {} chunks (total {})", dropped, queue_drops);
+                };
+                if read_queue.is_closed() == true { break; };
 
-                        // signal取得
-                        let signal = signal_get(&device_file, &channel_type);
-                        debug!("パケットドロップ PID={}(0x{:04x}) , continuity_counter={} , next_continuity_counter={} , rcount={} , signel={}",
-                            pid, pid, continuity_counter, next_continuity_counter[pid], rcount, signal);
+            };
 
-                    };
+            // 録画終了コマンド出力
+            if let Some(file) = &device_file {
+                if stop_command_flag == 0 {
+                    unsafe { stop_rec(file.as_raw_fd()).unwrap() };
+                };
+            };
+            read_queue.close();
+
+            // 途絶したまま終了した場合も途絶区間を記録
+            if let Some(stall_time) = stall_start.take() {
+                gaps.push(StallGap {
+                    offset: stall_time.duration_since(start_time).unwrap_or_default().as_secs(),
+                    seconds: SystemTime::now().duration_since(stall_time).unwrap_or_default().as_secs(),
+                    device: device.to_string(),
+                });
+            };
+
+            // チューナーデバイス解放
+            drop(device_file);
+            if queue_drops > 0 {
+                warn!("Chunks dropped before decode = {}", queue_drops);
+            };
+
+            // リターン情報
+            (drop_check.drop_count, gaps)
 
-                    // 次パケット巡回カウンターの計算
-                    next_continuity_counter[pid] = (continuity_counter + 1) & 0x0f;
-                    continuity_counter_flag[pid] = 1;
+        })
+    };
+
+    // デコードスレッド(B25デコード＆SID分離)
+    let decoder = {
+        let read_queue = Arc::clone(&read_queue);
+        let write_queue = Arc::clone(&write_queue);
+        let free_queue = Arc::clone(&free_queue);
+        let use_b25 = command_opt.use_b25;
+        let use_splitter = command_opt.use_splitter;
+        let sid_list = command_opt.sid_list.clone();
+        let b25 = B25Context::new(dec, bcas);
+
+        thread::spawn(move || {
 
-                    // 次パケットまでインデックス更新
-                    index += LENGTH_PACKET;
+            // デコーダーコンテキストをスレッドへ移動
+            let b25 = b25;
 
+            // ts splitterの初期化処理
+            let mut pipeline = TsPipeline::new(&sid_list, use_splitter);
+
+            while let Some(read_buffer) = read_queue.pop() {
+
+                // デコード、分離しない場合はリードデータをそのまま出力
+                if use_b25 == false && use_splitter == false {
+                    if write_queue.push(read_buffer) == false { break; };
+                    continue;
                 };
 
                 // リードバッファ作成
@@ -774,107 +983,93 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
                 };
 
                 // B25デコード処理
-                let (buffer, len) = match command_opt.use_b25 {
+                let buffer = match use_b25 {
                     // フラグがtrueの場合にデコード
                     true => {
                         // デコード処理
-                        let (buffer, len) = unsafe { b25_decode(dec, &b25_buff) };
+                        let (buffer, len) = unsafe { b25_decode(b25.dec, &b25_buff) };
 
                         // リターン情報
-                        (buffer, len)
+                        &buffer[..len as usize]
                     },
                     // フラグがtrue以外の処理
                     false => {
                         // リードデータをそのまま出力
-                        let buffer = read_buffer as &[u8];
-
-                        // リターン情報
-                        (buffer, read_buffer.len() as i32)
+                        &read_buffer[..]
                     },
                 };
 
-                // ts splitter向け変数
-                let mut split_buff: Vec<u8> = vec![];
-                let mut result = 0;
-
-                // ts splitter処理
-                if command_opt.use_splitter == true && len > 0 {
-
-                    // buffer.lenチェック
-                    if (buffer.len() % LENGTH_PACKET) != 0 {
+                // ts splitter処理(分離しない場合はbufferをそのまま出力)
+                let write_buffer = pipeline.process(buffer);
 
-                        warn!("buffer.len({})がLENGTH_PACKET({})の倍数ではありません。", buffer.len(), LENGTH_PACKET);
-
-                    }
+                // ファイル出力スレッドへ送信(返却されたバッファへコピーし、リードバッファは返却)
+                if write_buffer.len() > 0 {
+                    let mut output = free_queue.try_pop().unwrap_or_else(|| Vec::with_capacity(CAP));
+                    output.clear();
+                    output.extend_from_slice(write_buffer);
+                    if write_queue.push(output) == false { break; };
+                };
+                free_queue.try_push(read_buffer);
 
-                    // データバッファ作成
-                    let mut data_buff: Vec<u8> = vec![0; buffer.len()];
-                    data_buff[..buffer.len()].copy_from_slice(&buffer[..buffer.len()]);
+            };
 
-                    // 処理するsidの取得
-                    if split_select_finish != TSS_SUCCESS {
+            // B-CASリーダーシャットダウン
+            if use_b25 == true {
+                unsafe { b25_shutdown(b25.dec, b25.bcas) };
+                info!("B25 shutdown");
+            };
 
-                        split_select_finish = split_select(&mut sp, &mut data_buff);
+            read_queue.close();
+            write_queue.close();
 
-                    };
+        })
+    };
 
-                    // sid取得OK時の処理
-                    if split_select_finish == TSS_SUCCESS {
+    // ファイル出力スレッド
+    let writer = {
+        let write_queue = Arc::clone(&write_queue);
+        let free_queue = Arc::clone(&free_queue);
+        let write_error = Arc::clone(&write_error);
+        let duration = Arc::clone(&duration);
+        let stats = Arc::clone(&stats);
 
-                        // sid split処理
-                        result = split_ts(&mut sp, &mut data_buff, &mut split_buff);
+        thread::spawn(move || {
 
-                    };
+            while let Some(write_buffer) = write_queue.pop() {
 
+                // ファイル出力(ディスクフル時はフォールバック先へ切り替え)
+                if let Err(e) = outfile.write(&write_buffer) {
+                    error!("Output file write error ({}, {})", outfile.path(), e);
+                    write_error.store(true, Ordering::Release);
+                    break;
                 };
+                stats.bytes.store(outfile.total_bytes(), Ordering::Relaxed);
+                free_queue.try_push(write_buffer);
 
-                // ファイル出力処理
-                if len > 0 || split_buff.len() > 0 {
-
-                    // 出力バッファ作成
-                    let write_buffer = if command_opt.use_splitter == true {
-                        
-                        if result != TSS_SUCCESS && split_buff.len() > 0 {
-                            debug!("recording split_ts failed split_buff.len={}", split_buff.len());
-                        }
-                            
-                        // ts splitter処理時のバッファ作成
-                        let buffer: &[u8] = &split_buff;
-                        //debug!("recording buffer.len={}", buffer.len());
-
-                        // リターン情報
-                        buffer
-
-                    }
-                    else {
+                // 空き容量の定期チェック
+                let rec_time = SystemTime::now().duration_since(start_time).unwrap().as_secs();
+                outfile.check_space(duration.load(Ordering::Relaxed).saturating_sub(rec_time), bitrate);
 
-                        // リターン情報
-                        buffer
-
-                    };
+            };
+            write_queue.close();
 
-                    // ファイル出力
-                    if write_buffer.len() > 0 {
+            // 出力終了処理
+            if let Err(e) = outfile.finish() {
+                error!("Output file close error ({}, {})", outfile.path(), e);
+            };
 
-                        outfile.write_all(write_buffer).unwrap();
-                        //debug!("recording write_buffer.len={}", write_buffer.len());
+            // リターン情報
+            outfile
 
-                        // バッファーフラッシュしてファイルに書き込み
-                        match outfile.flush() {
-                            Ok(_) => { },
-                            Err(_) => { error!("write_buf_file.flush Error"); },
-                        };
+        })
+    };
 
-                    };
+    // メトリクスの出力時刻
+    let mut metrics_time = start_time;
 
-                };
-
-                // リターン情報
-                read_buffer.len()
-            };
-            
-            // リードバッファクリア
-            data_reader.consume(length);
+    // 録画ループ（録画時間が経過するまでループ）
+    rec_time = {
+        loop {
 
 #[warn(unused_assignments)]
             //let mut new_rec_time = 0;
@@ -892,45 +1087,66 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
 
                 // 新しい録画時間を設定＆表示
                 command_opt.duration = new_rec_time.clone();
+                duration.store(new_rec_time, Ordering::Relaxed);
                 info!("New Record Time Recived = {}", new_rec_time);
 
             }
 
-            // 録画時間が経過したらループ終了
+            // 録画時間が経過したら録画終了要求
             rec_time = SystemTime::now().duration_since(start_time).unwrap().as_secs();
 
-            if rec_time > command_opt.duration {
-
-                // 録画ストップしデータが読み込まなくなったら終了
-                if stop_command_flag == 1  && length == 0 {
-                    break rec_time;
-                }
+            if rec_time > command_opt.duration || write_error.load(Ordering::Acquire) == true {
+                stop_request.store(true, Ordering::Release);
+            };
 
-                // 録画終了コマンド出力
-                if stop_command_flag == 0 {
-                    debug!("call stop_rec");
-                    unsafe { stop_rec(device_file.as_raw_fd()).unwrap() };
+            // メトリクスの定期出力(node_exporter textfile)
+            if command_opt.metrics_file != "" && SystemTime::now().duration_since(metrics_time).unwrap_or_default().as_secs() >= METRICS_INTERVAL {
+                if let Err(e) = write_textfile(&command_opt.metrics_file, &recording_metrics(&command_opt.channel, &stats, rec_time, true)) {
+                    warn!("Metrics file write error ({}, {})", command_opt.metrics_file, e);
                 };
-                stop_command_flag = 1;
+                metrics_time = SystemTime::now();
+            };
 
+            // 全スレッド終了でループ終了
+            if reader.is_finished() && decoder.is_finished() && writer.is_finished() {
+                break rec_time;
             };
 
-            // SIGNAL受信
-            if loop_exit.load(Ordering::Relaxed) == true { break rec_time; };
+            std::thread::sleep(Duration::from_millis(100));
         }
     };
 
-    // B-CASリーダーシャットダウン
-    if command_opt.use_b25 == true {
-        unsafe { b25_shutdown(dec, bcas) };
-        info!("B25 shutdown");
+    // スレッド終了処理
+    let (drop_count, gaps) = reader.join().unwrap_or((0, vec![]));
+    decoder.join().unwrap_or(());
+    let outfile = match writer.join() {
+        Ok(outfile) => outfile,
+        Err(_) => {
+            error!("Output thread panicked");
+            return false;
+        },
     };
 
-    // チューナーデバイス解放
-    drop(device_file);
- 
     // 録画終了情報出力
     info!("Recorded {}sec", rec_time);
+    info!("Packet drops = {}", drop_count);
+    if gaps.len() > 0 {
+        let total: u64 = gaps.iter().map(|gap| gap.seconds).sum();
+        warn!("Data gaps = {} ({}sec)", gaps.len(), total);
+        for gap in &gaps {
+            warn!("  at {}sec : {}sec without data ({})", gap.offset, gap.seconds, gap.device);
+        };
+    };
+    read_queue.report();
+    write_queue.report();
+    outfile.summary();
+
+    // メトリクスの最終出力
+    if command_opt.metrics_file != "" {
+        if let Err(e) = write_textfile(&command_opt.metrics_file, &recording_metrics(&command_opt.channel, &stats, rec_time, false)) {
+            warn!("Metrics file write error ({}, {})", command_opt.metrics_file, e);
+        };
+    };
 
     // posixメッセージキュー削除
     let mq_name = Name::new(&mq_name_id).unwrap();
@@ -943,6 +1159,35 @@ pub fn recording(command_opt: &mut CommanLineOpt, decoder_opt: DecoderOptions) -
 
 }
 
+// チューナーデバイス判定(デバイステーブルに含まれるかチェック)
+#[allow(dead_code)]
+pub fn is_tuner_device(device: &str) -> bool {
+
+    BSDEV.contains(&device) || ISDB_T_DEV.contains(&device)
+
+}
+
+// 利用可能なチューナーデバイス一覧(デバイスファイルが存在するもの)
+// デバイス毎に対応するチャンネルタイプ(BS,CS,T,CATV)を設定
+#[allow(dead_code)]
+pub fn tuner_list() -> Vec<(String, Vec<&'static str>)> {
+
+    let mut list: Vec<(String, Vec<&'static str>)> = vec![];
+
+    for (tuner, types) in [(BSDEV, ["BS", "CS"]), (ISDB_T_DEV, ["T", "CATV"])] {
+        for device in tuner.iter().filter(|device| Path::new(device).exists()) {
+            match list.iter_mut().find(|(name, _)| name == device) {
+                // 両方のテーブルに有るデバイス(マルチバンド)
+                Some((_, device_types)) => device_types.extend(types),
+                None => list.push((device.to_string(), types.to_vec())),
+            };
+        };
+    };
+
+    list
+
+}
+
 // チューナデバイスファイルの確定処理
 pub fn tuner_device(device: &String, channel: &String, reverse_device_order: &bool) -> (String, Result<fs::File, io::Error>) {
 
@@ -1034,10 +1279,20 @@ pub fn tuner_device(device: &String, channel: &String, reverse_device_order: &bo
 
 }
 
-// チューナー設定
+// チューナー設定(選局失敗時はプロセス終了)
 #[allow(dead_code)]
 pub fn tune(device: &String, file: &File, channel: &String, lnb: &u64) -> () {
 
+    if try_tune(device, file, channel, lnb) == false {
+        process::exit(-1);
+    };
+
+}
+
+// チューナー設定(選局失敗時はfalseをリターン)
+#[allow(dead_code)]
+pub fn try_tune(device: &String, file: &File, channel: &String, lnb: &u64) -> bool {
+
     // チューナーデバイスのファイルディスクリプタ作成
     let fd = file.as_raw_fd();
     debug!("tune tuner fd = {}", fd);
@@ -1072,12 +1327,100 @@ pub fn tune(device: &String, file: &File, channel: &String, lnb: &u64) -> () {
         Err(e) => {
             error!("tune set_ch error(code={})", e);
             error!("tune Cannot tune to the specified channel: {}", device);
-            process::exit(-1);
+            return false;
         },
     };
     //if errno < 0 { error!("Cannot tune to the specified channel: {}", device) };
     //info!("device = {}", device);
 
+    true
+
+}
+
+// 録画中の統計情報(メトリクス出力用)
+#[allow(dead_code)]
+pub struct RecordingStats {
+    pub device: Mutex<String>,
+    pub bytes: AtomicU64,
+    pub drops: AtomicU64,
+    pub signal: AtomicU32,
+    pub gaps: AtomicU64,
+}
+
+// 録画メトリクスの作成
+#[allow(dead_code)]
+pub fn recording_metrics(channel: &String, stats: &RecordingStats, rec_time: u64, active: bool) -> Vec<Metric> {
+
+    let device = stats.device.lock().unwrap().to_string();
+    let labels = [("channel", channel.as_str()), ("device", device.as_str())];
+
+    let mut active_metric = Metric::new("recpt3_recording_active", "Recording in progress", MetricType::Gauge);
+    active_metric.sample(&labels, if active { 1.0 } else { 0.0 });
+    let mut elapsed = Metric::new("recpt3_recording_elapsed_seconds", "Elapsed recording time", MetricType::Gauge);
+    elapsed.sample(&labels, rec_time as f64);
+    let mut bytes = Metric::new("recpt3_recording_bytes", "Bytes written to the output file", MetricType::Counter);
+    bytes.sample(&labels, stats.bytes.load(Ordering::Relaxed) as f64);
+    let mut drops = Metric::new("recpt3_recording_cc_errors", "Continuity counter errors", MetricType::Counter);
+    drops.sample(&labels, stats.drops.load(Ordering::Relaxed) as f64);
+    let mut signal = Metric::new("recpt3_signal_cn_db", "Tuner C/N in dB", MetricType::Gauge);
+    signal.sample(&labels, f32::from_bits(stats.signal.load(Ordering::Relaxed)) as f64);
+    let mut gaps = Metric::new("recpt3_recording_stall_gaps", "Data stalls recovered by the watchdog", MetricType::Counter);
+    gaps.sample(&labels, stats.gaps.load(Ordering::Relaxed) as f64);
+    let mut b25_errors = Metric::new("recpt3_b25_decode_errors", "B25 decode failures", MetricType::Counter);
+    b25_errors.sample(&labels, b25_decode_errors() as f64);
+
+    vec![active_metric, elapsed, bytes, drops, signal, gaps, b25_errors]
+
+}
+
+// データ途絶区間の情報
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct StallGap {
+    pub offset: u64,
+    pub seconds: u64,
+    pub device: String,
+}
+
+// チューナーの再オープン＆再選局(データ途絶時)
+// 同じデバイスを優先し、デバイス未指定時は他の空きチューナーも検索する
+#[allow(dead_code)]
+pub fn reopen_tuner(device: &String, channel: &String, lnb: &u64, use_device: bool, reverse_device_order: &bool) -> Option<(String, File)> {
+
+    // 再オープン候補(同じデバイス → 空きチューナー)
+    let mut candidates = vec![device.to_string()];
+    if use_device == false {
+        candidates.push("".to_string());
+    };
+
+    for candidate in candidates {
+
+        // チューナーデバイスのオープン
+        let (new_device, file) = tuner_device(&candidate, channel, reverse_device_order);
+        let new_file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Reopen tuner failed ({}, {})", candidate, e);
+                continue;
+            },
+        };
+
+        // 再選局＆録画開始
+        if try_tune(&new_device, &new_file, channel, lnb) == false {
+            continue;
+        };
+        if let Err(e) = unsafe { start_rec(new_file.as_raw_fd()) } {
+            warn!("Restart recording failed ({}, {})", new_device, e);
+            continue;
+        };
+
+        info!("Tuner reopened: {}", new_device);
+        return Some((new_device, new_file));
+
+    };
+
+    None
+
 }
 
 // posix message queue 受信処理